@@ -9,6 +9,17 @@
 #define ONE_CKB ((uint64_t)100000000)
 #define MIN_CONTRACT_CAPACITY ((uint64_t)(ONE_CKB * (uint64_t)158))
 
+/* The recognized EoA locks. The address of an EoA does not cover its lock,
+ * so an EoA cell must keep the same lock in the output. */
+#define EOA_LOCK_ANYONE_CAN_PAY 0
+#define EOA_LOCK_ANYONE_CAN_PAY_MAINNET 1
+#define EOA_LOCK_ANYONE_CAN_PAY_TESTNET 2
+#define EOA_LOCK_SIGHASH 3
+#define EOA_LOCK_MULTISIG 4
+/* The multisig lock args: blake160 of the multisig script and an optional
+ * since */
+#define MAX_EOA_LOCK_ARGS_SIZE 28
+
 int check_script_code(const uint8_t *script_data_a,
                       const size_t script_size_a,
                       const uint8_t *script_data_b,
@@ -56,6 +67,7 @@ int check_eoa_script(blake2b_state *blake2b_ctx,
                      uint8_t *lock_script,
                      const size_t lock_script_size,
                      bool *matched,
+                     uint8_t *lock_kind,
                      evmc_address *address,
                      mol_seg_t *lock_args_bytes_seg,
                      mol_seg_t *type_args_bytes_seg) {
@@ -94,8 +106,55 @@ int check_eoa_script(blake2b_state *blake2b_ctx,
     /*    0x86, 0xb0, 0x91, 0xde, 0xfb, 0x62, 0x52, 0x15, */
     /*    0x4e, 0x0b, 0xb5, 0x10, 0x44, 0xfd, 0x28, 0x79, */
     /*    0xfd, 0xe5, 0xb2, 0x73, 0x14, 0x50, 0x61, 0x11}; */
+  /* The anyone-can-pay deployed with type id on mainnet (lina) and testnet
+   * (aggron), matched by type hash */
+  static uint8_t anyone_can_pay_type_hashes[2][32]
+    = {{0xd3, 0x69, 0x59, 0x7f, 0xf4, 0x7f, 0x29, 0xfb,
+        0xc0, 0xd4, 0x7d, 0x2e, 0x37, 0x75, 0x37, 0x0d,
+        0x12, 0x50, 0xb8, 0x51, 0x40, 0xc6, 0x70, 0xe4,
+        0x71, 0x8a, 0xf7, 0x12, 0x98, 0x3a, 0x23, 0x54},
+       {0x34, 0x19, 0xa1, 0xc0, 0x9e, 0xb2, 0x56, 0x7f,
+        0x65, 0x52, 0xee, 0x7a, 0x8e, 0xcf, 0xfd, 0x64,
+        0x15, 0x5c, 0xff, 0xe0, 0xf1, 0x79, 0x6e, 0x6e,
+        0x61, 0xec, 0x08, 0x8d, 0x74, 0x0c, 0x13, 0x56}};
   mol_seg_t lock_code_hash_seg = MolReader_Script_get_code_hash(&lock_script_seg);
-  if (memcmp(lock_code_hash_seg.ptr, anyone_can_pay_code_hash, 32) != 0) {
+  mol_seg_t lock_hash_type_seg = MolReader_Script_get_hash_type(&lock_script_seg);
+  bool is_anyone_can_pay = false;
+  if (*lock_hash_type_seg.ptr == 0) {
+    /* The code hash is a data hash. With hash_type type the same bytes name
+     * the cell whose type script hashes to them, which can be any lock, so
+     * the cell must not be counted as an EoA cell. */
+    is_anyone_can_pay = memcmp(lock_code_hash_seg.ptr, anyone_can_pay_code_hash, 32) == 0;
+    *lock_kind = EOA_LOCK_ANYONE_CAN_PAY;
+  } else if (*lock_hash_type_seg.ptr == 1) {
+    for (size_t i = 0; i < 2; i++) {
+      if (memcmp(lock_code_hash_seg.ptr, anyone_can_pay_type_hashes[i], 32) == 0) {
+        is_anyone_can_pay = true;
+        *lock_kind = EOA_LOCK_ANYONE_CAN_PAY_MAINNET + i;
+      }
+    }
+  }
+  /* The secp256k1 system scripts, the same type hashes on every chain */
+  static uint8_t sighash_type_hash[32]
+    = {0x9b, 0xd7, 0xe0, 0x6f, 0x3e, 0xcf, 0x4b, 0xe0,
+       0xf2, 0xfc, 0xd2, 0x18, 0x8b, 0x23, 0xf1, 0xb9,
+       0xfc, 0xc8, 0x8e, 0x5d, 0x4b, 0x65, 0xa8, 0x63,
+       0x7b, 0x17, 0x72, 0x3b, 0xbd, 0xa3, 0xcc, 0xe8};
+  static uint8_t multisig_type_hash[32]
+    = {0x5c, 0x50, 0x69, 0xeb, 0x08, 0x57, 0xef, 0xc6,
+       0x5e, 0x1b, 0xca, 0x0c, 0x07, 0xdf, 0x34, 0xc3,
+       0x16, 0x63, 0xb3, 0x62, 0x2f, 0xd3, 0x87, 0x6c,
+       0x87, 0x63, 0x20, 0xfc, 0x96, 0x34, 0xe2, 0xa8};
+  if (!is_anyone_can_pay && *lock_hash_type_seg.ptr == 1) {
+    if (memcmp(lock_code_hash_seg.ptr, sighash_type_hash, 32) == 0) {
+      *lock_kind = EOA_LOCK_SIGHASH;
+    } else if (memcmp(lock_code_hash_seg.ptr, multisig_type_hash, 32) == 0) {
+      *lock_kind = EOA_LOCK_MULTISIG;
+    } else {
+      *matched = false;
+      return 0;
+    }
+  } else if (!is_anyone_can_pay) {
     *matched = false;
     return 0;
   }
@@ -108,7 +167,8 @@ int check_eoa_script(blake2b_state *blake2b_ctx,
     debug_print_int("Invalid type script args length", type_args_bytes_seg->size);
     return -99;
   }
-  if (lock_args_bytes_seg->size != 20) {
+  if (lock_args_bytes_seg->size != 20
+      && !(*lock_kind == EOA_LOCK_MULTISIG && lock_args_bytes_seg->size == MAX_EOA_LOCK_ARGS_SIZE)) {
     debug_print_int("Invalid lock script args length", lock_args_bytes_seg->size);
     return -99;
   }
@@ -230,6 +290,8 @@ typedef struct contract_program {
   struct contract_program *next_program;
   struct contract_program *prev_program;
   bool is_processed;
+  /* Signed depth 0 program of the main contract */
+  bool is_entrance;
 } contract_program;
 
 typedef struct {
@@ -265,8 +327,9 @@ typedef struct {
 
 typedef struct {
   evmc_address address;
+  uint8_t lock_kind;
   uint8_t type_args[32];
-  uint8_t lock_args[20];
+  uint8_t lock_args[MAX_EOA_LOCK_ARGS_SIZE];
   /* -1 for not exists in input */
   int input_index;
   uint64_t input_balance;
@@ -493,6 +556,7 @@ int contract_program_load(contract_program *program, const uint8_t *buf, const s
   program->next_program = NULL;
   program->prev_program = NULL;
   program->is_processed = false;
+  program->is_entrance = false;
   return 0;
 }
 
@@ -511,6 +575,7 @@ void find_eoa_account(eoa_account **account,
 }
 
 void eoa_account_init(eoa_account *account,
+                      uint8_t lock_kind,
                       evmc_address *address,
                       mol_seg_t *type_args,
                       mol_seg_t *lock_args,
@@ -518,6 +583,7 @@ void eoa_account_init(eoa_account *account,
                       uint64_t input_capacity,
                       int input_index) {
   account->is_used = false;
+  account->lock_kind = lock_kind;
   account->address = *address;
   memcpy(account->type_args, type_args->ptr, type_args->size);
   memcpy(account->lock_args, lock_args->ptr, lock_args->size);
@@ -1504,6 +1570,7 @@ int load_account_infos(blake2b_state *blake2b_ctx) {
       global_info_count += 1;
     } else {
       bool is_eoa = false;
+      uint8_t lock_kind;
       evmc_address eoa_address;
       mol_seg_t type_args_bytes_seg;
       mol_seg_t lock_args_bytes_seg;
@@ -1513,6 +1580,7 @@ int load_account_infos(blake2b_state *blake2b_ctx) {
                              lock_script,
                              lock_script_size,
                              &is_eoa,
+                             &lock_kind,
                              &eoa_address,
                              &lock_args_bytes_seg,
                              &type_args_bytes_seg);
@@ -1524,6 +1592,7 @@ int load_account_infos(blake2b_state *blake2b_ctx) {
         debug_print_data("eoa->address", eoa_address.bytes, 20);
         eoa_account *account = global_eoa_list + global_eoa_count;
         eoa_account_init(account,
+                         lock_kind,
                          &eoa_address,
                          &type_args_bytes_seg,
                          &lock_args_bytes_seg,
@@ -1655,6 +1724,7 @@ int load_account_infos(blake2b_state *blake2b_ctx) {
       info->output_balance = balance;
     } else {
       bool is_eoa = false;
+      uint8_t lock_kind;
       evmc_address eoa_address;
       mol_seg_t type_args_bytes_seg;
       mol_seg_t lock_args_bytes_seg;
@@ -1664,9 +1734,10 @@ int load_account_infos(blake2b_state *blake2b_ctx) {
                              lock_script,
                              lock_script_size,
                              &is_eoa,
+                             &lock_kind,
                              &eoa_address,
-                             &type_args_bytes_seg,
-                             &lock_args_bytes_seg);
+                             &lock_args_bytes_seg,
+                             &type_args_bytes_seg);
       if (ret != CKB_SUCCESS) {
         debug_print_int("check eoa script failed, input index:", input_index);
         return ret;
@@ -1679,6 +1750,10 @@ int load_account_infos(blake2b_state *blake2b_ctx) {
           debug_print_data("eoa account not in inputs", eoa_address.bytes, 20);
           return -100;
         }
+        if (account->lock_kind != lock_kind) {
+          debug_print_data("eoa account lock changed", eoa_address.bytes, 20);
+          return -100;
+        }
         account->output_balance = balance;
         account->output_capacity = capacity;
         account->output_index = output_index;
@@ -1694,9 +1769,11 @@ int verify_signature_count() {
   uint8_t zero_signature[65];
   memset(zero_signature, 0, 65);
   /* Verify:
-   *  - there is one and only one non-zero signature
+   *  - all signed (entrance) programs are depth 0 CALL/CREATE programs of one contract
+   *  - the first program of that contract is an entrance program
+   *  - all normal(not CALLCODE/DELEGATECALL) programs of that contract are entrance programs
    */
-  bool has_entrance_signature = false;
+  contract_info *main_info = NULL;
   for (size_t info_idx = 0; info_idx < global_info_count; info_idx++) {
     contract_info *info = &global_info_list[info_idx];
     contract_program *current_program = info->head_program;
@@ -1706,32 +1783,41 @@ int verify_signature_count() {
       debug_print_int("program_idx", program_idx);
       debug_print_data("current_program->signature", current_program->signature, 65);
       if (memcmp(current_program->signature, zero_signature, 65) != 0) {
-        if (program_idx != 0) {
-          debug_print("main signature is not in first program");
-          return -100;
-        }
-        if ((info->program_count - info->special_call_total_count) != 1) {
-          debug_print("main contract only allow 1 normal(not CALLCODE/DELEGATECALL) program");
+        if (is_special_call(current_program->kind) || current_program->depth != 0) {
+          debug_print("entrance program must be a depth 0 CALL/CREATE program");
           return -100;
         }
-        if (has_entrance_signature) {
-          debug_print("has multiple entrance signature");
+        if (main_info != NULL && main_info != info) {
+          debug_print("has multiple entrance contract");
           return -100;
         }
-        if (memcmp(global_current_contract.bytes, info->address.bytes, 20) == 0) {
-          global_current_is_main = true;
-        }
-        info->is_main = true;
-        memcpy(global_tx_context.tx_origin.bytes, current_program->tx_origin.bytes, 20);
-        has_entrance_signature = true;
+        main_info = info;
+        current_program->is_entrance = true;
       }
       current_program = current_program->next_program;
     }
   }
-  if (!has_entrance_signature) {
+  if (main_info == NULL) {
     debug_print("no entrance signature found");
     return -100;
   }
+  if (!main_info->head_program->is_entrance) {
+    debug_print("main signature is not in first program");
+    return -100;
+  }
+  contract_program *current_program = main_info->head_program;
+  for (size_t program_idx = 0; program_idx < main_info->program_count; program_idx++) {
+    if (!is_special_call(current_program->kind) && !current_program->is_entrance) {
+      debug_print("main contract only allow entrance and CALLCODE/DELEGATECALL programs");
+      return -100;
+    }
+    current_program = current_program->next_program;
+  }
+  if (memcmp(global_current_contract.bytes, main_info->address.bytes, 20) == 0) {
+    global_current_is_main = true;
+  }
+  main_info->is_main = true;
+  memcpy(global_tx_context.tx_origin.bytes, main_info->head_program->tx_origin.bytes, 20);
   return 0;
 }
 
@@ -1858,6 +1944,60 @@ int load_headers(blake2b_state *blake2b_ctx) {
   return 0;
 }
 
+/* A tx_origin locked by multisig signs the entrance programs by a member key,
+ * the multisig script is in the witness lock of its input:
+ *   S | R | M | N | blake160(pubkey) * N | signatures
+ * The multisig lock verifies the script and the signatures of the members. */
+int check_multisig_member(blake2b_state *blake2b_ctx,
+                          uint8_t *witness_buf,
+                          const eoa_account *account,
+                          const uint8_t *pubkey_hash) {
+  uint64_t len = WITNESS_SIZE;
+  int ret = ckb_load_witness(witness_buf, &len, 0, account->input_index, CKB_SOURCE_INPUT);
+  if (ret != CKB_SUCCESS) {
+    debug_print_int("load multisig witness failed, input index:", account->input_index);
+    return -95;
+  }
+  if (len > WITNESS_SIZE) {
+    return ERROR_INVALID_DATA;
+  }
+  mol_seg_t witness_seg;
+  witness_seg.ptr = witness_buf;
+  witness_seg.size = len;
+  if (MolReader_WitnessArgs_verify(&witness_seg, false) != MOL_OK) {
+    return ERROR_INVALID_DATA;
+  }
+  mol_seg_t lock_seg = MolReader_WitnessArgs_get_lock(&witness_seg);
+  if (MolReader_BytesOpt_is_none(&lock_seg)) {
+    debug_print("multisig witness lock is missing");
+    return -95;
+  }
+  mol_seg_t lock_bytes_seg = MolReader_Bytes_raw_bytes(&lock_seg);
+  if (lock_bytes_seg.size < 4) {
+    return -95;
+  }
+  size_t pubkeys_count = lock_bytes_seg.ptr[3];
+  size_t script_size = 4 + 20 * pubkeys_count;
+  if (lock_bytes_seg.size < script_size) {
+    return -95;
+  }
+  uint8_t script_hash[32];
+  blake2b_init(blake2b_ctx, 32);
+  blake2b_update(blake2b_ctx, lock_bytes_seg.ptr, script_size);
+  blake2b_final(blake2b_ctx, script_hash, 32);
+  if (memcmp(script_hash, account->lock_args, 20) != 0) {
+    debug_print("multisig script not match lock script args");
+    return -95;
+  }
+  for (size_t i = 0; i < pubkeys_count; i++) {
+    if (memcmp(lock_bytes_seg.ptr + 4 + 20 * i, pubkey_hash, 20) == 0) {
+      return 0;
+    }
+  }
+  debug_print("Signature pubkey is not a multisig member");
+  return -95;
+}
+
 int load_tx_context(blake2b_state *blake2b_ctx) {
   int ret;
   uint64_t len = HEADER_SIZE;
@@ -2119,8 +2259,20 @@ inline int verify_params(const uint8_t *signature_data,
     return -99;
   }
 
-  /* Change tx_origin balance */
+  /* The program of main contract to run */
+  contract_program *main_program = NULL;
   if (global_current_is_main) {
+    contract_info *main_info = NULL;
+    find_contract_info(&main_info, global_info_list, global_info_count, &global_current_contract);
+    if (main_info == NULL || main_info->current_program == NULL) {
+      debug_print("can not find main contract program");
+      return -99;
+    }
+    main_program = main_info->current_program;
+  }
+
+  /* Change tx_origin balance */
+  if (main_program != NULL && main_program->is_entrance) {
     intx::uint256 transfer_value_u256 = intx::be::load<intx::uint256>(value->bytes);
     uint64_t transfer_value = transfer_value_u256.lo.lo;
     if (transfer_value > 0) {
@@ -2136,6 +2288,9 @@ inline int verify_params(const uint8_t *signature_data,
       }
       contract_info_add_balance(info, transfer_value);
     }
+  }
+  /* Capacity of created contracts is paid by the first entrance program */
+  if (main_program != NULL && main_program->prev_program == NULL) {
     for (size_t idx = 0; idx < global_info_count; idx++) {
       contract_info *info = &global_info_list[idx];
       contract_program *current_program = info->head_program;
@@ -2169,7 +2324,14 @@ inline int verify_params(const uint8_t *signature_data,
         contract_info *info = &global_info_list[info_idx];
         memcpy(witness_buf, info->witness_buf, info->witness_size);
         if (info->is_main) {
-          memset(witness_buf + 4, 0, 65);
+          /* Signatures of all entrance programs are excluded */
+          contract_program *program = info->head_program;
+          for (size_t program_idx = 0; program_idx < info->program_count; program_idx++) {
+            if (program->is_entrance) {
+              memset(witness_buf + (program->signature - info->witness_buf), 0, 65);
+            }
+            program = program->next_program;
+          }
         }
         blake2b_update(&blake2b_ctx, witness_buf, info->witness_size);
       }
@@ -2217,21 +2379,21 @@ inline int verify_params(const uint8_t *signature_data,
       if (account == NULL) {
         return -95;
       }
-      if (memcmp(account->lock_args, temp, 20) != 0) {
+      if (account->lock_kind == EOA_LOCK_MULTISIG) {
+        ret = check_multisig_member(&blake2b_ctx, witness_buf, account, temp);
+        if (ret != CKB_SUCCESS) {
+          return ret;
+        }
+      } else if (memcmp(account->lock_args, temp, 20) != 0) {
         debug_print_data("account->lock_args", account->lock_args, 20);
         debug_print_data("temp", temp, 20);
         debug_print("Signature pubkey not match lock script args");
         return -95;
       }
 
-      uint8_t hash[32];
-      blake2b_init(&blake2b_ctx, 32);
-      blake2b_update(&blake2b_ctx, account->type_args, 32);
-      blake2b_update(&blake2b_ctx, temp, 20);
-      blake2b_final(&blake2b_ctx, hash, 32);
-
-      /* Verify entrance program sender */
-      if (memcmp(sender->bytes, hash, 20) != 0) {
+      /* Verify entrance program sender, the address covers the whole lock
+       * args (a multisig since included) */
+      if (memcmp(sender->bytes, account->address.bytes, 20) != 0) {
         debug_print("EoA sender not match the signature");
         return -95;
       }
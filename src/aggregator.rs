@@ -0,0 +1,152 @@
+use crate::server::TransactionReceipt;
+use crate::storage::{Loader, Runner};
+use crate::types::{ContractAddress, RunConfig};
+use ckb_types::{bytes::Bytes, H160};
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Queue calls to the same contract within a batching window and execute
+/// them in order, every transaction is built on top of the pending state of
+/// the previous one so concurrent senders no longer spend the same contract
+/// cell.
+///
+/// Consecutive calls of the same sender are run as entrance programs of one
+/// transaction, these callers get the same receipt (one step per call).
+/// The validator only accepts one tx_origin per transaction, so calls of
+/// different senders are always in different transactions.
+pub struct Aggregator {
+    loader: Arc<Loader>,
+    run_config: RunConfig,
+    window: Duration,
+    // A contract has a queue as long as its batch thread is running, so the
+    // calls to one contract are executed in order
+    queues: Arc<Mutex<HashMap<ContractAddress, Vec<QueuedCall>>>>,
+}
+
+struct QueuedCall {
+    sender: H160,
+    input: Bytes,
+    value: u64,
+    result_sender: Sender<Result<TransactionReceipt, String>>,
+}
+
+impl Aggregator {
+    pub fn new(loader: Arc<Loader>, run_config: RunConfig, window: Duration) -> Aggregator {
+        Aggregator {
+            loader,
+            run_config,
+            window,
+            queues: Arc::new(Mutex::new(HashMap::default())),
+        }
+    }
+
+    pub fn call(
+        &self,
+        sender: H160,
+        contract_address: ContractAddress,
+        input: Bytes,
+        value: u64,
+    ) -> Result<TransactionReceipt, String> {
+        let (result_sender, result_receiver) = channel();
+        let is_new_queue = {
+            let mut queues = self.queues.lock().expect("lock queues");
+            let is_new_queue = !queues.contains_key(&contract_address);
+            queues
+                .entry(contract_address.clone())
+                .or_default()
+                .push(QueuedCall {
+                    sender,
+                    input,
+                    value,
+                    result_sender,
+                });
+            is_new_queue
+        };
+        if is_new_queue {
+            self.spawn_batches(contract_address);
+        }
+        result_receiver.recv().map_err(|err| err.to_string())?
+    }
+
+    // Execute the queued calls of a contract batch by batch, until no call is
+    // queued during a batch
+    fn spawn_batches(&self, contract_address: ContractAddress) {
+        let loader = Arc::clone(&self.loader);
+        let run_config = self.run_config.clone();
+        let window = self.window;
+        let queues = Arc::clone(&self.queues);
+        thread::spawn(move || loop {
+            thread::sleep(window);
+            let batch = {
+                let mut queues = queues.lock().expect("lock queues");
+                let batch = queues.remove(&contract_address).unwrap_or_default();
+                if batch.is_empty() {
+                    break;
+                }
+                // Keep the queue, calls arriving now wait for this batch
+                queues.insert(contract_address.clone(), Vec::new());
+                batch
+            };
+            log::debug!(
+                "execute {} aggregated calls to contract {:x}",
+                batch.len(),
+                contract_address.0
+            );
+            let mut calls = batch.into_iter().peekable();
+            while let Some(call) = calls.next() {
+                let mut group = vec![call];
+                while let Some(next) = calls.peek() {
+                    if next.sender != group[0].sender {
+                        break;
+                    }
+                    group.extend(calls.next());
+                }
+                execute_group(&loader, &run_config, &contract_address, group);
+            }
+        });
+    }
+}
+
+// Run the calls of one sender in one transaction. When one of them fails the
+// others must not fail with it, so every call is run again on its own.
+fn execute_group(
+    loader: &Loader,
+    run_config: &RunConfig,
+    contract_address: &ContractAddress,
+    group: Vec<QueuedCall>,
+) {
+    let run = |calls: &[QueuedCall]| {
+        TransactionReceipt::build(loader, |loader| {
+            let mut context = Runner::new(loader, run_config.clone())
+                .new_context()
+                .map_err(|err| err.to_string())?;
+            for call in calls {
+                context
+                    .call(
+                        call.sender.clone(),
+                        contract_address.clone(),
+                        call.input.clone(),
+                        call.value,
+                    )
+                    .map_err(|err| err.to_string())?;
+            }
+            Ok(context)
+        })
+    };
+    if group.len() > 1 {
+        if let Ok(receipt) = run(&group) {
+            for call in group {
+                // The caller may be gone, the transaction is still pending
+                let _ = call.result_sender.send(Ok(receipt.clone()));
+            }
+            return;
+        }
+    }
+    for call in group {
+        let result = run(std::slice::from_ref(&call));
+        let _ = call.result_sender.send(result);
+    }
+}
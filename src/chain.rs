@@ -0,0 +1,393 @@
+use ckb_jsonrpc_types::{
+    BlockView, CellData, CellInfo, CellWithStatus, EpochView, HeaderView, JsonBytes, OutPoint,
+    TransactionWithStatus,
+};
+use ckb_types::{
+    bytes::Bytes,
+    core::{self, EpochNumberWithFraction},
+    packed,
+    prelude::*,
+    H256,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::client::HttpRpcClient;
+use crate::types::{ChainScripts, ONE_CKB};
+
+/// The CKB chain data read by the indexer and the loaders
+pub trait ChainSource: Send + Sync {
+    fn get_block(&mut self, hash: H256) -> Result<Option<BlockView>, String>;
+    fn get_epoch_by_number(&mut self, number: u64) -> Result<Option<EpochView>, String>;
+    fn get_header(&mut self, hash: H256) -> Result<Option<HeaderView>, String>;
+    fn get_header_by_number(&mut self, number: u64) -> Result<Option<HeaderView>, String>;
+    fn get_live_cell(
+        &mut self,
+        out_point: OutPoint,
+        with_data: bool,
+    ) -> Result<CellWithStatus, String>;
+    fn get_tip_block_number(&mut self) -> Result<u64, String>;
+    fn get_tip_header(&mut self) -> Result<HeaderView, String>;
+    fn get_transaction(&mut self, hash: H256) -> Result<Option<TransactionWithStatus>, String>;
+
+    /// Another handle of the same chain, used by other threads
+    fn clone_box(&self) -> Box<dyn ChainSource>;
+}
+
+impl Clone for Box<dyn ChainSource> {
+    fn clone(&self) -> Box<dyn ChainSource> {
+        self.clone_box()
+    }
+}
+
+impl ChainSource for HttpRpcClient {
+    fn get_block(&mut self, hash: H256) -> Result<Option<BlockView>, String> {
+        HttpRpcClient::get_block(self, hash)
+    }
+    fn get_epoch_by_number(&mut self, number: u64) -> Result<Option<EpochView>, String> {
+        HttpRpcClient::get_epoch_by_number(self, number)
+    }
+    fn get_header(&mut self, hash: H256) -> Result<Option<HeaderView>, String> {
+        HttpRpcClient::get_header(self, hash)
+    }
+    fn get_header_by_number(&mut self, number: u64) -> Result<Option<HeaderView>, String> {
+        HttpRpcClient::get_header_by_number(self, number)
+    }
+    fn get_live_cell(
+        &mut self,
+        out_point: OutPoint,
+        with_data: bool,
+    ) -> Result<CellWithStatus, String> {
+        HttpRpcClient::get_live_cell(self, out_point, with_data)
+    }
+    fn get_tip_block_number(&mut self) -> Result<u64, String> {
+        HttpRpcClient::get_tip_block_number(self)
+    }
+    fn get_tip_header(&mut self) -> Result<HeaderView, String> {
+        HttpRpcClient::get_tip_header(self)
+    }
+    fn get_transaction(&mut self, hash: H256) -> Result<Option<TransactionWithStatus>, String> {
+        HttpRpcClient::get_transaction(self, hash)
+    }
+    fn clone_box(&self) -> Box<dyn ChainSource> {
+        Box::new(self.clone())
+    }
+}
+
+/// The system scripts of the chain, by the presets or from the genesis block
+pub fn chain_scripts(client: &mut dyn ChainSource) -> Result<ChainScripts, String> {
+    let genesis = client
+        .get_header_by_number(0)?
+        .ok_or_else(|| String::from("Genesis block not found"))?;
+    if let Some(scripts) = ChainScripts::preset(&genesis.hash) {
+        return Ok(scripts);
+    }
+    let block = client
+        .get_block(genesis.hash.clone())?
+        .ok_or_else(|| String::from("Genesis block not found"))?;
+    let dep_group_tx = block
+        .transactions
+        .get(1)
+        .ok_or_else(|| String::from("Dep group transaction not found in genesis block"))?;
+    Ok(ChainScripts::dev(dep_group_tx.hash.clone()))
+}
+
+// Blocks per epoch of the fake chain
+const FAKE_EPOCH_LENGTH: u64 = 100;
+
+/// An in-process chain for tests. Blocks are built from the pushed
+/// transactions (e.g. signed `build-tx` outputs) and committed at once,
+/// a cellbase paying to the miner lock is added to every block.
+#[derive(Clone, Default)]
+pub struct FakeChain {
+    inner: Arc<Mutex<FakeChainState>>,
+}
+
+#[derive(Default)]
+struct FakeChainState {
+    blocks: Vec<core::BlockView>,
+    // tx_hash => (block number, transaction)
+    transactions: HashMap<H256, (u64, core::TransactionView)>,
+    // out_point => the block number where it is spent
+    spent: HashMap<packed::OutPoint, u64>,
+    // The lock of cellbase outputs, the default script if not set
+    miner_lock: packed::Script,
+}
+
+impl FakeChain {
+    /// A chain with the genesis block holding the transactions
+    pub fn new(genesis_transactions: Vec<packed::Transaction>) -> FakeChain {
+        let chain = FakeChain::default();
+        chain
+            .push_block(genesis_transactions)
+            .expect("genesis block");
+        chain
+    }
+
+    /// Pay the cellbases of later blocks to the lock
+    pub fn set_miner_lock(&self, lock: packed::Script) {
+        self.inner.lock().expect("lock fake chain").miner_lock = lock;
+    }
+
+    /// Commit a block with the transactions, return its hash. Inputs must be
+    /// live cells of earlier blocks or earlier transactions of this block.
+    pub fn push_block(&self, transactions: Vec<packed::Transaction>) -> Result<H256, String> {
+        let mut state = self.inner.lock().expect("lock fake chain");
+        let number = state.blocks.len() as u64;
+        let cellbase = core::TransactionBuilder::default()
+            .input(packed::CellInput::new_cellbase_input(number))
+            .output(
+                packed::CellOutput::new_builder()
+                    .capacity((1000 * ONE_CKB).pack())
+                    .lock(state.miner_lock.clone())
+                    .build(),
+            )
+            .output_data(Bytes::new().pack())
+            .witness(Bytes::new().pack())
+            .build();
+        let mut block_spent: HashMap<packed::OutPoint, u64> = HashMap::default();
+        let mut block_transactions: HashMap<H256, core::TransactionView> = HashMap::default();
+        for tx in transactions.iter().map(|tx| tx.clone().into_view()) {
+            for out_point in tx.input_pts_iter() {
+                let tx_hash: H256 = out_point.tx_hash().unpack();
+                let index: u32 = out_point.index().unpack();
+                let exists = state
+                    .transactions
+                    .get(&tx_hash)
+                    .map(|(_, tx)| tx)
+                    .or_else(|| block_transactions.get(&tx_hash))
+                    .map(|tx| (index as usize) < tx.outputs().len())
+                    .unwrap_or(false);
+                if !exists {
+                    return Err(format!("Unknown input {:#x}-{}", tx_hash, index));
+                }
+                if state.spent.contains_key(&out_point) || block_spent.contains_key(&out_point) {
+                    return Err(format!("Dead input {:#x}-{}", tx_hash, index));
+                }
+                block_spent.insert(out_point, number);
+            }
+            block_transactions.insert(tx.hash().unpack(), tx);
+        }
+        let parent_hash = state
+            .blocks
+            .last()
+            .map(|block| block.hash())
+            .unwrap_or_default();
+        let epoch = EpochNumberWithFraction::new(
+            number / FAKE_EPOCH_LENGTH,
+            number % FAKE_EPOCH_LENGTH,
+            FAKE_EPOCH_LENGTH,
+        );
+        let block = core::BlockBuilder::default()
+            .parent_hash(parent_hash)
+            .number(number.pack())
+            .epoch(epoch.full_value().pack())
+            .timestamp((number * 1000).pack())
+            .transaction(cellbase)
+            .transactions(transactions.into_iter().map(|tx| tx.into_view()))
+            .build();
+        for tx in block.transactions() {
+            state.transactions.insert(tx.hash().unpack(), (number, tx));
+        }
+        state.spent.extend(block_spent);
+        let hash = block.hash().unpack();
+        state.blocks.push(block);
+        Ok(hash)
+    }
+
+    /// Drop the blocks after the number, blocks pushed later fork the chain
+    pub fn rollback(&self, number: u64) {
+        let mut state = self.inner.lock().expect("lock fake chain");
+        state.blocks.truncate(number as usize + 1);
+        state
+            .transactions
+            .retain(|_, (block_number, _)| *block_number <= number);
+        state
+            .spent
+            .retain(|_, block_number| *block_number <= number);
+    }
+
+    pub fn tip_number(&self) -> u64 {
+        let state = self.inner.lock().expect("lock fake chain");
+        state.blocks.len() as u64 - 1
+    }
+}
+
+impl FakeChainState {
+    fn block(&self, hash: &H256) -> Option<&core::BlockView> {
+        let hash = hash.pack();
+        self.blocks.iter().find(|block| block.hash() == hash)
+    }
+
+    fn tip(&self) -> &core::BlockView {
+        self.blocks.last().expect("genesis block")
+    }
+}
+
+impl ChainSource for FakeChain {
+    fn get_block(&mut self, hash: H256) -> Result<Option<BlockView>, String> {
+        let state = self.inner.lock().expect("lock fake chain");
+        Ok(state.block(&hash).cloned().map(BlockView::from))
+    }
+    fn get_epoch_by_number(&mut self, number: u64) -> Result<Option<EpochView>, String> {
+        let state = self.inner.lock().expect("lock fake chain");
+        let start_number = number * FAKE_EPOCH_LENGTH;
+        if start_number >= state.blocks.len() as u64 {
+            return Ok(None);
+        }
+        Ok(Some(EpochView {
+            number: number.into(),
+            start_number: start_number.into(),
+            length: FAKE_EPOCH_LENGTH.into(),
+            compact_target: 0u32.into(),
+        }))
+    }
+    fn get_header(&mut self, hash: H256) -> Result<Option<HeaderView>, String> {
+        let state = self.inner.lock().expect("lock fake chain");
+        Ok(state
+            .block(&hash)
+            .map(|block| HeaderView::from(block.header())))
+    }
+    fn get_header_by_number(&mut self, number: u64) -> Result<Option<HeaderView>, String> {
+        let state = self.inner.lock().expect("lock fake chain");
+        Ok(state
+            .blocks
+            .get(number as usize)
+            .map(|block| HeaderView::from(block.header())))
+    }
+    fn get_live_cell(
+        &mut self,
+        out_point: OutPoint,
+        with_data: bool,
+    ) -> Result<CellWithStatus, String> {
+        let state = self.inner.lock().expect("lock fake chain");
+        let index = out_point.index.value() as usize;
+        let cell = state
+            .transactions
+            .get(&out_point.tx_hash)
+            .and_then(|(_, tx)| tx.output_with_data(index));
+        let out_point = packed::OutPoint::from(out_point);
+        let status = match cell {
+            Some(_) if state.spent.contains_key(&out_point) => "dead",
+            Some(_) => "live",
+            None => "unknown",
+        };
+        let cell = cell
+            .filter(|_| status == "live")
+            .map(|(output, data)| CellInfo {
+                output: output.into(),
+                data: if with_data {
+                    Some(CellData {
+                        hash: packed::CellOutput::calc_data_hash(&data).unpack(),
+                        content: JsonBytes::from_bytes(data),
+                    })
+                } else {
+                    None
+                },
+            });
+        Ok(CellWithStatus {
+            cell,
+            status: status.to_string(),
+        })
+    }
+    fn get_tip_block_number(&mut self) -> Result<u64, String> {
+        Ok(self.tip_number())
+    }
+    fn get_tip_header(&mut self) -> Result<HeaderView, String> {
+        let state = self.inner.lock().expect("lock fake chain");
+        Ok(HeaderView::from(state.tip().header()))
+    }
+    fn get_transaction(&mut self, hash: H256) -> Result<Option<TransactionWithStatus>, String> {
+        let state = self.inner.lock().expect("lock fake chain");
+        Ok(state.transactions.get(&hash).map(|(number, tx)| {
+            let block_hash = state.blocks[*number as usize].hash().unpack();
+            TransactionWithStatus::with_committed(tx.clone(), block_hash)
+        }))
+    }
+    fn clone_box(&self) -> Box<dyn ChainSource> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn transfer(inputs: Vec<packed::OutPoint>, outputs: usize) -> packed::Transaction {
+        let mut builder = core::TransactionBuilder::default();
+        for out_point in inputs {
+            builder = builder.input(packed::CellInput::new(out_point, 0));
+        }
+        for _ in 0..outputs {
+            builder = builder
+                .output(
+                    packed::CellOutput::new_builder()
+                        .capacity((100 * ONE_CKB).pack())
+                        .build(),
+                )
+                .output_data(Bytes::new().pack());
+        }
+        builder.build().data()
+    }
+
+    #[test]
+    fn test_fake_chain() {
+        let genesis_tx = transfer(vec![], 2);
+        let genesis_tx_hash = genesis_tx.calc_tx_hash();
+        let mut chain = FakeChain::new(vec![genesis_tx]);
+        let cell_0 = packed::OutPoint::new(genesis_tx_hash.clone(), 0);
+        let cell_1 = packed::OutPoint::new(genesis_tx_hash, 1);
+
+        let tx = transfer(vec![cell_0.clone()], 1);
+        let block_1 = chain.push_block(vec![tx.clone()]).unwrap();
+        assert_eq!(chain.get_tip_block_number().unwrap(), 1);
+        assert_eq!(chain.get_tip_header().unwrap().hash, block_1);
+        assert_eq!(
+            chain
+                .get_live_cell(cell_0.clone().into(), false)
+                .unwrap()
+                .status,
+            "dead"
+        );
+        // Double spend
+        assert!(chain.push_block(vec![tx.clone()]).is_err());
+        let tx_status = chain
+            .get_transaction(tx.calc_tx_hash().unpack())
+            .unwrap()
+            .unwrap()
+            .tx_status;
+        assert_eq!(tx_status.block_hash, Some(block_1.clone()));
+
+        // Fork at block 1
+        chain.rollback(0);
+        assert_eq!(
+            chain.get_live_cell(cell_0.into(), true).unwrap().status,
+            "live"
+        );
+        assert!(chain
+            .get_transaction(tx.calc_tx_hash().unpack())
+            .unwrap()
+            .is_none());
+        let fork_block_1 = chain.push_block(vec![transfer(vec![cell_1], 1)]).unwrap();
+        assert_ne!(fork_block_1, block_1);
+        let header = chain.get_header_by_number(1).unwrap().unwrap();
+        assert_eq!(header.hash, fork_block_1);
+        assert_eq!(
+            header.inner.parent_hash,
+            chain.get_header_by_number(0).unwrap().unwrap().hash
+        );
+    }
+
+    #[test]
+    fn test_dev_chain_scripts() {
+        let dep_group_tx = transfer(vec![], 2);
+        let dep_group_tx_hash: H256 = dep_group_tx.calc_tx_hash().unpack();
+        let mut chain = FakeChain::new(vec![dep_group_tx]);
+        let scripts = chain_scripts(&mut chain).unwrap();
+        let out_point = scripts.sighash_dep.out_point();
+        let tx_hash: H256 = out_point.tx_hash().unpack();
+        let index: u32 = out_point.index().unpack();
+        assert_eq!((tx_hash, index), (dep_group_tx_hash, 0));
+        assert!(scripts.anyone_can_pay_dep.is_none());
+    }
+}
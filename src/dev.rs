@@ -0,0 +1,433 @@
+use crate::chain::{ChainSource, FakeChain};
+use crate::server::RpcImpl;
+use crate::sign_transaction;
+use crate::storage::{Indexer, IndexerHealth, KvStore, Loader, MemoryStore, PendingPool};
+use crate::types::{
+    ChainScripts, EoaLockKind, RunConfig, ALWAYS_SUCCESS_BINARY, ALWAYS_SUCCESS_SCRIPT,
+    DEV_ANYONE_CAN_PAY_CODE_HASH, MULTISIG_TYPE_HASH, ONE_CKB, SECP256K1, SIGHASH_TYPE_HASH,
+};
+use ckb_chain_spec::consensus::TYPE_ID_CODE_HASH;
+use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_jsonrpc_types::Transaction;
+use ckb_sdk::{MockResourceLoader, MockTransaction, MockTransactionHelper, MultisigConfig};
+use ckb_system_scripts::BUNDLED_CELL;
+use ckb_types::{
+    bytes::Bytes,
+    core::{self, DepType, ScriptHashType},
+    packed,
+    prelude::*,
+    H160, H256,
+};
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Capacity of an EoA cell without balance, see `new-eoa-account`
+const EOA_CELL_CAPACITY: u64 = ONE_CKB * (8 + (32 + 1 + 32) + (32 + 1 + 20));
+// Polling the indexer every 50ms
+const WAIT_ROUNDS: usize = 600;
+// The max cycles of a CKB block
+const MAX_CYCLES: u64 = 3_500_000_000;
+// `tests/anyone_can_pay`, the EoA lock of the accounts
+const ANYONE_CAN_PAY_BINARY: &[u8] = include_bytes!("../tests/anyone_can_pay");
+// The output indexes of the system scripts in the genesis cellbase of mainnet,
+// testnet and CKB dev chains, the type ids are the same on every chain
+const SIGHASH_GENESIS_INDEX: u64 = 1;
+const MULTISIG_GENESIS_INDEX: u64 = 4;
+
+/// The EoA lock of a dev account
+#[derive(Clone)]
+pub enum DevLock {
+    AnyoneCanPay,
+    Sighash,
+    /// The account key is one of the members, `DevChain::commit` only adds
+    /// its signature
+    Multisig(MultisigConfig),
+}
+
+/// A prefunded account of the dev chain
+#[derive(Clone)]
+pub struct DevAccount {
+    pub privkey: secp256k1::SecretKey,
+    pub lock: DevLock,
+    /// blake160(pubkey), or blake160(multisig script) of a multisig account
+    pub lock_arg: H160,
+    /// The EoA address
+    pub address: H160,
+}
+
+/// An in-memory chain for local development. The scripts and the EoA cells
+/// of the accounts are in the genesis block, transactions of the accounts
+/// are signed and mined at once. The sighash and multisig locks are the
+/// bundled system scripts, with the type ids of the CKB genesis cellbase.
+///
+/// Only the generator is required. Given the validator and the secp256k1
+/// data (`c/build/secp256k1_data`), the scripts of every transaction are run
+/// before it is mined.
+pub struct DevChain {
+    chain: FakeChain,
+    loader: Arc<Loader>,
+    run_config: RunConfig,
+    health: Arc<Mutex<IndexerHealth>>,
+    accounts: Vec<DevAccount>,
+    verify_scripts: bool,
+    // One block is mined at a time
+    mining: Mutex<()>,
+}
+
+impl DevChain {
+    /// Start the chain and the indexer, the first account is the miner
+    pub fn new(
+        generator: Bytes,
+        validator: Option<Bytes>,
+        secp256k1_data: Option<Bytes>,
+        accounts: Vec<(secp256k1::SecretKey, DevLock, u64)>,
+    ) -> Result<DevChain, String> {
+        let verify_scripts = validator.is_some() && secp256k1_data.is_some();
+        let bundled_cell = |name: &str| {
+            BUNDLED_CELL
+                .get(&format!("specs/cells/{}", name))
+                .map(|data| Bytes::from(data.into_owned()))
+                .map_err(|err| format!("Bundled {}: {}", name, err))
+        };
+        let system_type = |index: u64, type_hash: &H256| {
+            let mut blake2b = new_blake2b();
+            blake2b.update(packed::CellInput::new_cellbase_input(0).as_slice());
+            blake2b.update(&index.to_le_bytes());
+            let mut type_args = [0u8; 32];
+            blake2b.finalize(&mut type_args);
+            let script = packed::Script::new_builder()
+                .code_hash(TYPE_ID_CODE_HASH.pack())
+                .hash_type(ScriptHashType::Type.into())
+                .args(Bytes::from(type_args.to_vec()).pack())
+                .build();
+            let script_hash: H256 = script.calc_script_hash().unpack();
+            if &script_hash != type_hash {
+                return Err(format!(
+                    "Type hash of genesis output {}: {:#x}, expected: {:#x}",
+                    index, script_hash, type_hash
+                ));
+            }
+            Ok(script)
+        };
+        // The secp256k1 data of the validator and of the bundled locks, the
+        // same cell unless the validator is built from another secp256k1
+        let bundled_secp256k1_data = bundled_cell("secp256k1_data")?;
+        let secp256k1_data = secp256k1_data.unwrap_or_else(|| bundled_secp256k1_data.clone());
+        let mut script_cells = vec![
+            (
+                validator.unwrap_or_else(|| Bytes::from_static(b"validator")),
+                None,
+            ),
+            (
+                Bytes::from(hex::decode(ALWAYS_SUCCESS_BINARY).unwrap()),
+                None,
+            ),
+            (Bytes::from_static(ANYONE_CAN_PAY_BINARY), None),
+            (
+                bundled_cell("secp256k1_blake160_sighash_all")?,
+                Some(system_type(SIGHASH_GENESIS_INDEX, &SIGHASH_TYPE_HASH)?),
+            ),
+            (
+                bundled_cell("secp256k1_blake160_multisig_all")?,
+                Some(system_type(MULTISIG_GENESIS_INDEX, &MULTISIG_TYPE_HASH)?),
+            ),
+        ];
+        let mut secp256k1_data_cells = vec![secp256k1_data.clone()];
+        if bundled_secp256k1_data != secp256k1_data {
+            secp256k1_data_cells.push(bundled_secp256k1_data);
+        }
+        script_cells.extend(secp256k1_data_cells.into_iter().map(|data| (data, None)));
+        let mut deploy_tx = core::TransactionBuilder::default();
+        for (data, type_) in &script_cells {
+            deploy_tx = deploy_tx
+                .output(
+                    packed::CellOutput::new_builder()
+                        .capacity(((data.len() as u64 + 200) * ONE_CKB).pack())
+                        .type_(type_.clone().pack())
+                        .build(),
+                )
+                .output_data(data.pack());
+        }
+        let deploy_tx = deploy_tx.build();
+        let cell_dep = |index: u32| {
+            packed::CellDep::new_builder()
+                .out_point(packed::OutPoint::new(deploy_tx.hash(), index))
+                .dep_type(DepType::Code.into())
+                .build()
+        };
+        // The secp256k1 data is loaded by the validator and the locks from the
+        // sighash and multisig dep groups, like on mainnet and testnet
+        let mut dep_group_tx = core::TransactionBuilder::default();
+        for lock_index in &[3, 4] {
+            let dep_group_data = (5..script_cells.len())
+                .chain(iter::once(*lock_index))
+                .fold(packed::OutPointVec::new_builder(), |builder, index| {
+                    builder.push(packed::OutPoint::new(deploy_tx.hash(), index as u32))
+                })
+                .build()
+                .as_bytes();
+            dep_group_tx = dep_group_tx
+                .output(
+                    packed::CellOutput::new_builder()
+                        .capacity(((dep_group_data.len() as u64 + 100) * ONE_CKB).pack())
+                        .build(),
+                )
+                .output_data(dep_group_data.pack());
+        }
+        let dep_group_tx = dep_group_tx.build();
+        let chain_scripts = ChainScripts::dev(dep_group_tx.hash().unpack());
+        let run_config = RunConfig {
+            generator,
+            type_dep: cell_dep(0),
+            type_script: packed::Script::new_builder()
+                .code_hash(packed::CellOutput::calc_data_hash(&script_cells[0].0))
+                .hash_type(ScriptHashType::Data.into())
+                .build(),
+            lock_dep: cell_dep(1),
+            lock_script: ALWAYS_SUCCESS_SCRIPT.clone(),
+            legacy_locks: Vec::new(),
+            eoa_lock_dep: cell_dep(2),
+            eoa_lock_script: packed::Script::new_builder()
+                .code_hash(DEV_ANYONE_CAN_PAY_CODE_HASH.pack())
+                .hash_type(ScriptHashType::Data.into())
+                .build(),
+            extra_eoa_locks: chain_scripts.system_eoa_locks(),
+            sighash_dep: chain_scripts.sighash_dep.clone(),
+        };
+        let eoa_lock_script = |lock: &DevLock| {
+            let kind = match lock {
+                DevLock::AnyoneCanPay => EoaLockKind::AnyoneCanPay,
+                DevLock::Sighash => EoaLockKind::Sighash,
+                DevLock::Multisig(_) => EoaLockKind::Multisig,
+            };
+            run_config
+                .eoa_lock_scripts()
+                .into_iter()
+                .find(|(lock_kind, _)| lock_kind == &kind)
+                .map(|(_, script)| script)
+                .expect("EoA lock of the kind")
+        };
+
+        let mut eoa_tx = core::TransactionBuilder::default();
+        let mut dev_accounts = Vec::new();
+        for (index, (privkey, lock, balance)) in accounts.into_iter().enumerate() {
+            let pubkey = secp256k1::PublicKey::from_secret_key(&SECP256K1, &privkey);
+            let lock_arg = match &lock {
+                DevLock::Multisig(config) => config.hash160(),
+                _ => H160::from_slice(&blake2b_256(&pubkey.serialize()[..])[0..20]).unwrap(),
+            };
+            // Not checked by the chain, only need to be unique
+            let type_args = blake2b_256(&(index as u64).to_le_bytes());
+            let mut blake2b = new_blake2b();
+            blake2b.update(&type_args);
+            blake2b.update(lock_arg.as_bytes());
+            let mut hash = [0u8; 32];
+            blake2b.finalize(&mut hash);
+            let lock_script = eoa_lock_script(&lock)
+                .as_builder()
+                .args(Bytes::from(lock_arg.as_bytes().to_vec()).pack())
+                .build();
+            let type_ = packed::Script::new_builder()
+                .code_hash(TYPE_ID_CODE_HASH.pack())
+                .hash_type(ScriptHashType::Type.into())
+                .args(Bytes::from(type_args.to_vec()).pack())
+                .build();
+            eoa_tx = eoa_tx
+                .output(
+                    packed::CellOutput::new_builder()
+                        .capacity((EOA_CELL_CAPACITY + balance).pack())
+                        .lock(lock_script)
+                        .type_(Some(type_).pack())
+                        .build(),
+                )
+                .output_data(Bytes::new().pack());
+            dev_accounts.push(DevAccount {
+                privkey,
+                lock,
+                lock_arg,
+                address: H160::from_slice(&hash[0..20]).unwrap(),
+            });
+        }
+        let miner = dev_accounts
+            .first()
+            .ok_or_else(|| String::from("No dev account"))?;
+        let miner_pubkey = secp256k1::PublicKey::from_secret_key(&SECP256K1, &miner.privkey);
+        let chain = FakeChain::new(vec![
+            deploy_tx.data(),
+            dep_group_tx.data(),
+            eoa_tx.build().data(),
+        ]);
+        chain.set_miner_lock(
+            eoa_lock_script(&DevLock::Sighash)
+                .as_builder()
+                .args(
+                    Bytes::from(blake2b_256(&miner_pubkey.serialize()[..])[0..20].to_vec()).pack(),
+                )
+                .build(),
+        );
+
+        let db: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        // Transactions are committed before the RPC returns
+        let pending = Arc::new(Mutex::new(PendingPool::new(Duration::from_secs(60))));
+        let loader = Arc::new(Loader::new(
+            Arc::clone(&db),
+            Box::new(chain.clone()),
+            Arc::clone(&pending),
+        )?);
+        let health = Arc::new(Mutex::new(IndexerHealth::default()));
+        let mut indexer = Indexer::new(
+            db,
+            Box::new(chain.clone()),
+            run_config.clone(),
+            pending,
+            None,
+            true,
+            Arc::clone(&health),
+        );
+        let _ = thread::spawn(move || indexer.run());
+
+        let dev_chain = DevChain {
+            chain,
+            loader,
+            run_config,
+            health,
+            accounts: dev_accounts,
+            verify_scripts,
+            mining: Mutex::new(()),
+        };
+        dev_chain.wait_indexed()?;
+        Ok(dev_chain)
+    }
+
+    pub fn accounts(&self) -> &[DevAccount] {
+        &self.accounts
+    }
+
+    pub fn chain(&self) -> &FakeChain {
+        &self.chain
+    }
+
+    /// The RPC of the chain, create/call/batch are mined before returning
+    pub fn rpc(self: &Arc<Self>) -> RpcImpl {
+        RpcImpl {
+            loader: Arc::clone(&self.loader),
+            run_config: self.run_config.clone(),
+            aggregator: None,
+            health: Arc::clone(&self.health),
+            dev: Some(Arc::clone(self)),
+        }
+    }
+
+    /// Sign the transaction of an account and mine it, return the signed
+    /// transaction after it is indexed. A multisig account needs a threshold
+    /// of 1, or the signatures of other members (see `sign_transaction`).
+    pub fn commit(&self, sender: &H160, tx: Transaction) -> Result<Transaction, String> {
+        let account = self
+            .accounts
+            .iter()
+            .find(|account| &account.address == sender)
+            .ok_or_else(|| format!("{:#x} is not a dev account", sender))?;
+        let multisig_config = match &account.lock {
+            DevLock::Multisig(config) => Some(config),
+            _ => None,
+        };
+        let mut chain = self.chain.clone();
+        let tx = sign_transaction(
+            tx,
+            &account.privkey,
+            &self.run_config.eoa_lock_scripts(),
+            multisig_config,
+            &mut chain,
+        )?;
+        self.send(tx)
+    }
+
+    /// Mine a signed transaction, return it after it is indexed
+    pub fn send(&self, tx: Transaction) -> Result<Transaction, String> {
+        let mut chain = self.chain.clone();
+        let packed_tx = packed::Transaction::from(tx.clone());
+        let tx_hash: H256 = packed_tx.calc_tx_hash().unpack();
+        if self.verify_scripts {
+            let mut mock_tx = MockTransaction {
+                mock_info: Default::default(),
+                tx: packed_tx.clone(),
+            };
+            let cycles = MockTransactionHelper::new(&mut mock_tx)
+                .verify(MAX_CYCLES, ChainLoader(&mut chain))
+                .map_err(|err| format!("Invalid transaction {:#x}: {}", tx_hash, err))?;
+            log::debug!("verified transaction {:#x}, cycles: {}", tx_hash, cycles);
+        }
+        self.mine(vec![packed_tx])?;
+        let quarantined = self
+            .health
+            .lock()
+            .expect("lock indexer health")
+            .quarantined
+            .iter()
+            .find(|quarantined| quarantined.tx_hash == tx_hash)
+            .map(|quarantined| quarantined.reason.clone());
+        if let Some(reason) = quarantined {
+            return Err(format!("Invalid transaction {:#x}: {}", tx_hash, reason));
+        }
+        Ok(tx)
+    }
+
+    /// Mine a block with the transactions, return after it is indexed
+    pub fn mine(&self, transactions: Vec<packed::Transaction>) -> Result<H256, String> {
+        let _guard = self.mining.lock().expect("lock mining");
+        let hash = self.chain.push_block(transactions)?;
+        log::info!("Mined block #{} {:#x}", self.chain.tip_number(), hash);
+        self.wait_indexed()?;
+        Ok(hash)
+    }
+
+    fn wait_indexed(&self) -> Result<(), String> {
+        let tip_number = self.chain.tip_number();
+        for _ in 0..WAIT_ROUNDS {
+            let health = self.health.lock().expect("lock indexer health").clone();
+            if health.halted {
+                return Err(format!("Indexer halted: {:?}", health.last_error));
+            }
+            let last_block = self.loader.load_last_block()?;
+            if last_block.map(|(number, _)| number >= tip_number) == Some(true) {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Err(format!("Block #{} is not indexed", tip_number))
+    }
+}
+
+// Load the cells and headers of a transaction for running its scripts
+struct ChainLoader<'a>(&'a mut dyn ChainSource);
+
+impl<'a> MockResourceLoader for ChainLoader<'a> {
+    fn get_header(&mut self, hash: H256) -> Result<Option<core::HeaderView>, String> {
+        Ok(self.0.get_header(hash)?.map(core::HeaderView::from))
+    }
+
+    fn get_live_cell(
+        &mut self,
+        out_point: packed::OutPoint,
+    ) -> Result<Option<(packed::CellOutput, Bytes)>, String> {
+        Ok(self
+            .0
+            .get_live_cell(out_point.into(), true)?
+            .cell
+            .map(|info| {
+                let data = info
+                    .data
+                    .map(|data| data.content.into_bytes())
+                    .unwrap_or_default();
+                (info.output.into(), data)
+            }))
+    }
+}
+
+/// The private key of the nth default dev account
+pub fn dev_privkey(index: usize) -> secp256k1::SecretKey {
+    let seed = format!("polyjuice dev account {}", index);
+    secp256k1::SecretKey::from_slice(&blake2b_256(seed.as_bytes())[..]).expect("valid private key")
+}
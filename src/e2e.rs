@@ -0,0 +1,1027 @@
+//! End to end tests on the dev chain: transactions built by the RPC are
+//! signed, mined and indexed before the next step.
+//!
+//! The validator is run on every transaction before it is mined. The
+//! binaries are required, they are read from `POLYJUICE_GENERATOR`,
+//! `POLYJUICE_VALIDATOR` and `POLYJUICE_SECP256K1_DATA` (default
+//! `c/build/generator`, `c/build/validator` and `c/build/secp256k1_data`,
+//! built by `make -C c all-via-docker`). `make integration` builds them and
+//! runs these tests, `make test` skips them.
+
+use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_jsonrpc_types::{JsonBytes, ScriptHashType, Transaction};
+use ckb_sdk::MultisigConfig;
+use ckb_types::{bytes::Bytes, packed, prelude::*, H160, H256};
+use std::error::Error as StdError;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::chain::{ChainSource, FakeChain};
+use crate::dev::{dev_privkey, DevAccount, DevChain, DevLock};
+use crate::server::{BatchStep, Rpc, RpcImpl, StaticCallResponse, TransactionReceipt};
+use crate::storage::{
+    Checkpoint, CsalRunContext, Indexer, IndexerHealth, MemoryStore, PendingPool, Runner,
+};
+use crate::types::{ChainScripts, EoaAddress, Program, MAINNET_GENESIS_HASH, ONE_CKB, SECP256K1};
+use crate::{entrance_message, entrance_signature_offsets, sign_transaction};
+
+const SENDER1_PRIVKEY: &str = "d00c06bfd800d27397002dca6fb0993d5ba6399b4238b2f29ee9deb97593d2bc";
+const SENDER2_PRIVKEY: &str = "3066aa42bfa95c6d033edfad9d1efb871991fd26f56270fedc171559823bee77";
+// BlockInfo.sol requires blockhash(0) to be the CKB dev chain genesis hash
+const DEV_CHAIN_GENESIS_HASH: &str =
+    "823b2ff5785b12da8b1363cac9a5cbe566d8b715a4311441b119c39a0367488c";
+
+const FN_GET: &str = "6d4ce63c";
+// keccak256("DoLog(address,uint256,bool)")
+const DO_LOG_TOPIC: &str = "33b708096f325a28269900b1f9361f84aa77ba6ca085f6b114e4a070a8239d52";
+
+type Account = DevAccount;
+
+struct Harness {
+    dev: Arc<DevChain>,
+    rpc: RpcImpl,
+    // Sender 1 (also the miner)
+    eoa1: Account,
+    // Sender 2
+    eoa2: Account,
+    // Another EoA of sender 2
+    eoa3: Account,
+    // Sender 2 with the sighash lock
+    eoa4: Account,
+    // The 2 of 2 multisig of sender 1 and 2, the account key is sender 1
+    eoa5: Account,
+}
+
+impl Harness {
+    fn new() -> Harness {
+        let read_binary = |var: &str, default_path: &str| {
+            let path = std::env::var(var)
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| manifest_path(default_path));
+            match fs::read(&path) {
+                Ok(data) => Bytes::from(data),
+                Err(err) => panic!(
+                    "Can not read {:?}: {}, build it by `make -C c all-via-docker` or set {}",
+                    path, err, var
+                ),
+            }
+        };
+        let generator = read_binary("POLYJUICE_GENERATOR", "c/build/generator");
+        let validator = read_binary("POLYJUICE_VALIDATOR", "c/build/validator");
+        let secp256k1_data = read_binary("POLYJUICE_SECP256K1_DATA", "c/build/secp256k1_data");
+
+        let privkey = |hex_str: &str| {
+            secp256k1::SecretKey::from_slice(&hex::decode(hex_str).unwrap()).unwrap()
+        };
+        let lock_arg = |hex_str: &str| {
+            let pubkey = secp256k1::PublicKey::from_secret_key(&SECP256K1, &privkey(hex_str));
+            H160::from_slice(&blake2b_256(&pubkey.serialize()[..])[0..20]).unwrap()
+        };
+        let multisig_config = MultisigConfig::new_with(
+            vec![lock_arg(SENDER1_PRIVKEY), lock_arg(SENDER2_PRIVKEY)],
+            0,
+            2,
+        )
+        .unwrap();
+        let dev = Arc::new(
+            DevChain::new(
+                generator,
+                Some(validator),
+                Some(secp256k1_data),
+                vec![
+                    (
+                        privkey(SENDER1_PRIVKEY),
+                        DevLock::AnyoneCanPay,
+                        100_000 * ONE_CKB,
+                    ),
+                    (
+                        privkey(SENDER2_PRIVKEY),
+                        DevLock::AnyoneCanPay,
+                        10_000 * ONE_CKB,
+                    ),
+                    (
+                        privkey(SENDER2_PRIVKEY),
+                        DevLock::AnyoneCanPay,
+                        100 * ONE_CKB,
+                    ),
+                    (privkey(SENDER2_PRIVKEY), DevLock::Sighash, 1000 * ONE_CKB),
+                    (
+                        privkey(SENDER1_PRIVKEY),
+                        DevLock::Multisig(multisig_config),
+                        1000 * ONE_CKB,
+                    ),
+                ],
+            )
+            .unwrap(),
+        );
+        let accounts = dev.accounts();
+        Harness {
+            rpc: dev.rpc(),
+            eoa1: accounts[0].clone(),
+            eoa2: accounts[1].clone(),
+            eoa3: accounts[2].clone(),
+            eoa4: accounts[3].clone(),
+            eoa5: accounts[4].clone(),
+            dev,
+        }
+    }
+
+    fn chain(&self) -> &FakeChain {
+        self.dev.chain()
+    }
+
+    // Build a transaction without signing or sending it
+    fn build<F>(&self, run: F) -> TransactionReceipt
+    where
+        F: FnOnce(&mut CsalRunContext) -> Result<(), Box<dyn StdError>>,
+    {
+        TransactionReceipt::build(&self.rpc.loader, |loader| {
+            let mut context = Runner::new(loader, self.rpc.run_config.clone())
+                .new_context()
+                .map_err(|err| err.to_string())?;
+            run(&mut context).map_err(|err| err.to_string())?;
+            Ok(context)
+        })
+        .unwrap()
+    }
+
+    fn sign(&self, sender: &Account, tx: Transaction) -> Transaction {
+        self.sign_with(sender, &sender.privkey, tx)
+    }
+
+    // Sign by another key of the sender, e.g. another member of a multisig
+    fn sign_with(
+        &self,
+        sender: &Account,
+        privkey: &secp256k1::SecretKey,
+        tx: Transaction,
+    ) -> Transaction {
+        let multisig_config = match &sender.lock {
+            DevLock::Multisig(config) => Some(config),
+            _ => None,
+        };
+        let mut chain = self.chain().clone();
+        sign_transaction(
+            tx,
+            privkey,
+            &self.rpc.run_config.eoa_lock_scripts(),
+            multisig_config,
+            &mut chain,
+        )
+        .unwrap()
+    }
+
+    // The transaction is rejected by the validator, drop it from the pending
+    // pool so the next transaction is built on the chain state
+    fn assert_rejected(&self, receipt: &TransactionReceipt, tx: Transaction) {
+        assert!(self.dev.send(tx).is_err());
+        self.rpc
+            .release_transaction(receipt.tx_hash.clone())
+            .unwrap();
+    }
+
+    fn mine(&self, blocks: usize) {
+        for _ in 0..blocks {
+            self.dev.mine(Vec::new()).unwrap();
+        }
+    }
+
+    // The dev RPC signs and mines the transaction before returning
+    fn create(&self, sender: &Account, code: &str, value: u64) -> TransactionReceipt {
+        self.rpc
+            .create(sender.address.clone(), hex_bytes(code), value)
+            .unwrap()
+    }
+
+    fn call(
+        &self,
+        sender: &Account,
+        address: &H160,
+        input: &str,
+        value: u64,
+    ) -> TransactionReceipt {
+        self.rpc
+            .call(
+                sender.address.clone(),
+                address.clone().into(),
+                hex_bytes(input),
+                value,
+            )
+            .unwrap()
+    }
+
+    fn static_call(&self, address: &H160, input: &str) -> StaticCallResponse {
+        self.rpc
+            .static_call(
+                self.eoa1.address.clone(),
+                address.clone().into(),
+                hex_bytes(input),
+            )
+            .unwrap()
+    }
+
+    fn return_data(&self, address: &H160, input: &str) -> String {
+        hex::encode(self.static_call(address, input).return_data.as_bytes())
+    }
+
+    fn balance(&self, address: &H160) -> u64 {
+        self.rpc.get_balance(address.clone()).unwrap()
+    }
+
+    fn storage(&self, address: &H160, slot: u64) -> Option<String> {
+        let key = H256::from_slice(&hex::decode(uint(slot)).unwrap()).unwrap();
+        self.rpc
+            .get_change(address.clone().into(), None)
+            .unwrap()
+            .new_storage
+            .into_iter()
+            .find(|(storage_key, _)| storage_key == &key)
+            .map(|(_, value)| hex::encode(value.as_bytes()))
+    }
+}
+
+fn manifest_path(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+fn contract_code(name: &str) -> String {
+    fs::read_to_string(manifest_path(&format!("tests/evm-contracts/{}.bin", name)))
+        .unwrap()
+        .trim()
+        .to_string()
+}
+
+fn hex_bytes(input: &str) -> JsonBytes {
+    JsonBytes::from_vec(hex::decode(input.trim_start_matches("0x")).unwrap())
+}
+
+fn uint(value: u64) -> String {
+    format!("{:064x}", value)
+}
+
+fn address_arg(address: &H160) -> String {
+    format!("{:0>64}", hex::encode(address.as_bytes()))
+}
+
+// Replace the signature of the nth entrance program of a signed transaction
+// An entrance signature of the transaction by any key
+fn entrance_signature(tx: &Transaction, privkey: &secp256k1::SecretKey) -> Vec<u8> {
+    let (message, _, _) = entrance_message(tx).unwrap();
+    let (recov_id, data) = SECP256K1
+        .sign_recoverable(&message, privkey)
+        .serialize_compact();
+    let mut signature = data.to_vec();
+    signature.push(recov_id.to_i32() as u8);
+    signature
+}
+
+fn set_entrance_signature(tx: &mut Transaction, n: usize, signature: &[u8]) {
+    let witness_args = packed::WitnessArgs::from_slice(tx.witnesses[0].as_bytes()).unwrap();
+    let is_output = witness_args.output_type().to_opt().is_some();
+    let witness_data = if is_output {
+        witness_args.output_type()
+    } else {
+        witness_args.input_type()
+    };
+    let mut raw_witness = witness_data.to_opt().unwrap().raw_data().to_vec();
+    let offset = entrance_signature_offsets(&raw_witness).unwrap()[n];
+    raw_witness[offset..offset + 65].copy_from_slice(signature);
+    let data = packed::BytesOpt::new_builder()
+        .set(Some(Bytes::from(raw_witness).pack()))
+        .build();
+    let witness_args = if is_output {
+        witness_args.as_builder().output_type(data).build()
+    } else {
+        witness_args.as_builder().input_type(data).build()
+    };
+    tx.witnesses[0] = JsonBytes::from_bytes(witness_args.as_bytes());
+}
+
+#[test]
+fn test_simple_storage() {
+    let harness = Harness::new();
+    let eoa1 = &harness.eoa1;
+    let address = harness
+        .create(eoa1, &contract_code("SimpleStorage"), 0)
+        .entrance_contract
+        .0;
+    assert_eq!(harness.return_data(&address, FN_GET), uint(123));
+    for value in &[0xd10, 0xccc] {
+        harness.call(eoa1, &address, &format!("60fe47b1{}", uint(*value)), 0);
+        assert_eq!(harness.return_data(&address, FN_GET), uint(*value));
+    }
+    assert_eq!(harness.storage(&address, 0), Some(uint(0xccc)));
+}
+
+#[test]
+fn test_batch() {
+    let harness = Harness::new();
+    let eoa1 = &harness.eoa1;
+    let call = |input: &str| BatchStep::Call {
+        contract_address: None,
+        input: hex_bytes(input),
+        value: 0,
+    };
+    let tip_number = harness.chain().tip_number();
+    let receipt = harness
+        .rpc
+        .batch(
+            eoa1.address.clone(),
+            vec![
+                BatchStep::Create {
+                    code: hex_bytes(&contract_code("SimpleStorage")),
+                    value: 0,
+                },
+                call(&format!("60fe47b1{}", uint(0xd10))),
+                call(FN_GET),
+            ],
+        )
+        .unwrap();
+    // All steps are in one transaction
+    assert_eq!(harness.chain().tip_number(), tip_number + 1);
+    assert_eq!(receipt.steps.len(), 3);
+    assert_eq!(
+        hex::encode(receipt.steps[2].return_data.as_bytes()),
+        uint(0xd10)
+    );
+    let address = receipt.entrance_contract.0;
+    assert_eq!(harness.return_data(&address, FN_GET), uint(0xd10));
+
+    // log()
+    let receipt = harness
+        .rpc
+        .batch(
+            eoa1.address.clone(),
+            vec![
+                BatchStep::Create {
+                    code: hex_bytes(&contract_code("LogEvents")),
+                    value: 0,
+                },
+                call("51973ec9"),
+            ],
+        )
+        .unwrap();
+    assert_eq!(receipt.logs.len(), 2);
+    assert_eq!(receipt.steps[0].logs.len(), 1);
+    assert_eq!(receipt.steps[1].logs.len(), 1);
+    assert_eq!(
+        hex::encode(receipt.steps[1].logs[0].data.as_bytes()),
+        format!("{}{}", uint(0), uint(0))
+    );
+}
+
+#[test]
+fn test_validator_entrance_programs() {
+    let harness = Harness::new();
+    let (eoa1, eoa2) = (&harness.eoa1, &harness.eoa2);
+    let address = harness
+        .create(eoa1, &contract_code("SimpleStorage"), 0)
+        .entrance_contract
+        .0;
+    let code = harness
+        .rpc
+        .get_code(address.clone().into())
+        .unwrap()
+        .code
+        .into_bytes();
+    let set = |value: u64| Bytes::from(hex::decode(format!("60fe47b1{}", uint(value))).unwrap());
+    let call = |context: &mut CsalRunContext, value: u64| {
+        context.call(
+            eoa1.address.clone(),
+            address.clone().into(),
+            set(value),
+            value,
+        )
+    };
+
+    // Mixed signers: an entrance program of another sender
+    let receipt = harness.build(|context| {
+        call(context, 1)?;
+        context.run(Program::new_call(
+            EoaAddress(eoa2.address.clone()),
+            eoa2.address.clone(),
+            address.clone(),
+            code.clone(),
+            set(2),
+            0,
+            false,
+        ))
+    });
+    harness.assert_rejected(&receipt, harness.sign(eoa1, receipt.tx.clone()));
+
+    // Forged entrance: the second entrance program is not signed by the sender
+    let receipt = harness.build(|context| {
+        call(context, 1)?;
+        call(context, 2)
+    });
+    let mut tx = harness.sign(eoa1, receipt.tx.clone());
+    let message = secp256k1::Message::from_slice(&[1u8; 32]).unwrap();
+    let (recov_id, data) = SECP256K1
+        .sign_recoverable(&message, &eoa2.privkey)
+        .serialize_compact();
+    let mut forged_signature = data.to_vec();
+    forged_signature.push(recov_id.to_i32() as u8);
+    set_entrance_signature(&mut tx, 1, &forged_signature);
+    harness.assert_rejected(&receipt, tx);
+
+    // Value bypass: the value of an unsigned program is not charged
+    let receipt = harness.build(|context| {
+        call(context, 1)?;
+        call(context, 2)
+    });
+    let mut tx = harness.sign(eoa1, receipt.tx.clone());
+    set_entrance_signature(&mut tx, 1, &[0u8; 65]);
+    harness.assert_rejected(&receipt, tx);
+
+    // Both signed
+    let receipt = harness.build(|context| {
+        call(context, 1)?;
+        call(context, 2)
+    });
+    harness
+        .dev
+        .send(harness.sign(eoa1, receipt.tx.clone()))
+        .unwrap();
+    assert_eq!(harness.return_data(&address, FN_GET), uint(2));
+    assert_eq!(harness.balance(&address), 3);
+}
+
+#[test]
+fn test_validator_create_capacity() {
+    let harness = Harness::new();
+    let eoa1 = &harness.eoa1;
+    let code = Bytes::from(hex::decode(contract_code("CreateContract")).unwrap());
+    let create =
+        |context: &mut CsalRunContext| context.create(eoa1.address.clone(), code.clone(), 0);
+
+    // The capacity of the contract created by the entrance contract is paid
+    // by the sender too, keeping it in the sender's cell is rejected
+    let receipt = harness.build(create);
+    assert_eq!(receipt.created_addresses.len(), 2);
+    let child_address = receipt.created_addresses[1].0.clone();
+    let mut tx = receipt.tx.clone();
+    let child_capacity = tx
+        .outputs
+        .iter()
+        .find(|output| {
+            output
+                .type_
+                .as_ref()
+                .map(|type_| type_.args.as_bytes() == child_address.as_bytes())
+                == Some(true)
+        })
+        .unwrap()
+        .capacity
+        .value();
+    let sender_output = tx
+        .outputs
+        .iter_mut()
+        .find(|output| output.lock.args.as_bytes() == eoa1.lock_arg.as_bytes())
+        .unwrap();
+    sender_output.capacity = (sender_output.capacity.value() + child_capacity).into();
+    harness.assert_rejected(&receipt, harness.sign(eoa1, tx));
+
+    let receipt = harness.build(create);
+    harness
+        .dev
+        .commit(&eoa1.address, receipt.tx.clone())
+        .unwrap();
+}
+
+#[test]
+fn test_validator_eoa_lock_hash_type() {
+    let harness = Harness::new();
+    let eoa1 = &harness.eoa1;
+    let code = Bytes::from(hex::decode(contract_code("SimpleStorage")).unwrap());
+    let create =
+        |context: &mut CsalRunContext| context.create(eoa1.address.clone(), code.clone(), 0);
+
+    // The anyone-can-pay data hash referenced by type hash is another lock,
+    // the change in such a cell leaves the sender without an EoA output
+    let receipt = harness.build(create);
+    let mut tx = receipt.tx.clone();
+    let sender_output = tx
+        .outputs
+        .iter_mut()
+        .find(|output| output.lock.args.as_bytes() == eoa1.lock_arg.as_bytes())
+        .unwrap();
+    assert_eq!(sender_output.lock.hash_type, ScriptHashType::Data);
+    sender_output.lock.hash_type = ScriptHashType::Type;
+    harness.assert_rejected(&receipt, harness.sign(eoa1, tx));
+
+    let receipt = harness.build(create);
+    harness
+        .dev
+        .commit(&eoa1.address, receipt.tx.clone())
+        .unwrap();
+}
+
+#[test]
+fn test_validator_eoa_lock_kept() {
+    let harness = Harness::new();
+    let eoa1 = &harness.eoa1;
+    let code = Bytes::from(hex::decode(contract_code("SimpleStorage")).unwrap());
+    let create =
+        |context: &mut CsalRunContext| context.create(eoa1.address.clone(), code.clone(), 0);
+
+    // Another anyone-can-pay keeps the address of the EoA, but the dev chain
+    // can not unlock it
+    let receipt = harness.build(create);
+    let mut tx = receipt.tx.clone();
+    let sender_output = tx
+        .outputs
+        .iter_mut()
+        .find(|output| output.lock.args.as_bytes() == eoa1.lock_arg.as_bytes())
+        .unwrap();
+    let mainnet_lock = ChainScripts::preset(&MAINNET_GENESIS_HASH)
+        .unwrap()
+        .anyone_can_pay_script;
+    sender_output.lock.code_hash = mainnet_lock.code_hash().unpack();
+    sender_output.lock.hash_type = ScriptHashType::Type;
+    harness.assert_rejected(&receipt, harness.sign(eoa1, tx));
+}
+
+#[test]
+fn test_validator_sighash_eoa() {
+    let harness = Harness::new();
+    let eoa4 = &harness.eoa4;
+    let address = harness
+        .create(eoa4, &contract_code("SimpleStorage"), 0)
+        .entrance_contract
+        .0;
+    harness.call(eoa4, &address, &format!("60fe47b1{}", uint(0x0d)), 0);
+    assert_eq!(harness.return_data(&address, FN_GET), uint(0x0d));
+
+    // The entrance program is signed by a key other than the lock's
+    let receipt = harness.build(|context| {
+        let input = hex::decode(format!("60fe47b1{}", uint(0x0e))).unwrap();
+        context.call(
+            eoa4.address.clone(),
+            address.clone().into(),
+            Bytes::from(input),
+            0,
+        )
+    });
+    let mut tx = harness.sign(eoa4, receipt.tx.clone());
+    let signature = entrance_signature(&tx, &harness.eoa1.privkey);
+    set_entrance_signature(&mut tx, 0, &signature);
+    harness.assert_rejected(&receipt, tx);
+}
+
+#[test]
+fn test_validator_multisig_eoa() {
+    let harness = Harness::new();
+    let (eoa2, eoa5) = (&harness.eoa2, &harness.eoa5);
+    let code = Bytes::from(hex::decode(contract_code("SimpleStorage")).unwrap());
+    let receipt = harness.build(|context| context.create(eoa5.address.clone(), code.clone(), 0));
+    // Both members sign the inputs, the entrance program is signed by the last one
+    let tx = harness.sign(eoa5, receipt.tx.clone());
+    harness
+        .dev
+        .send(harness.sign_with(eoa5, &eoa2.privkey, tx))
+        .unwrap();
+    let address = receipt.entrance_contract.0;
+    let set = |context: &mut CsalRunContext| {
+        let input = hex::decode(format!("60fe47b1{}", uint(0x0d))).unwrap();
+        context.call(
+            eoa5.address.clone(),
+            address.clone().into(),
+            Bytes::from(input),
+            0,
+        )
+    };
+
+    // The entrance program is signed by a key out of the multisig
+    let receipt = harness.build(set);
+    let tx = harness.sign(eoa5, receipt.tx.clone());
+    let mut tx = harness.sign_with(eoa5, &eoa2.privkey, tx);
+    let signature = entrance_signature(&tx, &dev_privkey(0));
+    set_entrance_signature(&mut tx, 0, &signature);
+    harness.assert_rejected(&receipt, tx);
+
+    // Signed by a member
+    let receipt = harness.build(set);
+    let tx = harness.sign(eoa5, receipt.tx.clone());
+    let mut tx = harness.sign_with(eoa5, &eoa2.privkey, tx);
+    let signature = entrance_signature(&tx, &eoa5.privkey);
+    set_entrance_signature(&mut tx, 0, &signature);
+    harness.dev.send(tx).unwrap();
+    assert_eq!(harness.return_data(&address, FN_GET), uint(0x0d));
+}
+
+#[test]
+fn test_checkpoint_after_contract() {
+    let harness = Harness::new();
+    harness.create(&harness.eoa1, &contract_code("SimpleStorage"), 0);
+    harness.mine(1);
+    // The contract is created before the checkpoint, its storage is unknown
+    let checkpoint = Checkpoint {
+        number: harness.chain().tip_number(),
+        ..Default::default()
+    };
+    let mut indexer = Indexer::new(
+        Arc::new(MemoryStore::new()),
+        Box::new(harness.chain().clone()),
+        harness.rpc.run_config.clone(),
+        Arc::new(Mutex::new(PendingPool::new(Duration::from_secs(60)))),
+        Some(checkpoint),
+        false,
+        Arc::new(Mutex::new(IndexerHealth::default())),
+    );
+    let err = indexer.index().unwrap_err();
+    assert!(err.to_string().contains("before the checkpoint block"));
+}
+
+#[test]
+fn test_release_transaction() {
+    let harness = Harness::new();
+    let address = harness
+        .create(&harness.eoa1, &contract_code("SimpleStorage"), 0)
+        .entrance_contract
+        .0;
+    // Build without sending it
+    let build = |value: u64| {
+        let input = hex::decode(format!("60fe47b1{}", uint(value))).unwrap();
+        TransactionReceipt::build(&harness.rpc.loader, |loader| {
+            Runner::new(loader, harness.rpc.run_config.clone())
+                .call(
+                    harness.eoa1.address.clone(),
+                    address.clone().into(),
+                    Bytes::from(input),
+                    0,
+                )
+                .map_err(|err| err.to_string())
+        })
+        .unwrap()
+    };
+    let spends = |receipt: &TransactionReceipt, tx_hash: &H256| {
+        receipt
+            .tx
+            .inputs
+            .iter()
+            .any(|input| &input.previous_output.tx_hash == tx_hash)
+    };
+    let receipt1 = build(1);
+    let receipt2 = build(2);
+    assert!(spends(&receipt2, &receipt1.tx_hash));
+
+    // Both are dropped, the next one spends the committed cells
+    harness
+        .rpc
+        .release_transaction(receipt1.tx_hash.clone())
+        .unwrap();
+    let receipt3 = build(3);
+    assert!(!spends(&receipt3, &receipt1.tx_hash));
+    assert!(!spends(&receipt3, &receipt2.tx_hash));
+}
+
+#[test]
+fn test_log_events() {
+    let harness = Harness::new();
+    let eoa1 = &harness.eoa1;
+    let receipt = harness.create(eoa1, &contract_code("LogEvents"), 0);
+    let address = receipt.entrance_contract.clone();
+    let sender_topic = H256::from_slice(&hex::decode(address_arg(&eoa1.address)).unwrap()).unwrap();
+    assert_eq!(receipt.logs.len(), 1);
+    let init_log = &receipt.logs[0];
+    assert_eq!(init_log.address, address);
+    assert_eq!(hex::encode(init_log.topics[0].as_bytes()), DO_LOG_TOPIC);
+    assert_eq!(init_log.topics[1], sender_topic);
+    // DoLog(msg.sender, msg.value, true)
+    assert_eq!(
+        hex::encode(init_log.data.as_bytes()),
+        format!("{}{}", uint(0), uint(1))
+    );
+
+    let logs = harness
+        .rpc
+        .get_logs(0, None, Some(address.clone()), None, None)
+        .unwrap();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].log.topics, init_log.topics);
+    assert_eq!(logs[0].log.data, init_log.data);
+
+    // log()
+    let response = harness.static_call(&address.0, "51973ec9");
+    assert_eq!(response.logs.len(), 1);
+    assert_eq!(response.logs[0].topics, init_log.topics);
+    assert_eq!(
+        hex::encode(response.logs[0].data.as_bytes()),
+        format!("{}{}", uint(0), uint(0))
+    );
+}
+
+#[test]
+fn test_self_destruct() {
+    let harness = Harness::new();
+    let (eoa1, eoa2) = (&harness.eoa1, &harness.eoa2);
+    let code = format!(
+        "{}{}",
+        contract_code("SelfDestruct"),
+        address_arg(&eoa2.address)
+    );
+    let address = harness.create(eoa1, &code, 0).entrance_contract;
+    let old_balance = harness.balance(&eoa2.address);
+    // done()
+    let receipt = harness.call(eoa1, &address.0, "ae8421e1", 0);
+    assert_eq!(receipt.destructed_addresses, vec![address.clone()]);
+    assert!(harness.balance(&eoa2.address) > old_balance);
+    let contracts = harness.rpc.get_contracts(0, None).unwrap();
+    let meta = contracts
+        .iter()
+        .find(|meta| meta.address == address)
+        .unwrap();
+    assert!(meta.destructed);
+}
+
+#[test]
+fn test_rollback_create_and_destruct() {
+    let harness = Harness::new();
+    let (eoa1, eoa2) = (&harness.eoa1, &harness.eoa2);
+    let code = format!(
+        "{}{}",
+        contract_code("SelfDestruct"),
+        address_arg(&eoa2.address)
+    );
+    let tip_number = harness.chain().tip_number();
+    // Created and destructed in the same block
+    let receipt = harness
+        .rpc
+        .batch(
+            eoa1.address.clone(),
+            vec![
+                BatchStep::Create {
+                    code: hex_bytes(&code),
+                    value: 0,
+                },
+                // done()
+                BatchStep::Call {
+                    contract_address: None,
+                    input: hex_bytes("ae8421e1"),
+                    value: 0,
+                },
+            ],
+        )
+        .unwrap();
+    let address = receipt.entrance_contract;
+    assert_eq!(receipt.destructed_addresses, vec![address.clone()]);
+    assert_eq!(harness.chain().tip_number(), tip_number + 1);
+
+    // Fork out the block, the indexer reverts both the creation and the
+    // destruction
+    harness.chain().rollback(tip_number);
+    harness.mine(2);
+    let contracts = harness.rpc.get_contracts(0, None).unwrap();
+    assert!(contracts.iter().all(|meta| meta.address != address));
+}
+
+#[test]
+fn test_erc20() {
+    let harness = Harness::new();
+    let (eoa1, eoa2, eoa3) = (&harness.eoa1, &harness.eoa2, &harness.eoa3);
+    let address = harness
+        .create(eoa1, &contract_code("ERC20"), 0)
+        .entrance_contract
+        .0;
+    let balance_of = |account: &Account| {
+        harness.return_data(
+            &address,
+            &format!("70a08231{}", address_arg(&account.address)),
+        )
+    };
+    assert_eq!(
+        balance_of(eoa1),
+        "000000000000000000000000000000000000000204fce5e3e250261100000000"
+    );
+    assert_eq!(balance_of(eoa2), uint(0));
+    // transfer(eoa2, 0x22b)
+    let input = format!("a9059cbb{}{}", address_arg(&eoa2.address), uint(0x22b));
+    harness.call(eoa1, &address, &input, 0);
+    assert_eq!(balance_of(eoa2), uint(0x22b));
+    // transfer(eoa2, 0x219)
+    let input = format!("a9059cbb{}{}", address_arg(&eoa2.address), uint(0x219));
+    harness.call(eoa1, &address, &input, 0);
+    assert_eq!(balance_of(eoa2), uint(0x444));
+    // burn(8908)
+    harness.call(eoa1, &address, &format!("42966c68{}", uint(0x22cc)), 0);
+    assert_eq!(
+        balance_of(eoa1),
+        "000000000000000000000000000000000000000204fce5e3e2502610ffffd8f0"
+    );
+    // approve(eoa3, 0x3e8)
+    let input = format!("095ea7b3{}{}", address_arg(&eoa3.address), uint(0x3e8));
+    harness.call(eoa1, &address, &input, 0);
+    // transferFrom(eoa1, eoa2, 0x3e8) by eoa3
+    let input = format!(
+        "23b872dd{}{}{}",
+        address_arg(&eoa1.address),
+        address_arg(&eoa2.address),
+        uint(0x3e8)
+    );
+    harness.call(eoa3, &address, &input, 0);
+    assert_eq!(balance_of(eoa2), uint(0x82c));
+}
+
+#[test]
+fn test_erc721_kitty_core() {
+    let harness = Harness::new();
+    let address = harness
+        .create(&harness.eoa1, &contract_code("KittyCore"), 0)
+        .entrance_contract;
+    let code = harness.rpc.get_code(address.clone()).unwrap().code;
+    assert!(!code.as_bytes().is_empty());
+    let contracts = harness.rpc.get_contracts(0, None).unwrap();
+    assert!(contracts.iter().any(|meta| meta.address == address));
+}
+
+#[test]
+fn test_contract_create_contract() {
+    let harness = Harness::new();
+    let receipt = harness.create(&harness.eoa1, &contract_code("CreateContract"), 0);
+    assert_eq!(receipt.created_addresses.len(), 2);
+    // DoLog(msg.sender, msg.value, 3)
+    assert_eq!(
+        hex::encode(receipt.logs[0].data.as_bytes()),
+        format!("{}{}", uint(0), uint(3))
+    );
+    let ss_address = &receipt.created_addresses[1].0;
+    assert_eq!(harness.return_data(ss_address, FN_GET), uint(0xff));
+    assert_eq!(harness.storage(ss_address, 0), Some(uint(0xff)));
+}
+
+#[test]
+fn test_contract_call_contract() {
+    let harness = Harness::new();
+    let eoa1 = &harness.eoa1;
+    let ss_address = harness
+        .create(eoa1, &contract_code("SimpleStorage"), 0)
+        .entrance_contract
+        .0;
+    let code = format!(
+        "{}{}",
+        contract_code("CallContract"),
+        address_arg(&ss_address)
+    );
+    let address = harness.create(eoa1, &code, 0).entrance_contract.0;
+    // proxySet(222)
+    harness.call(eoa1, &address, &format!("28cc7b25{}", uint(222)), 0);
+    assert_eq!(harness.return_data(&ss_address, FN_GET), uint(225));
+    assert_eq!(harness.storage(&ss_address, 0), Some(uint(225)));
+}
+
+#[test]
+fn test_call_multiple_times() {
+    let harness = Harness::new();
+    let eoa1 = &harness.eoa1;
+    let ss1_address = harness
+        .create(eoa1, &contract_code("SimpleStorage"), 0)
+        .entrance_contract
+        .0;
+    let ss2_address = harness
+        .create(eoa1, &contract_code("SimpleStorage"), 0)
+        .entrance_contract
+        .0;
+    let code = format!(
+        "{}{}",
+        contract_code("CallMultipleTimes"),
+        address_arg(&ss1_address)
+    );
+    let address = harness.create(eoa1, &code, 0).entrance_contract.0;
+    // proxySet(ss2, 20)
+    let input = format!("bca0b9c2{}{}", address_arg(&ss2_address), uint(20));
+    harness.call(eoa1, &address, &input, 0);
+    assert_eq!(harness.return_data(&ss1_address, FN_GET), uint(0x16));
+    assert_eq!(harness.return_data(&ss2_address, FN_GET), uint(0x19));
+}
+
+#[test]
+fn test_call_selfdestruct() {
+    let harness = Harness::new();
+    let (eoa1, eoa2) = (&harness.eoa1, &harness.eoa2);
+    let code = format!(
+        "{}{}",
+        contract_code("SelfDestruct"),
+        address_arg(&eoa2.address)
+    );
+    let destruct_address = harness.create(eoa1, &code, 0).entrance_contract.0;
+    let address = harness
+        .create(eoa1, &contract_code("CallSelfDestruct"), 0)
+        .entrance_contract
+        .0;
+    let old_balance = harness.balance(&eoa2.address);
+    // proxyDone(destruct_address)
+    let input = format!("9a33d968{}", address_arg(&destruct_address));
+    let receipt = harness.call(eoa1, &address, &input, 0);
+    assert_eq!(harness.balance(&eoa2.address) - old_balance, 15_800_000_000);
+
+    // The beneficiary's EoA cell
+    let output = &receipt.tx.outputs[1];
+    assert_eq!(output.lock.args.as_bytes(), eoa2.lock_arg.as_bytes());
+    let mut blake2b = new_blake2b();
+    blake2b.update(output.type_.as_ref().unwrap().args.as_bytes());
+    blake2b.update(output.lock.args.as_bytes());
+    let mut hash = [0u8; 32];
+    blake2b.finalize(&mut hash);
+    assert_eq!(&hash[0..20], eoa2.address.as_bytes());
+}
+
+#[test]
+fn test_get_block_info() {
+    let harness = Harness::new();
+    harness.mine(12);
+    let genesis_hash = harness
+        .chain()
+        .clone()
+        .get_header_by_number(0)
+        .unwrap()
+        .unwrap()
+        .hash;
+    let code = contract_code("BlockInfo").replace(
+        DEV_CHAIN_GENESIS_HASH,
+        &hex::encode(genesis_hash.as_bytes()),
+    );
+    // The contract is created at the tip block
+    let number = harness.chain().tip_number();
+    let address = harness.create(&harness.eoa1, &code, 0).entrance_contract.0;
+
+    // getNumber()
+    assert_eq!(harness.return_data(&address, "f2c9ecd8"), uint(number));
+    // getTimestamp(), in seconds
+    assert_eq!(harness.return_data(&address, "188ec356"), uint(number));
+    // getCoinbase()
+    assert_eq!(
+        harness.return_data(&address, "d1a82a9d"),
+        address_arg(&harness.eoa1.lock_arg)
+    );
+    // getGenesisHash()
+    assert_eq!(
+        harness.return_data(&address, "f6c99388"),
+        hex::encode(genesis_hash.as_bytes())
+    );
+}
+
+#[test]
+fn test_delegatecall() {
+    let harness = Harness::new();
+    let eoa1 = &harness.eoa1;
+    let address = harness
+        .create(eoa1, &contract_code("DelegateCall"), 0)
+        .entrance_contract
+        .0;
+    assert_eq!(harness.return_data(&address, FN_GET), uint(0x7b));
+    let ss_address = harness
+        .create(eoa1, &contract_code("SimpleStorage"), 0)
+        .entrance_contract
+        .0;
+
+    // set, overwrite, multiCall
+    for (function, expected) in &[("3825d828", 0x22), ("3144564b", 0x23), ("c6c211e9", 0x24)] {
+        let input = format!("{}{}{}", function, address_arg(&ss_address), uint(0x22));
+        harness.call(eoa1, &address, &input, 0);
+        assert_eq!(harness.return_data(&address, FN_GET), uint(*expected));
+        // Only the storage of the caller is changed
+        assert_eq!(harness.return_data(&ss_address, FN_GET), uint(0x7b));
+    }
+}
+
+#[test]
+fn test_simple_transfer() {
+    let harness = Harness::new();
+    let (eoa1, eoa3) = (&harness.eoa1, &harness.eoa3);
+    let address = harness
+        .create(eoa1, &contract_code("SimpleTransfer"), 3)
+        .entrance_contract
+        .0;
+    assert_eq!(harness.balance(&address), 3);
+    let ss_address = harness
+        .create(eoa1, &contract_code("SimpleStorage"), 0)
+        .entrance_contract
+        .0;
+
+    let check_balance = |function: &str, target: &H160, contract_delta: u64, value: u64| {
+        let old_contract_balance = harness.balance(&address);
+        let old_balance = harness.balance(target);
+        let input = format!("{}{}", function, address_arg(target));
+        harness.call(eoa1, &address, &input, value);
+        assert_eq!(harness.balance(target) - old_balance, 1);
+        assert_eq!(
+            old_contract_balance - harness.balance(&address),
+            contract_delta
+        );
+    };
+    let (transfer_to, transfer_to_ss1, transfer_to_ss2) = ("a03fa7e3", "f10c7360", "2a5eb963");
+    // Transfer to EoA address
+    check_balance(transfer_to, &eoa3.address, 1, 0);
+    check_balance(transfer_to, &eoa3.address, 0, 1);
+    // Transfer to contract address (storage unchanged)
+    check_balance(transfer_to, &ss_address, 1, 0);
+    check_balance(transfer_to, &ss_address, 0, 1);
+    // Transfer to contract and change target contract's storage
+    check_balance(transfer_to_ss1, &ss_address, 0, 1);
+    check_balance(transfer_to_ss2, &ss_address, 0, 1);
+    assert_eq!(harness.return_data(&ss_address, FN_GET), uint(3));
+
+    assert_eq!(harness.balance(&address), 1);
+}
@@ -1,9 +1,17 @@
+mod aggregator;
+mod chain;
 mod client;
+mod dev;
+#[cfg(test)]
+mod e2e;
 mod server;
 mod storage;
 mod types;
 
+use aggregator::Aggregator;
+use chain::{chain_scripts, ChainSource};
 use client::HttpRpcClient;
+use dev::{dev_privkey, DevChain, DevLock};
 use jsonrpc_core::IoHandler;
 use jsonrpc_http_server::ServerBuilder;
 use jsonrpc_server_utils::cors::AccessControlAllowOrigin;
@@ -11,15 +19,16 @@ use jsonrpc_server_utils::hosts::DomainsValidation;
 
 use ckb_hash::{blake2b_256, new_blake2b};
 use ckb_jsonrpc_types as json_types;
-use ckb_sdk::{build_signature, Address, AddressPayload, HumanCapacity, NetworkType};
+use ckb_sdk::{
+    build_signature, Address, AddressPayload, HumanCapacity, MultisigConfig, NetworkType,
+};
 use ckb_types::{
     bytes::{BufMut, Bytes, BytesMut},
-    h256, packed,
+    packed,
     prelude::*,
     H160, H256,
 };
 use clap::{App, Arg, SubCommand};
-use rocksdb::DB;
 use serde::{Deserialize, Serialize};
 use server::{Rpc, RpcImpl, TransactionReceipt};
 use std::collections::HashMap;
@@ -28,12 +37,16 @@ use std::process::Command;
 use std::str::FromStr;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use storage::{Indexer, Loader};
+use std::time::Duration;
+use storage::{
+    check_schema, clear_db, export_state, import_state, open_db, rollback_to, state_file_block,
+    Checkpoint, Indexer, IndexerHealth, Loader, PendingPool, Verifier,
+};
 use tempfile::NamedTempFile;
-use types::{CallKind, EoaAddress, Program, RunConfig, WitnessData, ONE_CKB, SECP256K1};
-
-const ANYONE_CAN_PAY_CODE_HASH: H256 =
-    h256!("0x8b10144daa110152e78dd002b44f429238cbbee5e62052205fdc6a1fc2c57a2a");
+use types::{
+    same_script_code, CallKind, EoaAddress, EoaLock, EoaLockKind, Program, RunConfig, WitnessData,
+    ALWAYS_SUCCESS_BINARY, ONE_CKB, SECP256K1,
+};
 
 fn main() -> Result<(), String> {
     env_logger::init();
@@ -44,6 +57,17 @@ fn main() -> Result<(), String> {
         .required(true)
         .default_value("http://127.0.0.1:8114")
         .help("The ckb rpc url");
+    let arg_db = Arg::with_name("db")
+        .long("db")
+        .takes_value(true)
+        .required(true)
+        .default_value("./data")
+        .help("Database directory");
+    let arg_state_file = Arg::with_name("file")
+        .long("file")
+        .takes_value(true)
+        .required(true)
+        .help("The state file");
     let arg_privkey = Arg::with_name("privkey")
         .long("privkey")
         .short("k")
@@ -55,44 +79,126 @@ fn main() -> Result<(), String> {
                 .map_err(|err| err.to_string())
         })
         .help("The private key file (hex)");
+    let arg_config = Arg::with_name("config")
+        .long("config")
+        .takes_value(true)
+        .validator(|input| {
+            fs::File::open(input)
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        })
+        .help("The config (json)");
+    let arg_generator = Arg::with_name("generator")
+        .long("generator")
+        .takes_value(true)
+        .required(true)
+        .validator(|input| {
+            fs::File::open(input)
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        })
+        .help("The generator riscv binary");
+    let arg_listen = Arg::with_name("listen")
+        .long("listen")
+        .takes_value(true)
+        .required(true)
+        .default_value("127.0.0.1:8214")
+        .help("Polyjuice rpc server listen address");
     let matches = App::new("polyjuice")
         .subcommand(
             SubCommand::with_name("run")
                 .about("Run the polyjuice server")
+                .arg(arg_generator.clone())
+                .arg(arg_config.clone().required(true))
+                .arg(arg_db.clone())
+                .arg(arg_listen.clone())
                 .arg(
-                    Arg::with_name("generator")
-                        .long("generator")
+                    Arg::with_name("pending-timeout")
+                        .long("pending-timeout")
                         .takes_value(true)
-                        .required(true)
-                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
-                        .help("The generator riscv binary")
+                        .default_value("120")
+                        .validator(|input| input.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                        .help("Drop pending transactions not committed after this many seconds")
                 )
                 .arg(
-                    Arg::with_name("config")
-                        .long("config")
+                    Arg::with_name("start-block")
+                        .long("start-block")
+                        .takes_value(true)
+                        .conflicts_with("checkpoint")
+                        .validator(|input| input.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                        .help("Start indexing from this block when the database is empty, rejected if a contract is created before it")
+                )
+                .arg(
+                    Arg::with_name("checkpoint")
+                        .long("checkpoint")
                         .takes_value(true)
-                        .required(true)
                         .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
-                        .help("The config (json)")
+                        .help("Start indexing from a trusted checkpoint (json: number, hash, eoa_cells) when the database is empty")
                 )
                 .arg(
-                    Arg::with_name("db")
-                        .long("db")
+                    Arg::with_name("invalid-tx")
+                        .long("invalid-tx")
                         .takes_value(true)
-                        .required(true)
-                        .default_value("./data")
-                        .help("Database directory")
+                        .possible_values(&["skip", "halt"])
+                        .default_value("halt")
+                        .help("Halt the indexer on an invalid polyjuice transaction, or skip it and stop serving the contracts it touches")
                 )
                 .arg(
-                    Arg::with_name("listen")
-                        .long("listen")
+                    Arg::with_name("aggregate-window")
+                        .long("aggregate-window")
                         .takes_value(true)
-                        .required(true)
-                        .default_value("127.0.0.1:8214")
-                        .help("Polyjuice rpc server listen address")
+                        .default_value("0")
+                        .validator(|input| input.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                        .help("Batch calls to the same contract within this many milliseconds (0 to disable)")
+                )
+                .arg(
+                    Arg::with_name("migrate")
+                        .long("migrate")
+                        .help("Upgrade the database to the current schema version before starting")
                 )
                 .arg(arg_ckb_url.clone())
         )
+        .subcommand(
+            SubCommand::with_name("dev")
+                .about("Run the polyjuice server on an in-memory chain with prefunded accounts, transactions are mined at once")
+                .arg(arg_generator.clone())
+                .arg(
+                    Arg::with_name("validator")
+                        .long("validator")
+                        .takes_value(true)
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The validator riscv binary to deploy")
+                )
+                .arg(
+                    Arg::with_name("secp256k1-data")
+                        .long("secp256k1-data")
+                        .takes_value(true)
+                        .requires("validator")
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The secp256k1 data (c/build/secp256k1_data), the scripts of every transaction are run before it is mined")
+                )
+                .arg(
+                    Arg::with_name("accounts")
+                        .long("accounts")
+                        .takes_value(true)
+                        .default_value("4")
+                        .validator(|input| match input.parse::<usize>() {
+                            Ok(0) => Err(String::from("At least one account is required")),
+                            Ok(_) => Ok(()),
+                            Err(err) => Err(err.to_string()),
+                        })
+                        .help("The number of prefunded accounts, the keys are fixed")
+                )
+                .arg(
+                    Arg::with_name("balance")
+                        .long("balance")
+                        .takes_value(true)
+                        .default_value("100000")
+                        .validator(|input| HumanCapacity::from_str(input.as_str()).map(|_| ()))
+                        .help("The balance of each account (unit: CKB)")
+                )
+                .arg(arg_listen.clone())
+        )
         .subcommand(
             SubCommand::with_name("sign-tx")
                 .about("Sign transaction generated by polyjuice")
@@ -113,6 +219,14 @@ fn main() -> Result<(), String> {
                         .takes_value(true)
                         .help("The output file path")
                 )
+                .arg(arg_config.clone().help("The config (json), the EoA locks are resolved from the chain if not provided"))
+                .arg(
+                    Arg::with_name("multisig-config")
+                        .long("multisig-config")
+                        .takes_value(true)
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The multisig config (json), required by multisig EoA")
+                )
                 .arg(arg_ckb_url.clone())
         )
         .subcommand(
@@ -127,6 +241,78 @@ fn main() -> Result<(), String> {
                         .validator(|input| HumanCapacity::from_str(input.as_str()).map(|_| ()))
                         .help("The balance to target EoA account (unit: CKB, format: 123.335, need extra capacity to create the cell)")
                 )
+                .arg(
+                    Arg::with_name("lock")
+                        .long("lock")
+                        .takes_value(true)
+                        .default_value("anyone_can_pay")
+                        .possible_values(&["anyone_can_pay", "sighash", "multisig"])
+                        .help("The lock of the EoA cell, only anyone_can_pay EoA can receive from others")
+                )
+                .arg(
+                    Arg::with_name("multisig-config")
+                        .long("multisig-config")
+                        .takes_value(true)
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The multisig config (json), required by multisig EoA")
+                )
+                .arg(arg_config.clone().help("The config (json), the EoA locks are resolved from the chain if not provided"))
+                .arg(arg_ckb_url.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("deploy-scripts")
+                .about("Deploy the scripts by ckb-cli and write the config for `run`")
+                .arg(arg_privkey.clone())
+                .arg(
+                    Arg::with_name("validator")
+                        .long("validator")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The validator riscv binary (type script of contract cells)")
+                )
+                .arg(
+                    Arg::with_name("generator")
+                        .long("generator")
+                        .takes_value(true)
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("Also deploy the generator riscv binary, so that it can be fetched from the chain")
+                )
+                .arg(
+                    Arg::with_name("contract-lock")
+                        .long("contract-lock")
+                        .takes_value(true)
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The lock script binary of contract cells [default: always success]")
+                )
+                .arg(
+                    Arg::with_name("eoa-lock")
+                        .long("eoa-lock")
+                        .takes_value(true)
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The lock script binary of EoA cells (tests/anyone_can_pay), deployed without type id [default: the deployed anyone-can-pay on mainnet/testnet]")
+                )
+                .arg(
+                    Arg::with_name("type-id")
+                        .long("type-id")
+                        .help("Deploy with type id, the scripts are referenced by type hash so that they can be upgraded")
+                )
+                .arg(
+                    Arg::with_name("tx-fee")
+                        .long("tx-fee")
+                        .takes_value(true)
+                        .default_value("0.001")
+                        .validator(|input| HumanCapacity::from_str(input.as_str()).map(|_| ()))
+                        .help("The transaction fee of each deployment (unit: CKB)")
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The config file path (json)")
+                )
                 .arg(arg_ckb_url.clone())
         )
         .subcommand(
@@ -187,6 +373,68 @@ fn main() -> Result<(), String> {
                         .validator(|input| parse_hex_binary(input.as_str()).map(|_| ()))
                         .help("The input data to create/call the contract, hex file path or hex string")
                 )
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Export/import the state for fast node bootstrap")
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Dump the state at the last indexed block")
+                        .arg(arg_db.clone())
+                        .arg(arg_state_file.clone())
+                        .arg(
+                            Arg::with_name("block")
+                                .long("block")
+                                .takes_value(true)
+                                .validator(|input| input.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                                .help("The block to export, must be the last indexed block (roll back by `reindex` first)")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Load a state file into an empty database")
+                        .arg(arg_db.clone())
+                        .arg(arg_state_file.clone().validator(|input| {
+                            fs::File::open(input)
+                                .map(|_| ())
+                                .map_err(|err| err.to_string())
+                        }))
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("reindex")
+                .about("Roll the database back before a block, `run` then reprocesses the blocks from CKB")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| match input.parse::<u64>() {
+                            Ok(0) => Err(String::from("Block 0 can not be reindexed, remove the database to resync")),
+                            Ok(_) => Ok(()),
+                            Err(err) => Err(err.to_string()),
+                        })
+                        .help("The first block to reindex")
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The state file to restore from when the rollback records are missing, must be before the first block")
+                )
+                .arg(arg_db.clone())
+                .arg(arg_ckb_url.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("db")
+                .about("Maintain the polyjuice database")
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Walk the database and report inconsistencies")
+                        .arg(arg_db.clone())
+                        .arg(arg_ckb_url.clone())
+                )
         ).get_matches();
 
     match matches.subcommand() {
@@ -194,63 +442,132 @@ fn main() -> Result<(), String> {
             let generator = fs::read(m.value_of("generator").unwrap())
                 .map(Bytes::from)
                 .map_err(|err| err.to_string())?;
-            let config_json: RunConfigJson = fs::read_to_string(m.value_of("config").unwrap())
-                .map_err(|err| err.to_string())
-                .and_then(|json_string| {
-                    serde_json::from_str(json_string.as_str()).map_err(|err| err.to_string())
-                })?;
+            let config_json = read_run_config(m.value_of("config").unwrap())?;
+            let ckb_uri = m.value_of("url").unwrap();
+            let sighash_dep = match config_json.sighash_dep {
+                Some(dep) => dep.into(),
+                None => chain_scripts(&mut HttpRpcClient::new(ckb_uri.to_string()))?.sighash_dep,
+            };
             let run_config = RunConfig {
                 generator,
                 type_dep: config_json.type_dep.into(),
                 type_script: config_json.type_script.into(),
                 lock_dep: config_json.lock_dep.into(),
                 lock_script: config_json.lock_script.into(),
+                legacy_locks: config_json
+                    .legacy_locks
+                    .into_iter()
+                    .map(|lock| (lock.lock_dep.into(), lock.lock_script.into()))
+                    .collect(),
                 eoa_lock_dep: config_json.eoa_lock_dep.into(),
                 eoa_lock_script: config_json.eoa_lock_script.into(),
+                extra_eoa_locks: config_json
+                    .extra_eoa_locks
+                    .into_iter()
+                    .map(EoaLock::from)
+                    .collect(),
+                sighash_dep,
             };
-            let ckb_uri = m.value_of("url").unwrap();
             let db_dir = m.value_of("db").unwrap();
             let listen_addr = m.value_of("listen").unwrap();
+            let pending_timeout: u64 = m.value_of("pending-timeout").unwrap().parse().unwrap();
+            let aggregate_window: u64 = m.value_of("aggregate-window").unwrap().parse().unwrap();
+            let skip_invalid_tx = m.value_of("invalid-tx") == Some("skip");
+            let checkpoint = if let Some(path) = m.value_of("checkpoint") {
+                let checkpoint: Checkpoint = fs::read_to_string(path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|json_string| {
+                        serde_json::from_str(json_string.as_str()).map_err(|err| err.to_string())
+                    })?;
+                Some(checkpoint)
+            } else {
+                m.value_of("start-block").map(|number| Checkpoint {
+                    number: number.parse().unwrap(),
+                    ..Default::default()
+                })
+            };
 
             log::info!("Open database: {:?}", db_dir);
-            let db = Arc::new(DB::open_default(db_dir).expect("rocksdb"));
-            let loader = Arc::new(Loader::new(Arc::clone(&db), ckb_uri).expect("loader failure"));
-            let mut indexer = Indexer::new(Arc::clone(&db), ckb_uri, run_config.clone());
-            let _ = thread::spawn(move || indexer.index().expect("indexer faliure"));
+            let db = Arc::new(open_db(db_dir)?);
+            check_schema(&db, ckb_uri, m.is_present("migrate"))?;
+            let pending = Arc::new(Mutex::new(PendingPool::new(Duration::from_secs(
+                pending_timeout,
+            ))));
+            let loader = Arc::new(
+                Loader::new(
+                    Arc::clone(&db),
+                    Box::new(HttpRpcClient::new(ckb_uri.to_string())),
+                    Arc::clone(&pending),
+                )
+                .expect("loader failure"),
+            );
+            let health = Arc::new(Mutex::new(IndexerHealth::default()));
+            let mut indexer = Indexer::new(
+                Arc::clone(&db),
+                Box::new(HttpRpcClient::new(ckb_uri.to_string())),
+                run_config.clone(),
+                Arc::clone(&pending),
+                checkpoint,
+                skip_invalid_tx,
+                Arc::clone(&health),
+            );
+            let _ = thread::spawn(move || indexer.run());
 
-            let mut io_handler = IoHandler::new();
-            io_handler.extend_with(
+            let aggregator = if aggregate_window > 0 {
+                Some(Arc::new(Aggregator::new(
+                    Arc::clone(&loader),
+                    run_config.clone(),
+                    Duration::from_millis(aggregate_window),
+                )))
+            } else {
+                None
+            };
+
+            serve(
                 RpcImpl {
                     loader: Arc::clone(&loader),
                     run_config,
-                }
-                .to_delegate(),
+                    aggregator,
+                    health,
+                    dev: None,
+                },
+                listen_addr,
             );
-
-            let rpc_server = ServerBuilder::new(io_handler)
-                .cors(DomainsValidation::AllowOnly(vec![
-                    AccessControlAllowOrigin::Null,
-                    AccessControlAllowOrigin::Any,
-                ]))
-                .threads(4)
-                .max_request_body_size(10_485_760)
-                .start_http(&listen_addr.parse().expect("parse listen address"))
-                .expect("jsonrpc initialize");
-            log::info!("RPC server listen on: {}", listen_addr);
-
-            // Wait for exit
-            let exit = Arc::new((Mutex::new(()), Condvar::new()));
-            let e = Arc::clone(&exit);
-            ctrlc::set_handler(move || {
-                e.1.notify_all();
-            })
-            .expect("error setting Ctrl-C handler");
-            let _guard = exit
-                .1
-                .wait(exit.0.lock().expect("locking"))
-                .expect("waiting");
-            rpc_server.close();
-            log::info!("exiting...");
+        }
+        ("dev", Some(m)) => {
+            let generator = fs::read(m.value_of("generator").unwrap())
+                .map(Bytes::from)
+                .map_err(|err| err.to_string())?;
+            let validator = m
+                .value_of("validator")
+                .map(|path| fs::read(path).map(Bytes::from))
+                .transpose()
+                .map_err(|err| err.to_string())?;
+            let secp256k1_data = m
+                .value_of("secp256k1-data")
+                .map(|path| fs::read(path).map(Bytes::from))
+                .transpose()
+                .map_err(|err| err.to_string())?;
+            let account_count: usize = m.value_of("accounts").unwrap().parse().unwrap();
+            let balance = HumanCapacity::from_str(m.value_of("balance").unwrap())?.0;
+            let listen_addr = m.value_of("listen").unwrap();
+            let accounts = (0..account_count)
+                .map(|index| (dev_privkey(index), DevLock::AnyoneCanPay, balance))
+                .collect::<Vec<_>>();
+            let dev_chain = Arc::new(DevChain::new(
+                generator,
+                validator,
+                secp256k1_data,
+                accounts,
+            )?);
+            for (index, account) in dev_chain.accounts().iter().enumerate() {
+                println!("Account #{}", index);
+                println!("  address:  {:#x}", account.address);
+                println!("  lock-arg: {:#x}", account.lock_arg);
+                println!("  privkey:  {}", hex::encode(&account.privkey[..]));
+                println!("  balance:  {} CKB", HumanCapacity(balance));
+            }
+            serve(dev_chain.rpc(), listen_addr);
         }
         ("sign-tx", Some(m)) => {
             let mut tx_receipt: TransactionReceipt =
@@ -269,191 +586,20 @@ fn main() -> Result<(), String> {
                 .and_then(|data| {
                     secp256k1::SecretKey::from_slice(data.as_slice()).map_err(|err| err.to_string())
                 })?;
-            let pubkey = secp256k1::PublicKey::from_secret_key(&SECP256K1, &privkey);
-            let tx_origin_lock_arg =
-                H160::from_slice(&blake2b_256(&pubkey.serialize()[..])[0..20]).unwrap();
             let ckb_uri = m.value_of("url").unwrap();
-
-            println!("Building signature");
-            let tx = packed::Transaction::from(tx_receipt.tx.clone());
-            let tx_hash: H256 = tx.calc_tx_hash().unpack();
-            let (message, entrance_witness_args, entrance_raw_witness) = {
-                let mut entrance_witness = None;
-                let mut unsigned_data = BytesMut::default();
-                unsigned_data.put(tx_hash.as_bytes());
-                let mut output_witnesses = Vec::new();
-                for (idx, witness) in tx_receipt.tx.witnesses.iter().enumerate() {
-                    let witness_args = packed::WitnessArgs::from_slice(witness.as_bytes())
-                        .map_err(|err| err.to_string())?;
-                    if let Some(raw_witness) = witness_args
-                        .input_type()
-                        .to_opt()
-                        .map(|witness_data| witness_data.raw_data())
-                    {
-                        if idx == 0 {
-                            entrance_witness = Some((witness_args, raw_witness.clone()));
-                            let mut raw_witness = raw_witness.as_ref().to_vec();
-                            // 4 bytes is for program length (u32)
-                            raw_witness[4..4 + 65].copy_from_slice(&[0u8; 65][..]);
-                            unsigned_data.put(&raw_witness[..]);
-                        } else {
-                            unsigned_data.put(raw_witness.as_ref());
-                        }
-                    } else if let Some(raw_witness) = witness_args
-                        .output_type()
-                        .to_opt()
-                        .map(|witness_data| witness_data.raw_data())
-                    {
-                        output_witnesses.push((idx, witness_args, raw_witness));
-                    }
-                }
-                for (idx, witness_args, raw_witness) in output_witnesses {
-                    if idx == 0 {
-                        entrance_witness = Some((witness_args, raw_witness.clone()));
-                        let mut raw_witness = raw_witness.as_ref().to_vec();
-                        // 4 bytes is for program length (u32)
-                        raw_witness[4..4 + 65].copy_from_slice(&[0u8; 65][..]);
-                        unsigned_data.put(&raw_witness[..]);
-                    } else {
-                        unsigned_data.put(raw_witness.as_ref());
-                    }
-                }
-
-                let (entrance_witness_args, entrance_raw_witness) =
-                    entrance_witness.ok_or_else(|| String::from("No entrance witness found"))?;
-                let message = secp256k1::Message::from_slice(&blake2b_256(&unsigned_data)[..])
-                    .map_err(|err| err.to_string())?;
-                (message, entrance_witness_args, entrance_raw_witness)
-            };
-            let signature = SECP256K1.sign_recoverable(&message, &privkey);
-            let (recov_id, data) = signature.serialize_compact();
-            let mut signature_bytes = [0u8; 65];
-            signature_bytes[0..64].copy_from_slice(&data[0..64]);
-            signature_bytes[64] = recov_id.to_i32() as u8;
-
-            println!("Rebuilding witness");
-            let mut raw_witness = entrance_raw_witness.as_ref().to_vec();
-            raw_witness[4..4 + 65].copy_from_slice(&signature_bytes[..]);
-            let data = packed::BytesOpt::new_builder()
-                .set(Some(Bytes::from(raw_witness).pack()))
-                .build();
-            let witness = if entrance_witness_args.output_type().to_opt().is_some() {
-                entrance_witness_args.as_builder().output_type(data).build()
-            } else {
-                entrance_witness_args.as_builder().input_type(data).build()
-            };
-
-            tx_receipt.tx.witnesses[0] = json_types::JsonBytes::from_bytes(witness.as_bytes());
-            while tx_receipt.tx.witnesses.len() < tx_receipt.tx.inputs.len() {
-                tx_receipt.tx.witnesses.push(Default::default());
-            }
-
-            println!("Sign anyone can pay");
-            let tx_view = packed::Transaction::from(tx_receipt.tx.clone()).into_view();
             let mut client = HttpRpcClient::new(ckb_uri.to_string());
-            // {lock_arg => {type_hash => (input_index, input_capacity, output_capacity)}}
-            let mut eoa_cells: HashMap<H160, HashMap<H256, (usize, u64, u64)>> = Default::default();
-            for (idx, input) in tx_view.inputs().into_iter().enumerate() {
-                let output: packed::CellOutput =
-                    get_live_cell(&mut client, input.previous_output().into(), false)?.into();
-                let lock_script = output.lock();
-                let code_hash: H256 = lock_script.code_hash().unpack();
-                if code_hash == ANYONE_CAN_PAY_CODE_HASH {
-                    let type_hash: H256 = output
-                        .type_()
-                        .to_opt()
-                        .expect("type id type script should exists")
-                        .calc_script_hash()
-                        .unpack();
-                    let input_capacity: u64 = output.capacity().unpack();
-                    let lock_arg =
-                        H160::from_slice(lock_script.args().raw_data().as_ref()).unwrap();
-                    let value = eoa_cells.entry(lock_arg).or_default();
-                    if value.contains_key(&type_hash) {
-                        return Err(format!("duplicated type script hash: {:x}", type_hash));
-                    }
-                    value.insert(type_hash, (idx, input_capacity, 0));
-                }
-            }
-            for output in tx_view.outputs().into_iter() {
-                let lock_script = output.lock();
-                let code_hash: H256 = lock_script.code_hash().unpack();
-                if code_hash == ANYONE_CAN_PAY_CODE_HASH {
-                    let type_hash: H256 = output
-                        .type_()
-                        .to_opt()
-                        .expect("type id type script should exists")
-                        .calc_script_hash()
-                        .unpack();
-                    let output_capacity: u64 = output.capacity().unpack();
-                    let lock_arg =
-                        H160::from_slice(lock_script.args().raw_data().as_ref()).unwrap();
-                    if let Some(value) = eoa_cells.get_mut(&lock_arg) {
-                        if let Some(inner_value) = value.get_mut(&type_hash) {
-                            inner_value.2 = output_capacity;
-                        } else {
-                            return Err(format!("type hash not found in output: {:x}", type_hash));
-                        }
-                    } else {
-                        return Err(format!("lock arg not found in output: {:x}", lock_arg));
-                    }
-                }
-            }
-            for (lock_arg, type_scripts) in eoa_cells {
-                let mut need_signature = false;
-                let mut idxs = type_scripts
-                    .values()
-                    .map(|(idx, _, _)| *idx)
-                    .collect::<Vec<_>>();
-                idxs.sort();
-                for (_, input_capacity, output_capacity) in type_scripts.values() {
-                    if input_capacity > output_capacity {
-                        need_signature = true;
-                        break;
-                    }
-                }
-
-                let lock_field = if need_signature {
-                    if lock_arg != tx_origin_lock_arg {
-                        return Err(format!("The only tx_origin need anyone can pay signature, current lock arg: {:x}", lock_arg));
-                    }
-                    let input_size = tx_view.inputs().len();
-                    let witnesses: Vec<packed::Bytes> = tx_view.witnesses().into_iter().collect();
-                    let signature = build_signature(
-                        &tx_view,
-                        input_size,
-                        &idxs,
-                        &witnesses,
-                        None,
-                        |message: &H256, _tx| {
-                            let message =
-                                secp256k1::Message::from_slice(message.as_bytes()).unwrap();
-                            Ok(serialize_signature(
-                                &SECP256K1.sign_recoverable(&message, &privkey),
-                            ))
-                        },
-                    )?;
-
-                    Some(signature)
-                } else {
-                    Some(Default::default())
-                };
-
-                let first_witness = &tx_receipt.tx.witnesses[idxs[0]];
-                let init_witness = if first_witness.is_empty() {
-                    packed::WitnessArgs::default()
-                } else {
-                    packed::WitnessArgs::from_slice(first_witness.as_bytes())
-                        .map_err(|err| err.to_string())?
-                };
-                tx_receipt.tx.witnesses[idxs[0]] = json_types::JsonBytes::from_bytes(
-                    init_witness
-                        .as_builder()
-                        .lock(lock_field.pack())
-                        .build()
-                        .as_bytes(),
-                );
-            }
+            let eoa_lock_scripts = load_eoa_lock_scripts(m.value_of("config"), &mut client)?;
+            let multisig_config = m
+                .value_of("multisig-config")
+                .map(read_multisig_config)
+                .transpose()?;
+            tx_receipt.tx = sign_transaction(
+                tx_receipt.tx,
+                &privkey,
+                &eoa_lock_scripts,
+                multisig_config.as_ref(),
+                &mut client,
+            )?;
 
             let tx_file = NamedTempFile::new().map_err(|err| err.to_string())?;
             let tx_path_str = tx_file.path().to_str().unwrap();
@@ -503,14 +649,30 @@ fn main() -> Result<(), String> {
             let chain_info = client.get_blockchain_info()?;
             let network = NetworkType::from_raw_str(chain_info.chain.as_str())
                 .ok_or_else(|| format!("Unexpected network type: {}", chain_info.chain))?;
-            // FIXME: transfer to anyone-can-pay
-            let pubkey = secp256k1::PublicKey::from_secret_key(&SECP256K1, &privkey);
-            let lock_arg = H160::from_slice(&blake2b_256(&pubkey.serialize()[..])[0..20]).unwrap();
+            let kind: EoaLockKind =
+                serde_json::from_str(&format!("\"{}\"", m.value_of("lock").unwrap())).unwrap();
+            let eoa_lock_script = load_eoa_lock_scripts(m.value_of("config"), &mut client)?
+                .into_iter()
+                .find(|(lock_kind, _)| lock_kind == &kind)
+                .map(|(_, script)| script)
+                .ok_or_else(|| format!("EoA lock {:?} is not configured", kind))?;
+            let lock_arg = if kind == EoaLockKind::Multisig {
+                let path = m
+                    .value_of("multisig-config")
+                    .ok_or_else(|| String::from("--multisig-config is required"))?;
+                read_multisig_config(path)?.hash160()
+            } else {
+                let pubkey = secp256k1::PublicKey::from_secret_key(&SECP256K1, &privkey);
+                H160::from_slice(&blake2b_256(&pubkey.serialize()[..])[0..20]).unwrap()
+            };
             println!("[lock-arg]: 0x{:x}", lock_arg);
-            let address_payload = AddressPayload::new_full_data(
-                ANYONE_CAN_PAY_CODE_HASH.pack(),
-                Bytes::from(lock_arg.as_bytes().to_vec()),
-            );
+            let lock_args = Bytes::from(lock_arg.as_bytes().to_vec());
+            let hash_type = json_types::Script::from(eoa_lock_script.clone()).hash_type;
+            let address_payload = if hash_type == json_types::ScriptHashType::Type {
+                AddressPayload::new_full_type(eoa_lock_script.code_hash(), lock_args)
+            } else {
+                AddressPayload::new_full_data(eoa_lock_script.code_hash(), lock_args)
+            };
             let address_string = Address::new(network, address_payload).to_string();
             println!(
                 "[Command]: ckb-cli wallet transfer --privkey-path {} --to-address {} --capacity {} --tx-fee 0.001 --type-id --skip-check-to-address",
@@ -557,6 +719,99 @@ fn main() -> Result<(), String> {
             blake2b.finalize(&mut ret);
             println!("0x{}", hex::encode(&ret[0..20]));
         }
+        ("deploy-scripts", Some(m)) => {
+            let ckb_uri = m.value_of("url").unwrap();
+            let privkey_path = m.value_of("privkey").unwrap();
+            let privkey = fs::read_to_string(privkey_path)
+                .map_err(|err| err.to_string())
+                .and_then(|privkey| {
+                    hex::decode(&privkey.trim().as_bytes()[0..64]).map_err(|err| err.to_string())
+                })
+                .and_then(|data| {
+                    secp256k1::SecretKey::from_slice(data.as_slice()).map_err(|err| err.to_string())
+                })?;
+            let mut client = HttpRpcClient::new(ckb_uri.to_string());
+            let chain_info = client.get_blockchain_info()?;
+            let network = NetworkType::from_raw_str(chain_info.chain.as_str())
+                .ok_or_else(|| format!("Unexpected network type: {}", chain_info.chain))?;
+            // The deployed cells are owned by the deployer
+            let pubkey = secp256k1::PublicKey::from_secret_key(&SECP256K1, &privkey);
+            let owner_address = Address::new(network, AddressPayload::from_pubkey(&pubkey));
+            let deployer = ScriptDeployer {
+                ckb_uri,
+                privkey_path,
+                owner_address: owner_address.to_string(),
+                tx_fee: m.value_of("tx-fee").unwrap(),
+                type_id: m.is_present("type-id"),
+            };
+            let scripts = chain_scripts(&mut client)?;
+            let anyone_can_pay_dep = scripts.anyone_can_pay_dep.clone();
+            if m.value_of("eoa-lock").is_none() && anyone_can_pay_dep.is_none() {
+                return Err(String::from(
+                    "No anyone-can-pay deployed on this chain, use --eoa-lock",
+                ));
+            }
+
+            let (type_dep, type_script) =
+                deployer.deploy(&mut client, m.value_of("validator").unwrap())?;
+            if let Some(generator_path) = m.value_of("generator") {
+                let (generator_dep, _) = deployer.deploy(&mut client, generator_path)?;
+                println!(
+                    "[generator]: {}",
+                    serde_json::to_string(&generator_dep.out_point).unwrap()
+                );
+            }
+            let always_success_file = NamedTempFile::new().map_err(|err| err.to_string())?;
+            let contract_lock_path = match m.value_of("contract-lock") {
+                Some(path) => path,
+                None => {
+                    fs::write(
+                        always_success_file.path(),
+                        hex::decode(ALWAYS_SUCCESS_BINARY).unwrap(),
+                    )
+                    .map_err(|err| err.to_string())?;
+                    always_success_file.path().to_str().unwrap()
+                }
+            };
+            let (lock_dep, lock_script) = deployer.deploy(&mut client, contract_lock_path)?;
+            let extra_eoa_locks = scripts
+                .system_eoa_locks()
+                .into_iter()
+                .map(EoaLockJson::from)
+                .collect();
+            // The validator recognizes a deployed anyone-can-pay by data hash
+            let eoa_lock_deployer = ScriptDeployer {
+                type_id: false,
+                ..deployer
+            };
+            let (eoa_lock_dep, eoa_lock_script) = match (m.value_of("eoa-lock"), anyone_can_pay_dep)
+            {
+                (Some(path), _) => eoa_lock_deployer.deploy(&mut client, path)?,
+                (None, Some(dep)) => (dep.into(), scripts.anyone_can_pay_script.into()),
+                (None, None) => unreachable!(),
+            };
+
+            let config_json = RunConfigJson {
+                type_dep,
+                type_script,
+                lock_dep,
+                lock_script,
+                legacy_locks: Vec::new(),
+                eoa_lock_dep,
+                eoa_lock_script,
+                extra_eoa_locks,
+                sighash_dep: Some(scripts.sighash_dep.into()),
+            };
+            let output = m.value_of("output").unwrap();
+            fs::write(
+                output,
+                serde_json::to_string_pretty(&config_json)
+                    .unwrap()
+                    .as_bytes(),
+            )
+            .map_err(|err| err.to_string())?;
+            println!("[config]: {}", output);
+        }
         ("build-tx", Some(m)) => {
             let signature = m
                 .value_of("signature")
@@ -605,13 +860,369 @@ fn main() -> Result<(), String> {
             );
             println!("[binary]: {}", hex::encode(program_data.as_ref()));
         }
+        ("snapshot", Some(m)) => match m.subcommand() {
+            ("export", Some(m)) => {
+                let db = open_db(m.value_of("db").unwrap())?;
+                let block = m.value_of("block").map(|input| input.parse().unwrap());
+                let last = export_state(&db, block, m.value_of("file").unwrap())?;
+                println!("Exported state at block #{} {:#x}", last.number, last.hash);
+            }
+            ("import", Some(m)) => {
+                let db = open_db(m.value_of("db").unwrap())?;
+                let last = import_state(&db, m.value_of("file").unwrap())?;
+                println!("Imported state at block #{} {:#x}", last.number, last.hash);
+            }
+            _ => println!("{}", m.usage()),
+        },
+        ("reindex", Some(m)) => {
+            let from: u64 = m.value_of("from").unwrap().parse().unwrap();
+            let ckb_uri = m.value_of("url").unwrap();
+            let db = open_db(m.value_of("db").unwrap())?;
+            check_schema(&db, ckb_uri, false)?;
+            let last = match (rollback_to(&db, from - 1), m.value_of("file")) {
+                (Ok(last), _) => last,
+                (Err(err), Some(path)) => {
+                    log::warn!("Can not roll back: {}, restore from {}", err, path);
+                    let state_last = state_file_block(path)?;
+                    if state_last.number >= from {
+                        return Err(format!(
+                            "The state file is at block #{}, not before block #{}",
+                            state_last.number, from
+                        ));
+                    }
+                    clear_db(&db)?;
+                    import_state(&db, path)?
+                }
+                (Err(err), None) => return Err(err),
+            };
+            println!(
+                "Rolled back to block #{} {:#x}, start polyjuice to reindex from block #{}",
+                last.number,
+                last.hash,
+                last.number + 1
+            );
+        }
+        ("db", Some(m)) => match m.subcommand() {
+            ("verify", Some(m)) => {
+                let ckb_uri = m.value_of("url").unwrap();
+                let db_dir = m.value_of("db").unwrap();
+                log::info!("Open database: {:?}", db_dir);
+                let db = Arc::new(open_db(db_dir)?);
+                check_schema(&db, ckb_uri, false)?;
+                let problems =
+                    Verifier::new(db, Box::new(HttpRpcClient::new(ckb_uri.to_string())))?
+                        .verify()?;
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                if !problems.is_empty() {
+                    return Err(format!("{} problems found", problems.len()));
+                }
+                println!("No problems found");
+            }
+            _ => println!("{}", m.usage()),
+        },
         _ => println!("{}", matches.usage()),
     }
     Ok(())
 }
 
+/// Sign the entrance program and the EoA inputs of a transaction built by
+/// `create`/`call`, the input cells are read from the chain. A multisig EoA
+/// input is signed by one member, the signatures are appended in the lock.
+pub fn sign_transaction(
+    mut tx: json_types::Transaction,
+    privkey: &secp256k1::SecretKey,
+    eoa_lock_scripts: &[(EoaLockKind, packed::Script)],
+    multisig_config: Option<&MultisigConfig>,
+    client: &mut dyn ChainSource,
+) -> Result<json_types::Transaction, String> {
+    let pubkey = secp256k1::PublicKey::from_secret_key(&SECP256K1, privkey);
+    let tx_origin_lock_arg =
+        H160::from_slice(&blake2b_256(&pubkey.serialize()[..])[0..20]).unwrap();
+
+    log::debug!("Building signature");
+    let (message, entrance_witness_args, entrance_raw_witness) = entrance_message(&tx)?;
+    let signature = SECP256K1.sign_recoverable(&message, privkey);
+    let (recov_id, data) = signature.serialize_compact();
+    let mut signature_bytes = [0u8; 65];
+    signature_bytes[0..64].copy_from_slice(&data[0..64]);
+    signature_bytes[64] = recov_id.to_i32() as u8;
+
+    log::debug!("Rebuilding witness");
+    let mut raw_witness = entrance_raw_witness.as_ref().to_vec();
+    for offset in entrance_signature_offsets(&raw_witness)? {
+        raw_witness[offset..offset + 65].copy_from_slice(&signature_bytes[..]);
+    }
+    let data = packed::BytesOpt::new_builder()
+        .set(Some(Bytes::from(raw_witness).pack()))
+        .build();
+    let witness = if entrance_witness_args.output_type().to_opt().is_some() {
+        entrance_witness_args.as_builder().output_type(data).build()
+    } else {
+        entrance_witness_args.as_builder().input_type(data).build()
+    };
+
+    tx.witnesses[0] = json_types::JsonBytes::from_bytes(witness.as_bytes());
+    while tx.witnesses.len() < tx.inputs.len() {
+        tx.witnesses.push(Default::default());
+    }
+
+    log::debug!("Signing EoA inputs");
+    let eoa_lock_kind = |lock_script: &packed::Script| {
+        eoa_lock_scripts
+            .iter()
+            .find(|(_, script)| same_script_code(script, lock_script))
+            .map(|(kind, _)| *kind)
+    };
+    let tx_view = packed::Transaction::from(tx.clone()).into_view();
+    // {lock_hash => (kind, lock_args, {type_hash => (input_index, input_capacity, output_capacity)})}
+    let mut eoa_cells: HashMap<H256, (EoaLockKind, Bytes, HashMap<H256, (usize, u64, u64)>)> =
+        Default::default();
+    for (idx, input) in tx_view.inputs().into_iter().enumerate() {
+        let output: packed::CellOutput =
+            get_live_cell(client, input.previous_output().into(), false)?.into();
+        let lock_script = output.lock();
+        if let Some(kind) = eoa_lock_kind(&lock_script) {
+            let type_hash: H256 = output
+                .type_()
+                .to_opt()
+                .expect("type id type script should exists")
+                .calc_script_hash()
+                .unpack();
+            let input_capacity: u64 = output.capacity().unpack();
+            let value = eoa_cells
+                .entry(lock_script.calc_script_hash().unpack())
+                .or_insert_with(|| (kind, lock_script.args().raw_data(), Default::default()));
+            if value.2.contains_key(&type_hash) {
+                return Err(format!("duplicated type script hash: {:x}", type_hash));
+            }
+            value.2.insert(type_hash, (idx, input_capacity, 0));
+        }
+    }
+    for output in tx_view.outputs().into_iter() {
+        let lock_script = output.lock();
+        if eoa_lock_kind(&lock_script).is_some() {
+            let type_hash: H256 = output
+                .type_()
+                .to_opt()
+                .expect("type id type script should exists")
+                .calc_script_hash()
+                .unpack();
+            let output_capacity: u64 = output.capacity().unpack();
+            let lock_hash: H256 = lock_script.calc_script_hash().unpack();
+            if let Some(value) = eoa_cells.get_mut(&lock_hash) {
+                if let Some(inner_value) = value.2.get_mut(&type_hash) {
+                    inner_value.2 = output_capacity;
+                } else {
+                    return Err(format!("type hash not found in output: {:x}", type_hash));
+                }
+            } else {
+                return Err(format!("lock hash not found in output: {:x}", lock_hash));
+            }
+        }
+    }
+    for (_, (kind, lock_args, type_scripts)) in eoa_cells {
+        let mut idxs = type_scripts
+            .values()
+            .map(|(idx, _, _)| *idx)
+            .collect::<Vec<_>>();
+        idxs.sort();
+        // Only anyone can pay lock accepts unsigned inputs
+        let need_signature = !kind.can_receive()
+            || type_scripts
+                .values()
+                .any(|(_, input_capacity, output_capacity)| input_capacity > output_capacity);
+
+        let first_witness = &tx.witnesses[idxs[0]];
+        let init_witness = if first_witness.is_empty() {
+            packed::WitnessArgs::default()
+        } else {
+            packed::WitnessArgs::from_slice(first_witness.as_bytes())
+                .map_err(|err| err.to_string())?
+        };
+        let lock_field = if need_signature {
+            let multisig_config = match kind {
+                EoaLockKind::Multisig => {
+                    let config = multisig_config.ok_or_else(|| {
+                        format!("Multisig config is required by EoA lock: {:x}", lock_args)
+                    })?;
+                    if lock_args.len() < 20 || config.hash160().as_bytes() != &lock_args[0..20] {
+                        return Err(format!(
+                            "Multisig config not match the EoA lock: {:x}",
+                            lock_args
+                        ));
+                    }
+                    Some(config)
+                }
+                _ => {
+                    if lock_args.as_ref() != tx_origin_lock_arg.as_bytes() {
+                        return Err(format!(
+                            "The only tx_origin need {:?} signature, current lock arg: {:x}",
+                            kind, lock_args
+                        ));
+                    }
+                    None
+                }
+            };
+            let input_size = tx_view.inputs().len();
+            let witnesses: Vec<packed::Bytes> = tx_view.witnesses().into_iter().collect();
+            let signature = build_signature(
+                &tx_view,
+                input_size,
+                &idxs,
+                &witnesses,
+                multisig_config,
+                |message: &H256, _tx| {
+                    let message = secp256k1::Message::from_slice(message.as_bytes()).unwrap();
+                    Ok(serialize_signature(
+                        &SECP256K1.sign_recoverable(&message, privkey),
+                    ))
+                },
+            )?;
+
+            match multisig_config {
+                Some(config) => {
+                    // Append to the signatures of other members
+                    let witness_data = config.to_witness_data();
+                    let mut lock_field = init_witness
+                        .lock()
+                        .to_opt()
+                        .map(|lock| lock.raw_data().to_vec())
+                        .filter(|lock| lock.starts_with(&witness_data))
+                        .unwrap_or(witness_data);
+                    lock_field.extend_from_slice(&signature);
+                    Some(Bytes::from(lock_field))
+                }
+                None => Some(signature),
+            }
+        } else {
+            Some(Default::default())
+        };
+
+        tx.witnesses[idxs[0]] = json_types::JsonBytes::from_bytes(
+            init_witness
+                .as_builder()
+                .lock(lock_field.pack())
+                .build()
+                .as_bytes(),
+        );
+    }
+    Ok(tx)
+}
+
+// The message signed by the entrance programs: the transaction hash and the
+// program witnesses with the entrance signatures cleared. The lock fields are
+// not signed, so the message is the same before and after the inputs are signed.
+fn entrance_message(
+    tx: &json_types::Transaction,
+) -> Result<(secp256k1::Message, packed::WitnessArgs, Bytes), String> {
+    let tx_hash: H256 = packed::Transaction::from(tx.clone())
+        .calc_tx_hash()
+        .unpack();
+    let mut entrance_witness = None;
+    let mut unsigned_data = BytesMut::default();
+    unsigned_data.put(tx_hash.as_bytes());
+    let mut output_witnesses = Vec::new();
+    for (idx, witness) in tx.witnesses.iter().enumerate() {
+        // Padded by a signed transaction, see `sign_transaction`
+        if witness.is_empty() {
+            continue;
+        }
+        let witness_args =
+            packed::WitnessArgs::from_slice(witness.as_bytes()).map_err(|err| err.to_string())?;
+        if let Some(raw_witness) = witness_args
+            .input_type()
+            .to_opt()
+            .map(|witness_data| witness_data.raw_data())
+        {
+            if idx == 0 {
+                entrance_witness = Some((witness_args, raw_witness.clone()));
+                unsigned_data.put(&unsigned_entrance_witness(&raw_witness)?[..]);
+            } else {
+                unsigned_data.put(raw_witness.as_ref());
+            }
+        } else if let Some(raw_witness) = witness_args
+            .output_type()
+            .to_opt()
+            .map(|witness_data| witness_data.raw_data())
+        {
+            output_witnesses.push((idx, witness_args, raw_witness));
+        }
+    }
+    for (idx, witness_args, raw_witness) in output_witnesses {
+        if idx == 0 {
+            entrance_witness = Some((witness_args, raw_witness.clone()));
+            unsigned_data.put(&unsigned_entrance_witness(&raw_witness)?[..]);
+        } else {
+            unsigned_data.put(raw_witness.as_ref());
+        }
+    }
+
+    let (entrance_witness_args, entrance_raw_witness) =
+        entrance_witness.ok_or_else(|| String::from("No entrance witness found"))?;
+    let message = secp256k1::Message::from_slice(&blake2b_256(&unsigned_data)[..])
+        .map_err(|err| err.to_string())?;
+    Ok((message, entrance_witness_args, entrance_raw_witness))
+}
+
+// Offsets of the entrance programs' signatures in the entrance witness, all
+// of them sign the same message
+fn entrance_signature_offsets(raw_witness: &[u8]) -> Result<Vec<usize>, String> {
+    let mut offsets = Vec::new();
+    let mut start = 0;
+    while let Some((offset, witness_data)) = WitnessData::load_from(&raw_witness[start..])? {
+        if witness_data.program.depth == 0 {
+            // 4 bytes is for program length (u32)
+            offsets.push(start + 4);
+        }
+        start += offset;
+    }
+    Ok(offsets)
+}
+
+// The entrance witness with all entrance signatures cleared
+fn unsigned_entrance_witness(raw_witness: &[u8]) -> Result<Vec<u8>, String> {
+    let mut raw_witness = raw_witness.to_vec();
+    for offset in entrance_signature_offsets(&raw_witness)? {
+        raw_witness[offset..offset + 65].copy_from_slice(&[0u8; 65][..]);
+    }
+    Ok(raw_witness)
+}
+
+// Serve the RPC until Ctrl-C
+fn serve(rpc: RpcImpl, listen_addr: &str) {
+    let mut io_handler = IoHandler::new();
+    io_handler.extend_with(rpc.to_delegate());
+
+    let rpc_server = ServerBuilder::new(io_handler)
+        .cors(DomainsValidation::AllowOnly(vec![
+            AccessControlAllowOrigin::Null,
+            AccessControlAllowOrigin::Any,
+        ]))
+        .threads(4)
+        .max_request_body_size(10_485_760)
+        .start_http(&listen_addr.parse().expect("parse listen address"))
+        .expect("jsonrpc initialize");
+    log::info!("RPC server listen on: {}", listen_addr);
+
+    // Wait for exit
+    let exit = Arc::new((Mutex::new(()), Condvar::new()));
+    let e = Arc::clone(&exit);
+    ctrlc::set_handler(move || {
+        e.1.notify_all();
+    })
+    .expect("error setting Ctrl-C handler");
+    let _guard = exit
+        .1
+        .wait(exit.0.lock().expect("locking"))
+        .expect("waiting");
+    rpc_server.close();
+    log::info!("exiting...");
+}
+
 pub fn get_live_cell(
-    client: &mut HttpRpcClient,
+    client: &mut dyn ChainSource,
     out_point: json_types::OutPoint,
     with_data: bool,
 ) -> Result<json_types::CellOutput, String> {
@@ -653,16 +1264,215 @@ fn parse_hex_binary(input: &str) -> Result<Vec<u8>, String> {
 }
 
 // Can deploy those scripts by:
-//     ckb-cli wallet transfer --data-path xxx
+//     polyjuice deploy-scripts
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RunConfigJson {
     // Type script (Validator)
     pub type_dep: json_types::CellDep,
     pub type_script: json_types::Script,
-    // Lock script for contract (default always success)
+    // Lock script for new contract cells (default always success)
     pub lock_dep: json_types::CellDep,
     pub lock_script: json_types::Script,
+    // Locks of contract cells created before `lock_script` changed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub legacy_locks: Vec<ContractLockJson>,
     // Lock script for EoA account
     pub eoa_lock_dep: json_types::CellDep,
     pub eoa_lock_script: json_types::Script,
+    // Sighash or multisig locks accepted for EoA account
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_eoa_locks: Vec<EoaLockJson>,
+    // The secp256k1 sighash dep group, resolved from the chain if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sighash_dep: Option<json_types::CellDep>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContractLockJson {
+    pub lock_dep: json_types::CellDep,
+    pub lock_script: json_types::Script,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EoaLockJson {
+    pub kind: EoaLockKind,
+    pub lock_dep: json_types::CellDep,
+    pub lock_script: json_types::Script,
+}
+
+impl From<EoaLockJson> for EoaLock {
+    fn from(json: EoaLockJson) -> EoaLock {
+        EoaLock {
+            kind: json.kind,
+            dep: json.lock_dep.into(),
+            script: json.lock_script.into(),
+        }
+    }
+}
+
+impl From<EoaLock> for EoaLockJson {
+    fn from(eoa_lock: EoaLock) -> EoaLockJson {
+        EoaLockJson {
+            kind: eoa_lock.kind,
+            lock_dep: eoa_lock.dep.into(),
+            lock_script: eoa_lock.script.into(),
+        }
+    }
+}
+
+// The members of a multisig EoA
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MultisigConfigJson {
+    pub sighash_lock_args: Vec<H160>,
+    pub require_first_n: u8,
+    pub threshold: u8,
+}
+
+fn read_multisig_config(path: &str) -> Result<MultisigConfig, String> {
+    let config_json: MultisigConfigJson = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json_string| {
+        serde_json::from_str(json_string.as_str()).map_err(|err| err.to_string())
+    })?;
+    MultisigConfig::new_with(
+        config_json.sighash_lock_args,
+        config_json.require_first_n,
+        config_json.threshold,
+    )
+}
+
+fn read_run_config(path: &str) -> Result<RunConfigJson, String> {
+    fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json_string| {
+            serde_json::from_str(json_string.as_str()).map_err(|err| err.to_string())
+        })
+}
+
+// The EoA locks of the config, or the anyone-can-pay and system locks of the chain
+fn load_eoa_lock_scripts(
+    config_path: Option<&str>,
+    client: &mut dyn ChainSource,
+) -> Result<Vec<(EoaLockKind, packed::Script)>, String> {
+    match config_path {
+        Some(path) => {
+            let config_json = read_run_config(path)?;
+            let mut scripts = vec![(
+                EoaLockKind::AnyoneCanPay,
+                config_json.eoa_lock_script.into(),
+            )];
+            scripts.extend(
+                config_json
+                    .extra_eoa_locks
+                    .into_iter()
+                    .map(|eoa_lock| (eoa_lock.kind, eoa_lock.lock_script.into())),
+            );
+            Ok(scripts)
+        }
+        None => Ok(chain_scripts(client)?.eoa_lock_scripts()),
+    }
+}
+
+// Deploy script binaries by `ckb-cli wallet transfer`, one transaction each
+struct ScriptDeployer<'a> {
+    ckb_uri: &'a str,
+    privkey_path: &'a str,
+    owner_address: String,
+    tx_fee: &'a str,
+    type_id: bool,
+}
+
+impl<'a> ScriptDeployer<'a> {
+    // Return the cell dep and the script referencing the binary
+    fn deploy(
+        &self,
+        client: &mut HttpRpcClient,
+        data_path: &str,
+    ) -> Result<(json_types::CellDep, json_types::Script), String> {
+        let data = fs::read(data_path).map_err(|err| err.to_string())?;
+        // capacity + lock (sighash) + type (type id) + data
+        let mut occupied = 8 + (32 + 1 + 20) + data.len() as u64;
+        if self.type_id {
+            occupied += 32 + 1 + 32;
+        }
+        let capacity_string = HumanCapacity(occupied * ONE_CKB).to_string();
+        println!(
+            "[Command]: ckb-cli wallet transfer --privkey-path {} --to-address {} --to-data-path {} --capacity {} --tx-fee {}{}",
+            self.privkey_path,
+            self.owner_address,
+            data_path,
+            capacity_string,
+            self.tx_fee,
+            if self.type_id { " --type-id" } else { "" },
+        );
+        let mut command = Command::new("ckb-cli");
+        command
+            .args(&["--url", self.ckb_uri])
+            .args(&["wallet", "transfer"])
+            .args(&["--privkey-path", self.privkey_path])
+            .args(&["--to-address", self.owner_address.as_str()])
+            .args(&["--to-data-path", data_path])
+            .args(&["--capacity", capacity_string.as_str()])
+            .args(&["--tx-fee", self.tx_fee]);
+        if self.type_id {
+            command.arg("--type-id");
+        }
+        let output = command.output().expect("Failed to execute command");
+        if !output.status.success() {
+            println!("[stdout]: {}", String::from_utf8_lossy(&output.stdout));
+            println!("[stderr]: {}", String::from_utf8_lossy(&output.stderr));
+            return Err(format!("Deploy {} failed", data_path));
+        }
+        let tx_hash_string = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .chars()
+            .skip(2)
+            .collect::<String>();
+        let tx_hash = H256::from_str(tx_hash_string.as_str()).map_err(|err| err.to_string())?;
+        println!("tx-hash: {:#x}, output-index: 0", tx_hash);
+
+        // The next deployment may spend the change of this transaction
+        let tx_with_status = wait_committed(client, &tx_hash)?;
+        let (code_hash, hash_type) = if self.type_id {
+            let type_script = tx_with_status.transaction.inner.outputs[0]
+                .type_
+                .clone()
+                .ok_or_else(|| String::from("The type id script is missing"))?;
+            let type_hash: H256 = packed::Script::from(type_script)
+                .calc_script_hash()
+                .unpack();
+            (type_hash, json_types::ScriptHashType::Type)
+        } else {
+            (H256(blake2b_256(&data)), json_types::ScriptHashType::Data)
+        };
+        let cell_dep = json_types::CellDep {
+            out_point: json_types::OutPoint {
+                tx_hash,
+                index: 0u32.into(),
+            },
+            dep_type: json_types::DepType::Code,
+        };
+        let script = json_types::Script {
+            code_hash,
+            hash_type,
+            args: json_types::JsonBytes::default(),
+        };
+        Ok((cell_dep, script))
+    }
+}
+
+fn wait_committed(
+    client: &mut HttpRpcClient,
+    tx_hash: &H256,
+) -> Result<json_types::TransactionWithStatus, String> {
+    // About 10 minutes
+    for _ in 0..600 {
+        if let Some(tx_with_status) = client.get_transaction(tx_hash.clone())? {
+            if tx_with_status.tx_status.status == json_types::Status::Committed {
+                return Ok(tx_with_status);
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    Err(format!("Transaction {:#x} is not committed", tx_hash))
 }
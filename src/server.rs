@@ -1,14 +1,19 @@
-use crate::storage::{CsalRunContext, Loader, Runner};
+use crate::aggregator::Aggregator;
+use crate::dev::DevChain;
+use crate::storage::{
+    value::TaintedContract, CsalRunContext, IndexerHealth, Loader, QuarantinedTx, Runner,
+};
 use crate::types::{ContractAddress, ContractChange, ContractMeta, EoaAddress, RunConfig};
 use ckb_hash::blake2b_256;
 use ckb_jsonrpc_types::{JsonBytes, Transaction};
-use ckb_types::{bytes::Bytes, prelude::*, H160, H256};
+use ckb_types::{bytes::Bytes, packed, prelude::*, H160, H256};
 use jsonrpc_core::{Error, ErrorCode, Result as RpcResult};
 use jsonrpc_derive::rpc;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::error::Error as StdError;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 #[rpc(server)]
 pub trait Rpc {
@@ -24,6 +29,12 @@ pub trait Rpc {
         value: u64,
     ) -> RpcResult<TransactionReceipt>;
 
+    #[rpc(name = "batch")]
+    fn batch(&self, sender: H160, steps: Vec<BatchStep>) -> RpcResult<TransactionReceipt>;
+
+    #[rpc(name = "release_transaction")]
+    fn release_transaction(&self, tx_hash: H256) -> RpcResult<()>;
+
     #[rpc(name = "static_call")]
     fn static_call(
         &self,
@@ -61,22 +72,31 @@ pub trait Rpc {
 
     #[rpc(name = "get_balance")]
     fn get_balance(&self, address: H160) -> RpcResult<u64>;
+
+    #[rpc(name = "get_indexer_status")]
+    fn get_indexer_status(&self) -> RpcResult<IndexerStatus>;
 }
 
 pub struct RpcImpl {
     pub loader: Arc<Loader>,
     pub run_config: RunConfig,
+    pub aggregator: Option<Arc<Aggregator>>,
+    pub health: Arc<Mutex<IndexerHealth>>,
+    /// Mine the transactions at once (`polyjuice dev`)
+    pub dev: Option<Arc<DevChain>>,
 }
 
 impl Rpc for RpcImpl {
     fn create(&self, sender: H160, code: JsonBytes, value: u64) -> RpcResult<TransactionReceipt> {
         log::debug!("create(sender: {:x}, value: {})", sender, value);
-        let loader = Loader::clone(&self.loader);
         let run_config = self.run_config.clone();
-        let context = Runner::new(loader, run_config)
-            .create(sender, code.into_bytes(), value)
-            .map_err(convert_err_box)?;
-        let resp = TransactionReceipt::try_from(context).map_err(convert_err);
+        let resp = TransactionReceipt::build(&self.loader, |loader| {
+            Runner::new(loader, run_config)
+                .create(sender.clone(), code.into_bytes(), value)
+                .map_err(|err| err.to_string())
+        })
+        .map_err(convert_err)
+        .and_then(|receipt| self.commit_dev(&sender, receipt));
         log::debug!("create finished");
         resp
     }
@@ -94,16 +114,77 @@ impl Rpc for RpcImpl {
             contract_address.0,
             hex::encode(input.as_bytes())
         );
-        let loader = Loader::clone(&self.loader);
+        if let Some(aggregator) = self.aggregator.as_ref() {
+            let resp = aggregator
+                .call(sender, contract_address, input.into_bytes(), value)
+                .map_err(convert_err);
+            log::debug!("aggregated call finished");
+            return resp;
+        }
         let run_config = self.run_config.clone();
-        let context = Runner::new(loader, run_config)
-            .call(sender, contract_address, input.into_bytes(), value)
-            .map_err(convert_err_box)?;
-        let resp = TransactionReceipt::try_from(context).map_err(convert_err);
+        let resp = TransactionReceipt::build(&self.loader, |loader| {
+            Runner::new(loader, run_config)
+                .call(sender.clone(), contract_address, input.into_bytes(), value)
+                .map_err(|err| err.to_string())
+        })
+        .map_err(convert_err)
+        .and_then(|receipt| self.commit_dev(&sender, receipt));
         log::debug!("call finished");
         resp
     }
 
+    fn batch(&self, sender: H160, steps: Vec<BatchStep>) -> RpcResult<TransactionReceipt> {
+        log::debug!("batch(sender: {:x}, steps: {})", sender, steps.len());
+        if steps.is_empty() {
+            return Err(convert_err(String::from("batch without steps")));
+        }
+        let run_config = self.run_config.clone();
+        let resp = TransactionReceipt::build(&self.loader, |loader| {
+            let mut context = Runner::new(loader, run_config)
+                .new_context()
+                .map_err(|err| err.to_string())?;
+            for (index, step) in steps.into_iter().enumerate() {
+                let result = match step {
+                    BatchStep::Create { code, value } => {
+                        context.create(sender.clone(), code.into_bytes(), value)
+                    }
+                    BatchStep::Call {
+                        contract_address,
+                        input,
+                        value,
+                    } => {
+                        let entrance_contract = if index > 0 {
+                            Some(context.entrance_contract())
+                        } else {
+                            None
+                        };
+                        match contract_address.or(entrance_contract) {
+                            Some(contract_address) => context.call(
+                                sender.clone(),
+                                contract_address,
+                                input.into_bytes(),
+                                value,
+                            ),
+                            None => Err(String::from("missing contract address").into()),
+                        }
+                    }
+                };
+                result.map_err(|err| format!("step {} failed: {}", index, err))?;
+            }
+            Ok(context)
+        })
+        .map_err(convert_err)
+        .and_then(|receipt| self.commit_dev(&sender, receipt));
+        log::debug!("batch finished");
+        resp
+    }
+
+    fn release_transaction(&self, tx_hash: H256) -> RpcResult<()> {
+        log::debug!("release_transaction(tx_hash: {:#x})", tx_hash);
+        self.loader.remove_pending_transaction(&tx_hash);
+        Ok(())
+    }
+
     fn static_call(
         &self,
         sender: H160,
@@ -116,7 +197,7 @@ impl Rpc for RpcImpl {
             contract_address.0,
             hex::encode(input.as_bytes())
         );
-        let loader = Loader::clone(&self.loader);
+        let loader = self.snapshot_loader()?;
         let run_config = self.run_config.clone();
         let context = Runner::new(loader, run_config)
             .static_call(sender, contract_address, input.into_bytes())
@@ -127,9 +208,13 @@ impl Rpc for RpcImpl {
 
     fn get_code(&self, contract_address: ContractAddress) -> RpcResult<ContractCodeJson> {
         log::debug!("get_code(contract_address: {:x})", contract_address.0);
-        self.loader
+        let loader = self.snapshot_loader()?;
+        loader
             .load_contract_meta(contract_address)
-            .map(ContractCodeJson::from)
+            .map(|meta| ContractCodeJson {
+                served_block: ServedBlock::from_loader(&loader),
+                ..ContractCodeJson::from(meta)
+            })
             .map_err(convert_err)
     }
 
@@ -143,13 +228,17 @@ impl Rpc for RpcImpl {
             from_block,
             to_block
         );
-        let mut loader = Loader::clone(&self.loader);
+        let mut loader = self.snapshot_loader()?;
+        let served_block = ServedBlock::from_loader(&loader);
         loader
             .load_contract_meta_list(from_block, to_block)
             .map(|metas| {
                 metas
                     .into_iter()
-                    .map(|(number, meta)| ContractMetaJson::new(number, meta))
+                    .map(|(number, meta)| ContractMetaJson {
+                        served_block: served_block.clone(),
+                        ..ContractMetaJson::new(number, meta)
+                    })
                     .collect::<Vec<_>>()
             })
             .map_err(convert_err)
@@ -160,10 +249,15 @@ impl Rpc for RpcImpl {
         contract_address: ContractAddress,
         block_number: Option<u64>,
     ) -> RpcResult<ContractChangeJson> {
-        self.loader
+        let loader = self.snapshot_loader()?;
+        let change = loader
             .load_latest_contract_change(contract_address, block_number, true, true)
-            .map(ContractChangeJson::from)
-            .map_err(convert_err)
+            .map_err(convert_err)?;
+        let new_storage = loader.load_contract_storage(&change).map_err(convert_err)?;
+        Ok(ContractChangeJson {
+            served_block: ServedBlock::from_loader(&loader),
+            ..ContractChangeJson::new(change, new_storage)
+        })
     }
 
     fn get_logs(
@@ -174,7 +268,8 @@ impl Rpc for RpcImpl {
         filter_topics: Option<Vec<H256>>,
         limit: Option<u32>,
     ) -> RpcResult<Vec<LogInfo>> {
-        let mut loader = Loader::clone(&self.loader);
+        let mut loader = self.snapshot_loader()?;
+        let served_block = ServedBlock::from_loader(&loader);
         loader
             .load_logs(from_block, to_block, address, filter_topics, limit)
             .map(|logs| {
@@ -185,6 +280,7 @@ impl Rpc for RpcImpl {
                             block_number: info.block_number,
                             tx_index: info.tx_index,
                             log,
+                            served_block: served_block.clone(),
                         }
                     })
                     .collect::<Vec<_>>()
@@ -193,7 +289,7 @@ impl Rpc for RpcImpl {
     }
 
     fn get_balance(&self, address: H160) -> RpcResult<u64> {
-        let mut loader = Loader::clone(&self.loader);
+        let mut loader = self.snapshot_loader()?;
         loader
             .load_eoa_live_cell(address.clone())
             .map(|(cell, _, _)| cell.balance())
@@ -204,6 +300,56 @@ impl Rpc for RpcImpl {
             })
             .map_err(convert_err)
     }
+
+    fn get_indexer_status(&self) -> RpcResult<IndexerStatus> {
+        let loader = self.snapshot_loader()?;
+        let last_block = loader.load_last_block().map_err(convert_err)?;
+        let tainted_contracts = loader.load_tainted_contracts().map_err(convert_err)?;
+        let health = self.health.lock().expect("lock indexer health").clone();
+        let last_number = last_block.as_ref().map(|(number, _)| *number);
+        let lag = health
+            .tip_number
+            .map(|tip_number| tip_number.saturating_sub(last_number.unwrap_or(0)));
+        Ok(IndexerStatus {
+            last_number,
+            last_hash: last_block.map(|(_, hash)| hash),
+            tip_number: health.tip_number,
+            lag,
+            rollback_count: health.rollback_count,
+            last_error: health.last_error,
+            alive: health.alive,
+            halted: health.halted,
+            quarantined: health.quarantined,
+            tainted_contracts,
+            served_block: ServedBlock::from_loader(&loader),
+        })
+    }
+}
+
+impl RpcImpl {
+    // Every request reads from one snapshot of the indexed state
+    fn snapshot_loader(&self) -> RpcResult<Loader> {
+        self.loader.snapshot().map_err(convert_err)
+    }
+
+    // Sign and mine the transaction in dev mode, a failed transaction and
+    // the later ones chained on it are dropped
+    fn commit_dev(
+        &self,
+        sender: &H160,
+        mut receipt: TransactionReceipt,
+    ) -> RpcResult<TransactionReceipt> {
+        if let Some(dev) = self.dev.as_ref() {
+            match dev.commit(sender, receipt.tx.clone()) {
+                Ok(tx) => receipt.tx = tx,
+                Err(err) => {
+                    self.loader.remove_pending_transaction(&receipt.tx_hash);
+                    return Err(convert_err(err));
+                }
+            }
+        }
+        Ok(receipt)
+    }
 }
 
 fn convert_err(err: String) -> Error {
@@ -237,10 +383,11 @@ pub struct ContractChangeJson {
     pub logs: Vec<(Vec<H256>, JsonBytes)>,
     /// The change is create the contract
     pub is_create: bool,
+    pub served_block: Option<ServedBlock>,
 }
 
-impl From<ContractChange> for ContractChangeJson {
-    fn from(change: ContractChange) -> ContractChangeJson {
+impl ContractChangeJson {
+    pub fn new(change: ContractChange, new_storage: HashMap<H256, H256>) -> ContractChangeJson {
         ContractChangeJson {
             tx_origin: change.tx_origin,
             address: change.address,
@@ -248,13 +395,14 @@ impl From<ContractChange> for ContractChangeJson {
             tx_index: change.tx_index,
             output_index: change.output_index,
             tx_hash: change.tx_hash,
-            new_storage: change.new_storage.into_iter().collect::<Vec<_>>(),
+            new_storage: new_storage.into_iter().collect::<Vec<_>>(),
             logs: change
                 .logs
                 .into_iter()
                 .map(|(topics, data)| (topics, JsonBytes::from_bytes(data)))
                 .collect::<Vec<_>>(),
             is_create: change.is_create,
+            served_block: None,
         }
     }
 }
@@ -266,6 +414,7 @@ pub struct ContractCodeJson {
     pub tx_hash: H256,
     /// The output index of the transaction where the contract created
     pub output_index: u32,
+    pub served_block: Option<ServedBlock>,
 }
 impl From<ContractMeta> for ContractCodeJson {
     fn from(meta: ContractMeta) -> ContractCodeJson {
@@ -273,22 +422,24 @@ impl From<ContractMeta> for ContractCodeJson {
             code: JsonBytes::from_bytes(meta.code),
             tx_hash: meta.tx_hash,
             output_index: meta.output_index,
+            served_block: None,
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogInfo {
-    block_number: u64,
-    tx_index: u32,
-    log: LogEntry,
+    pub block_number: u64,
+    pub tx_index: u32,
+    pub log: LogEntry,
+    pub served_block: Option<ServedBlock>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
-    address: ContractAddress,
-    topics: Vec<H256>,
-    data: JsonBytes,
+    pub address: ContractAddress,
+    pub topics: Vec<H256>,
+    pub data: JsonBytes,
 }
 
 impl LogEntry {
@@ -301,6 +452,22 @@ impl LogEntry {
     }
 }
 
+/// The indexed block a request was served from, None before the first
+/// block is indexed
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ServedBlock {
+    pub number: u64,
+    pub hash: H256,
+}
+
+impl ServedBlock {
+    fn from_loader(loader: &Loader) -> Option<ServedBlock> {
+        loader
+            .snapshot_block()
+            .map(|(number, hash)| ServedBlock { number, hash })
+    }
+}
+
 /// The transaction receipt
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransactionReceipt {
@@ -313,12 +480,36 @@ pub struct TransactionReceipt {
     pub destructed_addresses: Vec<ContractAddress>,
     pub logs: Vec<LogEntry>,
     pub return_data: Option<JsonBytes>,
+    /// The result of each entrance program (batch step)
+    pub steps: Vec<StepReceipt>,
+    pub served_block: Option<ServedBlock>,
 }
 
-impl TryFrom<CsalRunContext> for TransactionReceipt {
-    type Error = String;
-    fn try_from(mut context: CsalRunContext) -> Result<TransactionReceipt, String> {
-        let tx = context.build_tx().map_err(|err| err.to_string())?;
+/// The return data and logs of an entrance program
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepReceipt {
+    pub return_data: JsonBytes,
+    pub logs: Vec<LogEntry>,
+}
+
+impl TransactionReceipt {
+    /// Run the programs on the pending state and build the transaction, it is
+    /// added to the pending pool so later transactions of the same senders
+    /// are chained on it. Release it if it will never be sent.
+    pub fn build<F>(loader: &Loader, run: F) -> Result<TransactionReceipt, String>
+    where
+        F: FnOnce(Loader) -> Result<CsalRunContext, String>,
+    {
+        loader.build_pending(|loader| {
+            let mut context = run(loader)?;
+            let tx = context.build_tx().map_err(|err| err.to_string())?;
+            let pending_tx = context.pending_transaction(&tx);
+            let receipt = TransactionReceipt::new(context, tx)?;
+            Ok((receipt, pending_tx))
+        })
+    }
+
+    fn new(context: CsalRunContext, tx: packed::Transaction) -> Result<TransactionReceipt, String> {
         let tx_hash: H256 = tx.calc_tx_hash().unpack();
         let entrance_contract = context.entrance_contract();
         let created_addresses = context.created_contracts();
@@ -328,8 +519,20 @@ impl TryFrom<CsalRunContext> for TransactionReceipt {
             .into_iter()
             .map(|(addr, topics, data)| LogEntry::new(addr, topics, data))
             .collect::<Vec<_>>();
+        let steps = context
+            .entrance_results()?
+            .into_iter()
+            .map(|result| StepReceipt {
+                return_data: JsonBytes::from_bytes(result.return_data),
+                logs: result
+                    .logs
+                    .into_iter()
+                    .map(|(addr, topics, data)| LogEntry::new(addr, topics, data))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
         let return_data = if context.is_create() {
-            Some(JsonBytes::from_bytes(context.entrance_info().return_data()))
+            steps.first().map(|step| step.return_data.clone())
         } else {
             None
         };
@@ -341,14 +544,57 @@ impl TryFrom<CsalRunContext> for TransactionReceipt {
             destructed_addresses,
             logs,
             return_data,
+            steps,
+            served_block: ServedBlock::from_loader(&context.loader),
         })
     }
 }
 
+/// One step of a batch, all steps are sent by the same sender to the same
+/// contract and run in one transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BatchStep {
+    Create {
+        code: JsonBytes,
+        value: u64,
+    },
+    Call {
+        /// Default to the contract of the first step
+        contract_address: Option<ContractAddress>,
+        input: JsonBytes,
+        value: u64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexerStatus {
+    /// The last indexed block, None before the first block is indexed
+    pub last_number: Option<u64>,
+    pub last_hash: Option<H256>,
+    /// The last known CKB tip block number, None before the indexer asks CKB
+    pub tip_number: Option<u64>,
+    /// How many blocks the indexer is behind the known CKB tip
+    pub lag: Option<u64>,
+    /// Rollbacks since start
+    pub rollback_count: u64,
+    pub last_error: Option<String>,
+    /// The indexer thread is running
+    pub alive: bool,
+    /// Indexing stopped on a fatal error
+    pub halted: bool,
+    /// Recently skipped invalid polyjuice transactions
+    pub quarantined: Vec<QuarantinedTx>,
+    /// Contracts not served any more because of skipped transactions
+    pub tainted_contracts: Vec<TaintedContract>,
+    pub served_block: Option<ServedBlock>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StaticCallResponse {
-    return_data: JsonBytes,
-    logs: Vec<LogEntry>,
+    pub return_data: JsonBytes,
+    pub logs: Vec<LogEntry>,
+    pub served_block: Option<ServedBlock>,
 }
 
 impl TryFrom<CsalRunContext> for StaticCallResponse {
@@ -362,6 +608,7 @@ impl TryFrom<CsalRunContext> for StaticCallResponse {
         Ok(StaticCallResponse {
             return_data: JsonBytes::from_bytes(context.entrance_info().return_data()),
             logs,
+            served_block: ServedBlock::from_loader(&context.loader),
         })
     }
 }
@@ -383,6 +630,7 @@ pub struct ContractMetaJson {
     pub output_index: u32,
     /// Check if the contract is destructed
     pub destructed: bool,
+    pub served_block: Option<ServedBlock>,
 }
 
 impl ContractMetaJson {
@@ -396,6 +644,7 @@ impl ContractMetaJson {
             tx_hash: meta.tx_hash,
             output_index: meta.output_index,
             destructed: meta.destructed,
+            served_block: None,
         }
     }
 }
@@ -0,0 +1,366 @@
+use bincode::{deserialize_from, serialize, serialize_into};
+use ckb_hash::{new_blake2b, Blake2b};
+use ckb_types::bytes::Bytes;
+use rocksdb::DB;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::migration::{is_empty, put_version, rebuild_storage_trees};
+use super::{column_family, db_get, value, Batch, Key, KeyType, SCHEMA_VERSION};
+
+const STATE_FILE_MAGIC: &[u8; 8] = b"PJSTATE\0";
+const STATE_FILE_VERSION: u32 = 1;
+// Records written per batch when importing
+const IMPORT_BATCH_SIZE: usize = 10_000;
+
+// The whole tables copied into the state file. Only the latest
+// ContractChange of each contract is kept.
+const STATE_KEY_TYPES: [KeyType; 5] = [
+    KeyType::ContractMeta,
+    KeyType::ContractState,
+    KeyType::EoaLiveCell,
+    KeyType::LockLiveCell,
+    KeyType::LiveCellMap,
+];
+
+/// State file layout (bincode):
+///   magic | StateHeader | Some((key, value))* | None | blake2b(all above)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct StateHeader {
+    version: u32,
+    schema_version: u32,
+    last: value::Last,
+}
+
+type Record = Option<(Vec<u8>, Vec<u8>)>;
+
+/// Dump the state at the last indexed block, return that block. The state
+/// of an earlier block is not kept, `block` must be the last indexed block.
+pub fn export_state<P: AsRef<Path>>(
+    db: &DB,
+    block: Option<u64>,
+    path: P,
+) -> Result<value::Last, String> {
+    let schema_version = db_get::<_, u32>(db, &Bytes::from(&Key::SchemaVersion))?;
+    if schema_version != Some(SCHEMA_VERSION) {
+        return Err(format!(
+            "Database schema version {:?} not match the supported version {}",
+            schema_version, SCHEMA_VERSION
+        ));
+    }
+    let last_key_bytes = Bytes::from(&Key::Last);
+    let last: value::Last =
+        db_get(db, &last_key_bytes)?.ok_or_else(|| String::from("Database is not indexed yet"))?;
+    match block {
+        Some(number) if number > last.number => {
+            return Err(format!(
+                "Block #{} is not indexed yet, the last indexed block is #{}",
+                number, last.number
+            ));
+        }
+        Some(number) if number < last.number => {
+            return Err(format!(
+                "The last indexed block is #{}, roll the database back by `reindex --from {}` to export block #{}",
+                last.number,
+                number + 1,
+                number
+            ));
+        }
+        _ => {}
+    }
+    let block_map_key_bytes = Bytes::from(&Key::BlockMap(last.number));
+    let block_map = db
+        .get_cf(column_family(db, KeyType::BlockMap), &block_map_key_bytes)
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| format!("BlockMap of the last block #{} not found", last.number))?;
+
+    let file = File::create(path).map_err(|err| err.to_string())?;
+    let mut writer = HashWriter::new(BufWriter::new(file));
+    writer
+        .write_all(STATE_FILE_MAGIC)
+        .map_err(|err| err.to_string())?;
+    let header = StateHeader {
+        version: STATE_FILE_VERSION,
+        schema_version: SCHEMA_VERSION,
+        last: last.clone(),
+    };
+    serialize_into(&mut writer, &header).map_err(|err| err.to_string())?;
+
+    let mut write_record = |key: &[u8], value: &[u8]| -> Result<(), String> {
+        let record: Record = Some((key.to_vec(), value.to_vec()));
+        serialize_into(&mut writer, &record).map_err(|err| err.to_string())
+    };
+    let mut count: usize = 2;
+    write_record(&last_key_bytes, &serialize(&last).unwrap())?;
+    write_record(&block_map_key_bytes, &block_map)?;
+    let tainted_key_bytes = Bytes::from(&Key::TaintedContracts);
+    if let Some(tainted) = db.get(&tainted_key_bytes).map_err(|err| err.to_string())? {
+        write_record(&tainted_key_bytes, &tainted)?;
+        count += 1;
+    }
+    for key_type in STATE_KEY_TYPES.iter() {
+        let mut iter = db.raw_iterator_cf(column_family(db, key_type.clone()));
+        iter.seek_to_first();
+        while iter.valid() {
+            if let (Some(key_bytes), Some(value_bytes)) = (iter.key(), iter.value()) {
+                write_record(key_bytes, value_bytes)?;
+                count += 1;
+            }
+            iter.next();
+        }
+    }
+    // Changes are sorted by (address, number, tx_index, output_index), keep
+    // the last one of each address
+    let mut latest_change: Option<(Vec<u8>, Vec<u8>)> = None;
+    let mut iter = db.raw_iterator_cf(column_family(db, KeyType::ContractChange));
+    iter.seek_to_first();
+    while iter.valid() {
+        if let (Some(key_bytes), Some(value_bytes)) = (iter.key(), iter.value()) {
+            if let Some((prev_key, prev_value)) = latest_change.take() {
+                // KeyType byte + contract address
+                if prev_key[0..21] != key_bytes[0..21] {
+                    write_record(&prev_key, &prev_value)?;
+                    count += 1;
+                }
+            }
+            latest_change = Some((key_bytes.to_vec(), value_bytes.to_vec()));
+        }
+        iter.next();
+    }
+    if let Some((key, value)) = latest_change {
+        write_record(&key, &value)?;
+        count += 1;
+    }
+
+    serialize_into(&mut writer, &Record::None).map_err(|err| err.to_string())?;
+    let (mut inner, checksum) = writer.finish();
+    inner.write_all(&checksum).map_err(|err| err.to_string())?;
+    inner.flush().map_err(|err| err.to_string())?;
+    log::info!(
+        "Exported {} records at block #{} {:#x}",
+        count,
+        last.number,
+        last.hash
+    );
+    Ok(last)
+}
+
+/// Load a state file into an empty database, return the block of the state.
+/// The indexer continues from the next block.
+pub fn import_state<P: AsRef<Path>>(db: &DB, path: P) -> Result<value::Last, String> {
+    if !is_empty(db) {
+        return Err(String::from("Database is not empty"));
+    }
+    // Check the whole file before writing anything
+    let header = read_state(path.as_ref(), |_, _| Ok(()))?;
+
+    let mut batch = Batch::new(db);
+    let mut batch_size: usize = 0;
+    let mut count: usize = 0;
+    read_state(path.as_ref(), |key, value| {
+        batch.put(key, value);
+        batch_size += 1;
+        count += 1;
+        if batch_size >= IMPORT_BATCH_SIZE {
+            std::mem::replace(&mut batch, Batch::new(db)).commit()?;
+            batch_size = 0;
+        }
+        Ok(())
+    })?;
+    batch.commit()?;
+    log::info!("Rebuild contract storage trees");
+    rebuild_storage_trees(db)?;
+    put_version(db, header.schema_version)?;
+    let last = header.last;
+    log::info!(
+        "Imported {} records at block #{} {:#x}",
+        count,
+        last.number,
+        last.hash
+    );
+    Ok(last)
+}
+
+/// The block of a state file, the whole file is checked
+pub fn state_file_block<P: AsRef<Path>>(path: P) -> Result<value::Last, String> {
+    read_state(path.as_ref(), |_, _| Ok(())).map(|header| header.last)
+}
+
+fn read_state<F>(path: &Path, mut f: F) -> Result<StateHeader, String>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<(), String>,
+{
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut reader = HashReader::new(BufReader::new(file));
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|err| err.to_string())?;
+    if &magic != STATE_FILE_MAGIC {
+        return Err(String::from("Not a polyjuice state file"));
+    }
+    let header: StateHeader = deserialize_from(&mut reader).map_err(|err| err.to_string())?;
+    if header.version != STATE_FILE_VERSION {
+        return Err(format!("Unsupported state file version {}", header.version));
+    }
+    if header.schema_version != SCHEMA_VERSION {
+        return Err(format!(
+            "State file schema version {} not match the supported version {}",
+            header.schema_version, SCHEMA_VERSION
+        ));
+    }
+    while let Some((key, value)) =
+        deserialize_from::<_, Record>(&mut reader).map_err(|err| err.to_string())?
+    {
+        match key.first().map(|byte| KeyType::try_from(*byte)) {
+            Some(Ok(KeyType::SchemaVersion)) | Some(Err(_)) | None => {
+                return Err(String::from("Invalid record in state file"));
+            }
+            _ => {}
+        }
+        f(&key, &value)?;
+    }
+    let (mut inner, checksum) = reader.finish();
+    let mut expected = [0u8; 32];
+    inner
+        .read_exact(&mut expected)
+        .map_err(|err| err.to_string())?;
+    if checksum != expected {
+        return Err(String::from("State file checksum mismatch"));
+    }
+    Ok(header)
+}
+
+struct HashWriter<W> {
+    inner: W,
+    hasher: Blake2b,
+}
+
+impl<W: Write> HashWriter<W> {
+    fn new(inner: W) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher: new_blake2b(),
+        }
+    }
+
+    fn finish(self) -> (W, [u8; 32]) {
+        let mut checksum = [0u8; 32];
+        self.hasher.finalize(&mut checksum);
+        (self.inner, checksum)
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.hasher.update(&buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashReader<R> {
+    inner: R,
+    hasher: Blake2b,
+}
+
+impl<R: Read> HashReader<R> {
+    fn new(inner: R) -> HashReader<R> {
+        HashReader {
+            inner,
+            hasher: new_blake2b(),
+        }
+    }
+
+    fn finish(self) -> (R, [u8; 32]) {
+        let mut checksum = [0u8; 32];
+        self.hasher.finalize(&mut checksum);
+        (self.inner, checksum)
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.hasher.update(&buf[..size]);
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::open_db;
+    use super::*;
+    use ckb_types::{h256, H160};
+
+    #[test]
+    fn test_export_import_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path().join("db")).unwrap();
+        put_version(&db, SCHEMA_VERSION).unwrap();
+        let last = value::Last {
+            number: 3,
+            hash: h256!("0x3"),
+        };
+        let eoa_cell = value::EoaLiveCell::new(h256!("0x2"), 0, 100, 10);
+        let mut batch = Batch::new(&db);
+        batch.put(&Bytes::from(&Key::Last), &serialize(&last).unwrap());
+        batch.put(
+            &Bytes::from(&Key::BlockMap(3)),
+            &serialize(&value::BlockMap(h256!("0x3"))).unwrap(),
+        );
+        // Not part of the state
+        batch.put(
+            &Bytes::from(&Key::BlockMap(2)),
+            &serialize(&value::BlockMap(h256!("0x2"))).unwrap(),
+        );
+        batch.put(
+            &Bytes::from(&Key::EoaLiveCell(H160::default())),
+            &serialize(&eoa_cell).unwrap(),
+        );
+        batch.commit().unwrap();
+
+        let path = dir.path().join("state.bin");
+        assert!(export_state(&db, Some(2), &path).is_err());
+        assert!(export_state(&db, Some(4), &path).is_err());
+        assert_eq!(export_state(&db, Some(3), &path).unwrap().hash, last.hash);
+
+        let new_db = open_db(dir.path().join("new-db")).unwrap();
+        assert_eq!(import_state(&new_db, &path).unwrap().number, 3);
+        let new_last: value::Last = db_get(&new_db, &Bytes::from(&Key::Last)).unwrap().unwrap();
+        assert_eq!(new_last.hash, last.hash);
+        let new_eoa_cell: value::EoaLiveCell =
+            db_get(&new_db, &Bytes::from(&Key::EoaLiveCell(H160::default())))
+                .unwrap()
+                .unwrap();
+        assert_eq!(new_eoa_cell, eoa_cell);
+        assert!(
+            db_get::<_, value::BlockMap>(&new_db, &Bytes::from(&Key::BlockMap(2)))
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(
+            db_get::<_, u32>(&new_db, &Bytes::from(&Key::SchemaVersion)).unwrap(),
+            Some(SCHEMA_VERSION)
+        );
+        // Only empty databases
+        assert!(import_state(&new_db, &path).is_err());
+
+        let mut data = std::fs::read(&path).unwrap();
+        let last_byte = data.len() - 1;
+        data[last_byte] ^= 1;
+        std::fs::write(&path, data).unwrap();
+        let corrupted_db = open_db(dir.path().join("corrupted-db")).unwrap();
+        assert_eq!(
+            import_state(&corrupted_db, &path).unwrap_err(),
+            "State file checksum mismatch"
+        );
+    }
+}
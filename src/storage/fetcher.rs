@@ -0,0 +1,302 @@
+use ckb_jsonrpc_types::{BlockView, CellOutput, HeaderView};
+use ckb_types::{bytes::Bytes, packed, prelude::*, H256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+use super::{db_get, value, IndexerHealth, Key, KvStore};
+use crate::chain::ChainSource;
+
+// How many blocks are fetched ahead of the block being indexed
+const PREFETCH_BLOCKS: usize = 16;
+// Threads resolving the spent outputs of a block
+const RESOLVE_WORKERS: usize = 8;
+// Recently created outputs kept in memory, most inputs spend them
+const OUTPUT_CACHE_SIZE: usize = 100_000;
+
+pub struct FetchedBlock {
+    pub header: HeaderView,
+    pub block: BlockView,
+    /// The spent outputs of all inputs in the block
+    pub inputs: HashMap<packed::OutPoint, SpentCell>,
+}
+
+#[derive(Clone)]
+pub struct SpentCell {
+    pub output: CellOutput,
+    pub data_size: u32,
+    /// Only small cell data is kept in the live cell index
+    pub data: Option<Bytes>,
+}
+
+impl From<value::LiveCellMap> for SpentCell {
+    fn from(cell: value::LiveCellMap) -> SpentCell {
+        let output = packed::CellOutput::from_slice(&cell.output).expect("DB corrupted");
+        SpentCell {
+            output: output.into(),
+            data_size: cell.data_size,
+            data: cell.data,
+        }
+    }
+}
+
+/// Fetch blocks ahead of the indexer in a background thread. Spent outputs
+/// are resolved from recent blocks and the live cell index, only cells created
+/// before indexing started are fetched from the CKB RPC (concurrently).
+pub struct BlockFetcher {
+    db: Arc<dyn KvStore>,
+    client: Box<dyn ChainSource>,
+    cache: Arc<Mutex<OutputCache>>,
+    pipeline: Option<Pipeline>,
+    health: Arc<Mutex<IndexerHealth>>,
+}
+
+struct Pipeline {
+    next_number: u64,
+    receiver: Receiver<Result<FetchedBlock, String>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+#[derive(Default)]
+struct OutputCache {
+    outputs: HashMap<packed::OutPoint, SpentCell>,
+    // Insertion order, for evicting the oldest outputs
+    order: VecDeque<packed::OutPoint>,
+}
+
+impl OutputCache {
+    fn insert(&mut self, out_point: packed::OutPoint, cell: SpentCell) {
+        if self.outputs.insert(out_point.clone(), cell).is_none() {
+            self.order.push_back(out_point);
+        }
+        while self.order.len() > OUTPUT_CACHE_SIZE {
+            if let Some(old_out_point) = self.order.pop_front() {
+                self.outputs.remove(&old_out_point);
+            }
+        }
+    }
+
+    fn get(&self, out_point: &packed::OutPoint) -> Option<SpentCell> {
+        self.outputs.get(out_point).cloned()
+    }
+}
+
+impl BlockFetcher {
+    pub fn new(
+        db: Arc<dyn KvStore>,
+        client: Box<dyn ChainSource>,
+        health: Arc<Mutex<IndexerHealth>>,
+    ) -> BlockFetcher {
+        BlockFetcher {
+            db,
+            client,
+            cache: Arc::new(Mutex::new(OutputCache::default())),
+            pipeline: None,
+            health,
+        }
+    }
+
+    /// Wait for the block of the given number. Blocks are prefetched in
+    /// order, asking for any other number (after a rollback) restarts
+    /// fetching from that number.
+    pub fn next_block(&mut self, number: u64) -> Result<FetchedBlock, String> {
+        let restart = self
+            .pipeline
+            .as_ref()
+            .map(|pipeline| pipeline.next_number != number)
+            .unwrap_or(true);
+        if restart {
+            log::debug!("start fetching blocks from {}", number);
+            self.pipeline = Some(self.start(number));
+        }
+        let pipeline = self.pipeline.as_mut().expect("pipeline started");
+        match pipeline.receiver.recv() {
+            Ok(Ok(fetched)) => {
+                pipeline.next_number += 1;
+                Ok(fetched)
+            }
+            Ok(Err(err)) => Err(err),
+            Err(_) => {
+                self.pipeline = None;
+                Err(String::from("block fetcher stopped"))
+            }
+        }
+    }
+
+    fn start(&self, from_number: u64) -> Pipeline {
+        let (sender, receiver) = sync_channel(PREFETCH_BLOCKS);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let db = Arc::clone(&self.db);
+        let mut client = self.client.clone();
+        let cache = Arc::clone(&self.cache);
+        let thread_stopped = Arc::clone(&stopped);
+        let health = Arc::clone(&self.health);
+        thread::spawn(move || {
+            let mut number = from_number;
+            let mut tip_number = None;
+            while !thread_stopped.load(Ordering::SeqCst) {
+                match fetch_block(&db, client.as_mut(), &cache, number) {
+                    Ok(Some(fetched)) => {
+                        // Passed the known tip, ask CKB for the new one
+                        if tip_number.map(|tip| number > tip).unwrap_or(true) {
+                            tip_number = client.get_tip_block_number().ok();
+                            health.lock().expect("lock indexer health").tip_number = tip_number;
+                        }
+                        if sender.send(Ok(fetched)).is_err() {
+                            break;
+                        }
+                        number += 1;
+                    }
+                    // Reach the tip, wait 50ms for next block
+                    Ok(None) => {
+                        if number > 0 && tip_number != Some(number - 1) {
+                            tip_number = Some(number - 1);
+                            health.lock().expect("lock indexer health").tip_number = tip_number;
+                        }
+                        sleep(Duration::from_millis(50))
+                    }
+                    Err(err) => {
+                        if sender.send(Err(err)).is_err() {
+                            break;
+                        }
+                        sleep(Duration::from_millis(1000));
+                    }
+                }
+            }
+        });
+        Pipeline {
+            next_number: from_number,
+            receiver,
+            stopped,
+        }
+    }
+}
+
+fn fetch_block(
+    db: &dyn KvStore,
+    client: &mut dyn ChainSource,
+    cache: &Mutex<OutputCache>,
+    number: u64,
+) -> Result<Option<FetchedBlock>, String> {
+    let header = match client.get_header_by_number(number)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let block = match client.get_block(header.hash.clone())? {
+        Some(block) => block,
+        None => {
+            log::warn!("Can not get block by hash: {:?}", header.hash);
+            return Ok(None);
+        }
+    };
+    {
+        // Inputs of this block and later blocks may spend these outputs
+        let mut cache = cache.lock().expect("lock output cache");
+        for tx in &block.transactions {
+            for (index, output) in tx.inner.outputs.iter().enumerate() {
+                let out_point = packed::OutPoint::new(tx.hash.pack(), index as u32);
+                let data = tx.inner.outputs_data[index].clone().into_bytes();
+                let cell = SpentCell {
+                    output: output.clone(),
+                    data_size: data.len() as u32,
+                    data: Some(data),
+                };
+                cache.insert(out_point, cell);
+            }
+        }
+    }
+    let inputs = resolve_inputs(db, &*client, cache, &block)?;
+    Ok(Some(FetchedBlock {
+        header,
+        block,
+        inputs,
+    }))
+}
+
+fn resolve_inputs(
+    db: &dyn KvStore,
+    client: &dyn ChainSource,
+    cache: &Mutex<OutputCache>,
+    block: &BlockView,
+) -> Result<HashMap<packed::OutPoint, SpentCell>, String> {
+    let mut inputs = HashMap::default();
+    let mut missing: HashMap<H256, Vec<packed::OutPoint>> = HashMap::default();
+    {
+        let cache = cache.lock().expect("lock output cache");
+        for tx in &block.transactions {
+            for input in &tx.inner.inputs {
+                // Cellbase input
+                if input.previous_output.tx_hash == H256::default() {
+                    continue;
+                }
+                let out_point = packed::OutPoint::from(input.previous_output.clone());
+                if let Some(cell) = cache.get(&out_point) {
+                    inputs.insert(out_point, cell);
+                } else if let Some(cell) = db_get::<_, value::LiveCellMap>(
+                    db,
+                    &Bytes::from(&Key::LiveCellMap(out_point.clone())),
+                )? {
+                    inputs.insert(out_point, SpentCell::from(cell));
+                } else {
+                    missing
+                        .entry(input.previous_output.tx_hash.clone())
+                        .or_default()
+                        .push(out_point);
+                }
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(inputs);
+    }
+    log::debug!("fetch {} transactions not indexed", missing.len());
+
+    let tx_hashes = missing.keys().cloned().collect::<Vec<_>>();
+    let chunk_size = (tx_hashes.len() + RESOLVE_WORKERS - 1) / RESOLVE_WORKERS;
+    let handles = tx_hashes
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let mut client = client.clone_box();
+            let chunk = chunk.to_vec();
+            thread::spawn(move || {
+                chunk
+                    .into_iter()
+                    .map(|tx_hash| {
+                        client
+                            .get_transaction(tx_hash.clone())?
+                            .map(|tx| (tx_hash.clone(), tx.transaction.inner))
+                            .ok_or_else(|| format!("Can not get transaction: {:#x}", tx_hash))
+                    })
+                    .collect::<Result<Vec<_>, String>>()
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        let txs = handle
+            .join()
+            .map_err(|_| String::from("resolve inputs thread panic"))??;
+        for (tx_hash, tx) in txs {
+            for out_point in &missing[&tx_hash] {
+                let index: u32 = out_point.index().unpack();
+                let index = index as usize;
+                let data = tx.outputs_data[index].clone().into_bytes();
+                let cell = SpentCell {
+                    output: tx.outputs[index].clone(),
+                    data_size: data.len() as u32,
+                    data: Some(data),
+                };
+                inputs.insert(out_point.clone(), cell);
+            }
+        }
+    }
+    Ok(inputs)
+}
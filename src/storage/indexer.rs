@@ -1,7 +1,7 @@
-use bincode::serialize;
+use bincode::{deserialize, serialize};
 use ckb_chain_spec::consensus::TYPE_ID_CODE_HASH;
-use ckb_hash::new_blake2b;
-use ckb_jsonrpc_types::{CellOutput, JsonBytes, Script, ScriptHashType};
+use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_jsonrpc_types::{CellOutput, JsonBytes, OutPoint, Script, ScriptHashType};
 use ckb_simple_account_layer::{run_with_context, CkbBlake2bHasher, Config, RunContext, RunResult};
 use ckb_types::{
     bytes::{BufMut, Bytes, BytesMut},
@@ -13,22 +13,26 @@ use ckb_vm::{
     registers::{A0, A1, A2, A3, A4, A7},
     Error as VMError, Memory, Register, SupportMachine,
 };
-use rocksdb::{WriteBatch, DB};
-use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree, H256 as SmtH256};
+use serde::{Deserialize, Serialize};
+use sparse_merkle_tree::{SparseMerkleTree, H256 as SmtH256};
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::error::Error as StdError;
-use std::sync::Arc;
+use std::fmt;
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
 
-use super::{db_get, value, Key, Loader};
-use crate::client::HttpRpcClient;
+use super::{
+    db_get, db_iter, value, Batch, BlockFetcher, Key, KeyType, KvStore, Loader, PendingPool,
+    SmtStore,
+};
+use crate::chain::ChainSource;
 use crate::types::{
     cell_balance, contract_account_balance, h256_to_smth256, parse_log, smth256_to_h256,
     vm_load_data, vm_load_h160, vm_load_h256, vm_load_i32, vm_load_i64, vm_load_u256, vm_load_u32,
-    vm_load_u8, CallKind, ContractAddress, ContractChange, ContractMeta, EoaAddress, RunConfig,
-    WitnessData, ONE_CKB,
+    vm_load_u8, CallKind, CallRecord, ContractAddress, ContractChange, ContractMeta, EoaAddress,
+    RunConfig, WitnessData, ONE_CKB,
 };
 
 pub const TYPE_ARGS_LEN: usize = 20;
@@ -36,197 +40,194 @@ pub const TYPE_ARGS_LEN: usize = 20;
 pub const OUTPUT_DATA_LEN: usize = 32 + 32;
 
 pub struct Indexer {
-    pub db: Arc<DB>,
+    pub db: Arc<dyn KvStore>,
     pub loader: Loader,
-    pub client: HttpRpcClient,
+    pub client: Box<dyn ChainSource>,
+    pub fetcher: BlockFetcher,
     pub run_config: RunConfig,
+    pub checkpoint: Option<Checkpoint>,
+    /// Skip invalid polyjuice transactions instead of halting
+    pub skip_invalid_tx: bool,
+    pub health: Arc<Mutex<IndexerHealth>>,
+}
+
+/// Where indexing starts when the database is empty
+///
+/// Polyjuice contracts must not be created before the checkpoint block, the
+/// checkpoint is rejected otherwise (the storage of a contract is not in its
+/// cell). Live EoA cells created before it are seeded from `eoa_cells`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Checkpoint {
+    pub number: u64,
+    /// Check the hash of the checkpoint block if provided
+    pub hash: Option<H256>,
+    #[serde(default)]
+    pub eoa_cells: Vec<OutPoint>,
+}
+
+// How many skipped transactions are kept in IndexerHealth
+const MAX_QUARANTINED: usize = 100;
+
+#[derive(Debug)]
+pub enum IndexerError {
+    /// CKB RPC failure, indexing will be retried
+    Rpc(String),
+    /// The fork is deeper than the indexed history
+    DeepFork(u64),
+    /// Invalid polyjuice transaction when skipping is disabled
+    InvalidTransaction { tx_hash: H256, reason: String },
+    /// Database failure or corrupted data
+    Fatal(String),
+}
+
+impl fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexerError::Rpc(err) => write!(f, "RPC error: {}", err),
+            IndexerError::DeepFork(number) => write!(
+                f,
+                "Fork is deeper than the indexed history (block {}), please stop polyjuice, \
+                 remove the database directory and start again to resync",
+                number
+            ),
+            IndexerError::InvalidTransaction { tx_hash, reason } => {
+                write!(f, "Invalid transaction {:#x}: {}", tx_hash, reason)
+            }
+            IndexerError::Fatal(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl StdError for IndexerError {}
+
+impl From<String> for IndexerError {
+    fn from(err: String) -> IndexerError {
+        IndexerError::Fatal(err)
+    }
+}
+
+/// Shared with the RPC server
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IndexerHealth {
+    /// The indexer thread is running
+    pub alive: bool,
+    /// Indexing stopped on a fatal error
+    pub halted: bool,
+    /// Rollbacks since start
+    pub rollback_count: u64,
+    /// The last known CKB tip block number, recorded while fetching blocks
+    pub tip_number: Option<u64>,
+    pub last_error: Option<String>,
+    /// Recently skipped invalid polyjuice transactions
+    pub quarantined: Vec<QuarantinedTx>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedTx {
+    pub number: u64,
+    pub tx_hash: H256,
+    pub reason: String,
+    /// The contracts tainted by the transaction
+    pub contracts: Vec<ContractAddress>,
 }
 
 impl Indexer {
-    pub fn new(db: Arc<DB>, ckb_uri: &str, run_config: RunConfig) -> Self {
-        let loader = Loader::new(Arc::clone(&db), ckb_uri).unwrap();
+    pub fn new(
+        db: Arc<dyn KvStore>,
+        client: Box<dyn ChainSource>,
+        run_config: RunConfig,
+        pending: Arc<Mutex<PendingPool>>,
+        checkpoint: Option<Checkpoint>,
+        skip_invalid_tx: bool,
+        health: Arc<Mutex<IndexerHealth>>,
+    ) -> Self {
+        let loader = Loader::new(Arc::clone(&db), client.clone(), pending).unwrap();
+        let fetcher = BlockFetcher::new(Arc::clone(&db), client.clone(), Arc::clone(&health));
         Indexer {
             db,
             loader,
-            client: HttpRpcClient::new(ckb_uri.to_string()),
+            client,
+            fetcher,
             run_config,
+            checkpoint,
+            skip_invalid_tx,
+            health,
         }
     }
 
     // Ideally this should never return. The caller is responsible for wrapping
     // it into a separate thread.
-    pub fn index(&mut self) -> Result<(), String> {
+    pub fn run(&mut self) {
+        // Also reset when the indexer thread panics
+        let _alive_guard = AliveGuard::new(Arc::clone(&self.health));
+        loop {
+            match self.index() {
+                Err(IndexerError::Rpc(err)) => {
+                    log::warn!("RPC error: {}", err);
+                    self.health.lock().expect("lock indexer health").last_error = Some(err);
+                    sleep(Duration::from_millis(5000));
+                }
+                Err(err) => {
+                    log::error!("Indexer failure: {}", err);
+                    let mut health = self.health.lock().expect("lock indexer health");
+                    health.halted = true;
+                    health.last_error = Some(err.to_string());
+                    return;
+                }
+                Ok(()) => return,
+            }
+        }
+    }
+
+    pub fn index(&mut self) -> Result<(), IndexerError> {
         let type_code_hash: H256 = self.run_config.type_script.code_hash().unpack();
         let type_hash_type = {
             let ty =
                 core::ScriptHashType::try_from(self.run_config.type_script.hash_type()).unwrap();
             ScriptHashType::from(ty)
         };
-        let eoa_lock_code_hash: H256 = self.run_config.eoa_lock_script.code_hash().unpack();
-        let eoa_lock_hash_type = {
-            let ty = core::ScriptHashType::try_from(self.run_config.eoa_lock_script.hash_type())
-                .unwrap();
-            ScriptHashType::from(ty)
-        };
         log::info!("type code hash: {:x}", type_code_hash);
         log::info!("type hash type: {:?}", type_hash_type);
-        log::info!("eoa lock code hash: {:x}", eoa_lock_code_hash);
-        log::info!("eoa lock hash type: {:?}", eoa_lock_hash_type);
+        for (kind, script) in self.run_config.eoa_lock_scripts() {
+            let code_hash: H256 = script.code_hash().unpack();
+            log::info!("eoa lock {:?}: code hash {:x}", kind, code_hash);
+        }
         let last_block_key_bytes = Bytes::from(&Key::Last);
+        if let Some(checkpoint) = self.checkpoint.take() {
+            if db_get::<_, value::Last>(&self.db, &last_block_key_bytes)?.is_none() {
+                self.init_checkpoint(&checkpoint)?;
+            } else {
+                log::info!("Database already initialized, ignore checkpoint");
+            }
+        }
         loop {
-            let next_header = if let Some(value::Last { number, hash }) =
-                db_get(&self.db, &last_block_key_bytes)?
-            {
-                match self.client.get_header_by_number(number + 1) {
-                    Ok(Some(header)) if header.inner.parent_hash == hash => header,
-                    // Rollback
-                    Ok(Some(_header)) => {
-                        log::info!("Rollback block, nubmer={}, hash={}", number, hash);
-                        let block_delta_key = Bytes::from(&Key::BlockDelta(number));
-                        let block_delta: value::BlockDelta = db_get(&self.db, &block_delta_key)?
-                            .unwrap_or_else(|| panic!("Can not load BlockDelta({})", number));
-                        let last_block_info_opt = if number >= 1 {
-                            let last_block_map_key = Bytes::from(&Key::BlockMap(number - 1));
-                            let block_hash: value::BlockMap =
-                                db_get(&self.db, &last_block_map_key)?.unwrap_or_else(|| {
-                                    panic!("Can not load BlockMap({})", number - 1)
-                                });
-                            Some(value::Last {
-                                number: number - 1,
-                                hash: block_hash.0,
-                            })
-                        } else {
-                            None
-                        };
-
-                        let mut batch = WriteBatch::default();
-                        for (address, is_create) in block_delta.contracts {
-                            let change_start_key = Key::ContractChange {
-                                address: address.clone(),
-                                number: Some(number),
-                                tx_index: None,
-                                output_index: None,
-                            };
-                            let change_end_key = Key::ContractChange {
-                                address: address.clone(),
-                                number: Some(number + 1),
-                                tx_index: None,
-                                output_index: None,
-                            };
-                            let logs_start_key = Key::ContractLogs {
-                                address: address.clone(),
-                                number: Some(number),
-                                tx_index: None,
-                                output_index: None,
-                            };
-                            let logs_end_key = Key::ContractLogs {
-                                address: address.clone(),
-                                number: Some(number + 1),
-                                tx_index: None,
-                                output_index: None,
-                            };
-                            batch.delete_range(
-                                &Bytes::from(&change_start_key),
-                                &Bytes::from(&change_end_key),
-                            );
-                            batch.delete_range(
-                                &Bytes::from(&logs_start_key),
-                                &Bytes::from(&logs_end_key),
-                            );
-                            if is_create {
-                                batch.delete(&Bytes::from(&Key::ContractMeta(address)));
-                            }
-                        }
-                        for (lock_hash, tx_index, output_index, value) in block_delta.added_cells {
-                            batch.delete(&Bytes::from(&Key::LockLiveCell {
-                                lock_hash,
-                                number: Some(number),
-                                tx_index: Some(tx_index),
-                                output_index: Some(output_index),
-                            }));
-                            batch.delete(&Bytes::from(&Key::LiveCellMap(value.out_point())));
-                        }
-                        for (lock_hash, old_number, tx_index, output_index, value) in
-                            block_delta.removed_cells
-                        {
-                            let key = Key::LockLiveCell {
-                                lock_hash,
-                                number: Some(old_number),
-                                tx_index: Some(tx_index),
-                                output_index: Some(output_index),
-                            };
-                            batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
-                            let map_key = Key::LiveCellMap(value.out_point());
-                            let map_value = value::LiveCellMap {
-                                number: old_number,
-                                tx_index,
-                            };
-                            batch.put(&Bytes::from(&map_key), &serialize(&map_value).unwrap());
-                        }
-                        for eoa_address in block_delta.eoa_added_cells {
-                            batch.delete(&Bytes::from(&Key::EoaLiveCell(eoa_address)));
-                        }
-                        for (eoa_address, value) in block_delta.eoa_removed_cells {
-                            let key = Key::EoaLiveCell(eoa_address);
-                            batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
-                        }
-                        for contract_address in block_delta.destructed_contracts {
-                            let key_bytes =
-                                Bytes::from(&Key::ContractMeta(contract_address.clone()));
-                            let mut meta: value::ContractMeta = db_get(&self.db, &key_bytes)?
-                                .ok_or_else(|| {
-                                    format!("no such contract: {:x}", contract_address.0)
-                                })?;
-                            assert_eq!(meta.destructed, true);
-                            meta.destructed = false;
-                            batch.put(&key_bytes, &serialize(&meta).unwrap());
-                        }
-                        batch.delete(&Bytes::from(&Key::BlockMap(number)));
-                        batch.delete(&block_delta_key);
-                        // Update last block info
-                        if let Some(block_info) = last_block_info_opt {
-                            let value_bytes =
-                                serialize(&block_info).map_err(|err| err.to_string())?;
-                            batch.put(&last_block_key_bytes, &value_bytes);
-                        }
-                        self.db.write(batch).map_err(|err| err.to_string())?;
-                        continue;
-                    }
-                    Ok(None) => {
-                        // Reach the tip, wait 50ms for next block
-                        sleep(Duration::from_millis(50));
-                        // TODO: clean up OLD block delta here (before tip-200)
-                        continue;
-                    }
-                    Err(err) => {
-                        log::warn!("RPC error: {}", err);
-                        sleep(Duration::from_millis(5000));
-                        continue;
-                    }
+            let last_opt: Option<value::Last> = db_get(&self.db, &last_block_key_bytes)?;
+            let next_number = last_opt.as_ref().map(|last| last.number + 1).unwrap_or(0);
+            let fetched = match self.fetcher.next_block(next_number) {
+                Ok(fetched) => fetched,
+                Err(err) => {
+                    log::warn!("RPC error: {}", err);
+                    self.health.lock().expect("lock indexer health").last_error = Some(err);
+                    sleep(Duration::from_millis(1000));
+                    continue;
                 }
-            } else {
-                self.client.get_header_by_number(0)?.unwrap()
             };
+            if let Some(value::Last { number, hash }) = last_opt {
+                if fetched.header.inner.parent_hash != hash {
+                    log::info!("Fork detected, number={}, hash={:#x}", number, hash);
+                    self.rollback(number)?;
+                    continue;
+                }
+            }
 
+            let next_header = fetched.header;
+            let next_block = fetched.block;
+            let block_inputs = fetched.inputs;
             log::debug!(
                 "get block {} => {:x}",
                 next_header.inner.number.value(),
                 next_header.hash
             );
-            let next_block = match self.client.get_block(next_header.hash.clone()) {
-                Ok(Some(block)) => block,
-                Ok(None) => {
-                    log::warn!("Can not get block by hash: {:?}", next_header.hash);
-                    sleep(Duration::from_millis(200));
-                    continue;
-                }
-                Err(err) => {
-                    log::error!("RPC error: {}", err);
-                    sleep(Duration::from_millis(1000));
-                    continue;
-                }
-            };
-
             let next_number = next_header.inner.number.value();
             let next_hash = next_header.hash;
 
@@ -237,17 +238,29 @@ impl Indexer {
             );
 
             let mut block_changes: Vec<ContractChange> = Vec::new();
+            // The storage trees updated in this block, later transactions may
+            // spend the contract cells of earlier ones
+            let mut block_trees: HashMap<ContractAddress, SmtStore> = HashMap::new();
             let mut block_codes: Vec<ContractMeta> = Vec::new();
             let mut destructed_contracts: Vec<ContractAddress> = Vec::new();
 
             let mut eoa_added_cells: HashMap<H160, value::EoaLiveCell> = HashMap::new();
             let mut eoa_removed_cells: HashMap<H160, value::EoaLiveCell> = HashMap::new();
             let mut added_cells: HashSet<(H256, u32, u32, value::LockLiveCell)> = HashSet::new();
-            let mut removed_cells: HashSet<(H256, u64, u32, u32, value::LockLiveCell)> =
-                HashSet::new();
-            let mut block_added_cells: HashMap<value::LockLiveCell, value::LiveCellMap> =
+            let mut removed_cells: HashSet<(
+                H256,
+                u64,
+                u32,
+                u32,
+                value::LockLiveCell,
+                value::LiveCellMap,
+            )> = HashSet::new();
+            let mut block_added_cells: HashMap<packed::OutPoint, value::LiveCellMap> =
                 HashMap::default();
-            let mut block_removed_cells: HashSet<value::LockLiveCell> = HashSet::default();
+            let mut block_removed_cells: HashSet<packed::OutPoint> = HashSet::default();
+            // For cleaning up pending transactions after the block is committed
+            let mut block_txs: Vec<(H256, Vec<packed::OutPoint>)> = Vec::new();
+            let mut tainted_contracts: Vec<value::TaintedContract> = Vec::new();
             for (tx_index, (tx, tx_hash)) in next_block
                 .transactions
                 .into_iter()
@@ -261,6 +274,15 @@ impl Indexer {
                 //   3. tx_index
                 let mut script_groups: HashMap<ContractAddress, ContractInfo> = HashMap::default();
                 let mut eoa_accounts: HashMap<H160, (u64, u64)> = HashMap::default();
+                // The contract changes of an invalid transaction are not indexed
+                let mut invalid_reason: Option<String> = None;
+                block_txs.push((
+                    tx_hash.clone(),
+                    tx.inputs
+                        .iter()
+                        .map(|input| packed::OutPoint::from(input.previous_output.clone()))
+                        .collect(),
+                ));
 
                 for (input_index, input) in tx.inputs.into_iter().enumerate() {
                     // Information from input
@@ -271,16 +293,13 @@ impl Indexer {
                         continue;
                     }
 
-                    let prev_tx = self
-                        .client
-                        .get_transaction(input.previous_output.tx_hash.clone())?
-                        .unwrap()
-                        .transaction
-                        .inner;
-                    let prev_index = input.previous_output.index.value() as usize;
-                    let output = prev_tx.outputs[prev_index].clone();
-                    let output_data_size = prev_tx.outputs_data[prev_index].len() as u32;
-                    let data = prev_tx.outputs_data[prev_index].clone().into_bytes();
+                    let out_point = packed::OutPoint::from(input.previous_output.clone());
+                    let spent_cell = block_inputs.get(&out_point).cloned().ok_or_else(|| {
+                        format!("input not resolved: {:?}", input.previous_output)
+                    })?;
+                    let output = spent_cell.output;
+                    let output_data_size = spent_cell.data_size;
+                    let data = spent_cell.data.unwrap_or_default();
                     let capacity = output.capacity.value();
                     if let Some(ref type_script) = output.type_ {
                         log::debug!(
@@ -296,6 +315,8 @@ impl Indexer {
                     }
                     let type_script = output.type_.clone().unwrap_or_default();
                     let lock_script = output.lock.clone();
+                    // Contract cells are matched by the type script, the lock may be
+                    // the configured one or a legacy one
                     if data.len() == OUTPUT_DATA_LEN
                         && type_script.code_hash == type_code_hash
                         && type_script.hash_type == type_hash_type
@@ -304,31 +325,36 @@ impl Indexer {
                         log::debug!("match type script: input_index={}", input_index);
                         let address = ContractAddress::try_from(type_script.args.as_bytes())
                             .expect("checked length");
-                        let change = self.loader.load_latest_contract_change(
+                        match self.loader.load_latest_contract_change(
                             address.clone(),
                             None,
                             false,
                             true,
-                        )?;
-                        let mut info = ContractInfo::default();
-                        info.tree = change.merkle_tree();
-                        info.input = Some((input_index, change));
-                        info.input_balance = info.init_balance();
-                        info.input_capacity = capacity;
-                        script_groups.insert(address, info);
+                        ) {
+                            Ok(change) => {
+                                let mut root = [0u8; 32];
+                                root.copy_from_slice(&data[0..32]);
+                                let store =
+                                    block_trees.get(&address).cloned().unwrap_or_else(|| {
+                                        SmtStore::new(Arc::clone(&self.db), address.clone())
+                                    });
+                                let mut info = ContractInfo::default();
+                                info.input_root = root.into();
+                                info.tree = SparseMerkleTree::new(info.input_root, store);
+                                info.input = Some((input_index, change));
+                                info.input_balance = info.init_balance();
+                                info.input_capacity = capacity;
+                                script_groups.insert(address, info);
+                            }
+                            Err(err) => invalid_reason = Some(err),
+                        }
                     }
-                    let out_point = packed::OutPoint::from(input.previous_output.clone());
                     let prev_tx_hash = input.previous_output.tx_hash;
                     let prev_output_index = input.previous_output.index.value();
                     let lock_hash: H256 = packed::Script::from(output.lock.clone())
                         .calc_script_hash()
                         .unpack();
-                    let is_eoa = is_eoa(
-                        &type_script,
-                        &lock_script,
-                        &eoa_lock_code_hash,
-                        &eoa_lock_hash_type,
-                    );
+                    let is_eoa = is_eoa(&type_script, &lock_script, &self.run_config);
                     let value = value::LockLiveCell {
                         tx_hash: prev_tx_hash,
                         output_index: prev_output_index,
@@ -340,12 +366,14 @@ impl Indexer {
                             .map(packed::Script::from)
                             .map(|data| data.calc_script_hash().unpack()),
                     };
-                    let info: value::LiveCellMap =
-                        block_added_cells.get(&value).cloned().unwrap_or_else(|| {
-                            db_get(&self.db, &Bytes::from(&Key::LiveCellMap(out_point.clone())))
-                                .unwrap()
-                                .unwrap()
-                        });
+                    let info_opt: Option<value::LiveCellMap> = match block_added_cells
+                        .get(&out_point)
+                    {
+                        Some(info) => Some(info.clone()),
+                        None => {
+                            db_get(&self.db, &Bytes::from(&Key::LiveCellMap(out_point.clone())))?
+                        }
+                    };
                     if is_eoa {
                         let (eoa_address, eoa_value) = eoa_record(
                             &type_script,
@@ -362,21 +390,31 @@ impl Indexer {
                             eoa_removed_cells.insert(eoa_address, eoa_value);
                         }
                     }
-                    block_removed_cells.insert(value.clone());
-                    removed_cells.insert((
-                        lock_hash,
-                        info.number,
-                        info.tx_index,
-                        prev_output_index,
-                        value,
-                    ));
+                    if let Some(info) = info_opt {
+                        block_removed_cells.insert(out_point);
+                        removed_cells.insert((
+                            lock_hash,
+                            info.number,
+                            info.tx_index,
+                            prev_output_index,
+                            value,
+                            info,
+                        ));
+                    } else {
+                        // Created before indexing started
+                        log::debug!("spent cell not indexed: {:?}", out_point);
+                    }
                 }
 
                 for (output_index, output) in tx.outputs.into_iter().enumerate() {
                     // Information from output
                     //   1. contract address
                     //   2. output_index
-                    let data = tx.outputs_data[output_index].clone().into_bytes();
+                    let data = tx
+                        .outputs_data
+                        .get(output_index)
+                        .map(|data| data.clone().into_bytes())
+                        .unwrap_or_default();
                     let data_size = data.len() as u32;
                     if let Some(ref type_script) = output.type_ {
                         log::debug!(
@@ -402,23 +440,19 @@ impl Indexer {
                             .expect("checked length");
                         let info = script_groups.entry(address).or_default();
                         if info.output.is_some() {
-                            panic!("multiple output contract address");
+                            invalid_reason = Some(String::from("multiple output contract address"));
+                        } else {
+                            let packed_output = packed::CellOutput::from(output.clone());
+                            info.output_balance =
+                                cell_balance(&packed_output, (data_size as u64) * ONE_CKB);
+                            info.output_capacity = output.capacity.value();
+                            info.output = Some((output_index, packed_output));
                         }
-                        let packed_output = packed::CellOutput::from(output.clone());
-                        info.output_balance =
-                            cell_balance(&packed_output, (data_size as u64) * ONE_CKB);
-                        info.output_capacity = output.capacity.value();
-                        info.output = Some((output_index, packed_output));
                     }
                     let lock_hash: H256 = packed::Script::from(output.lock.clone())
                         .calc_script_hash()
                         .unpack();
-                    let is_eoa = is_eoa(
-                        &type_script,
-                        &lock_script,
-                        &eoa_lock_code_hash,
-                        &eoa_lock_hash_type,
-                    );
+                    let is_eoa = is_eoa(&type_script, &lock_script, &self.run_config);
                     let value = value::LockLiveCell {
                         tx_hash: tx_hash.clone(),
                         output_index: output_index as u32,
@@ -431,11 +465,8 @@ impl Indexer {
                             .map(|data| data.calc_script_hash().unpack()),
                     };
                     block_added_cells.insert(
-                        value.clone(),
-                        value::LiveCellMap {
-                            number: next_number,
-                            tx_index: tx_index as u32,
-                        },
+                        value.out_point(),
+                        live_cell_map(next_number, tx_index as u32, &output, &data),
                     );
                     if is_eoa {
                         let (eoa_address, eoa_value) = eoa_record(
@@ -448,10 +479,15 @@ impl Indexer {
                         );
                         if eoa_removed_cells.contains_key(&eoa_address) {
                             let _ = eoa_removed_cells.remove(&eoa_address);
-                            eoa_accounts
-                                .get_mut(&eoa_address)
-                                .expect("eoa not exists")
-                                .1 = eoa_value.balance();
+                            match eoa_accounts.get_mut(&eoa_address) {
+                                Some(account) => account.1 = eoa_value.balance(),
+                                None => {
+                                    invalid_reason = Some(format!(
+                                        "EoA {:x} spent by an earlier transaction",
+                                        eoa_address
+                                    ))
+                                }
+                            }
                         }
                         eoa_added_cells.insert(eoa_address, eoa_value);
                     }
@@ -461,36 +497,72 @@ impl Indexer {
                 if let Some(tip_block_hash) = tx.header_deps.get(0) {
                     let tip_block = self
                         .client
-                        .get_block(tip_block_hash.clone())?
+                        .get_block(tip_block_hash.clone())
+                        .map_err(IndexerError::Rpc)?
                         .map(core::BlockView::from)
-                        .expect("block not exists");
+                        .ok_or_else(|| {
+                            IndexerError::Rpc(format!("block not exists: {:#x}", tip_block_hash))
+                        })?;
                     let mut header_deps = HashMap::default();
                     for block_hash in tx.header_deps {
                         let header_view = self
                             .client
-                            .get_header(block_hash.clone())?
+                            .get_header(block_hash.clone())
+                            .map_err(IndexerError::Rpc)?
                             .map(core::HeaderView::from)
-                            .expect("header deps block not exists");
+                            .ok_or_else(|| {
+                                IndexerError::Rpc(format!(
+                                    "header deps block not exists: {:#x}",
+                                    block_hash
+                                ))
+                            })?;
                         header_deps.insert(header_view.number(), header_view);
                     }
-                    if let Some(mut extractor) = ContractExtractor::init(
-                        self.run_config.clone(),
-                        tip_block,
-                        header_deps,
-                        (tx_hash, tx_index as u32),
-                        tx.witnesses,
-                        script_groups,
-                        eoa_accounts,
-                    )? {
-                        extractor.run().map_err(|err| err.to_string())?;
-                        block_changes.extend(extractor.get_contract_changes(next_number));
-                        block_codes.extend(extractor.get_created_contracts());
-                        destructed_contracts.extend(extractor.get_destructed_contracts());
+                    let tx_contracts = script_groups.keys().cloned().collect::<Vec<_>>();
+                    let result = match invalid_reason {
+                        Some(reason) => Err(reason),
+                        None => ContractExtractor::init(
+                            self.run_config.clone(),
+                            tip_block,
+                            header_deps,
+                            (tx_hash.clone(), tx_index as u32),
+                            tx.witnesses,
+                            script_groups,
+                            eoa_accounts,
+                        )
+                        .and_then(|extractor_opt| {
+                            if let Some(mut extractor) = extractor_opt {
+                                extractor.run().map_err(|err| err.to_string())?;
+                                let changes = extractor.get_contract_changes(next_number)?;
+                                let destructed = extractor.get_destructed_contracts()?;
+                                Ok(Some((extractor, changes, destructed)))
+                            } else {
+                                Ok(None)
+                            }
+                        }),
+                    };
+                    match result {
+                        Ok(Some((extractor, changes, destructed))) => {
+                            block_changes.extend(changes);
+                            block_trees.extend(extractor.get_contract_trees());
+                            block_codes.extend(extractor.get_created_contracts());
+                            destructed_contracts.extend(destructed);
+                        }
+                        Ok(None) => {}
+                        Err(reason) => {
+                            let tainted =
+                                self.quarantine(next_number, &tx_hash, reason, tx_contracts)?;
+                            tainted_contracts.extend(tainted);
+                        }
                     }
                 }
             }
 
-            let mut batch = WriteBatch::default();
+            let mut batch = Batch::new(&self.db);
+            // Key::SmtBranch, Key::SmtLeaf
+            for (address, store) in &block_trees {
+                store.write_nodes(&mut batch, address);
+            }
             // Key::BlockMap
             let block_map_value_bytes = serialize(&value::BlockMap(next_hash.clone())).unwrap();
             batch.put(
@@ -506,12 +578,27 @@ impl Indexer {
             let last_block_info_bytes = serialize(&last_block_info).unwrap();
             batch.put(&last_block_key_bytes, &last_block_info_bytes);
 
+            // {address => created in this block}, sticky once created
             let mut block_contracts: HashMap<ContractAddress, bool> = HashMap::default();
             for change in block_changes {
-                block_contracts.insert(change.address.clone(), change.is_create);
+                *block_contracts
+                    .entry(change.address.clone())
+                    .or_insert(false) |= change.is_create;
                 // Key::ContractChange
                 let db_value_bytes = serialize(&change.db_value()).unwrap();
                 batch.put(&Bytes::from(&change.db_key()), &db_value_bytes);
+                // Key::ContractState
+                for (key, _old_value, new_value) in &change.storage_diff {
+                    let state_key = Bytes::from(&Key::ContractState {
+                        address: change.address.clone(),
+                        storage_key: Some(key.clone()),
+                    });
+                    if new_value == &H256::default() {
+                        batch.delete(&state_key);
+                    } else {
+                        batch.put(&state_key, &serialize(new_value).unwrap());
+                    }
+                }
                 if !change.is_create {
                     let meta_key_bytes = Bytes::from(&Key::ContractMeta(change.address.clone()));
                     let mut meta: value::ContractMeta = db_get(&self.db, &meta_key_bytes)?
@@ -542,7 +629,7 @@ impl Indexer {
                 .cloned()
                 .collect::<HashSet<_>>();
             for (lock_hash, tx_index, output_index, value) in added_cells.clone() {
-                if common_cells.contains(&value) {
+                if common_cells.contains(&value.out_point()) {
                     continue;
                 }
                 log::debug!(
@@ -558,14 +645,13 @@ impl Indexer {
                 };
                 batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
                 let map_key = Key::LiveCellMap(value.out_point());
-                let map_value = value::LiveCellMap {
-                    number: next_number,
-                    tx_index,
-                };
-                batch.put(&Bytes::from(&map_key), &serialize(&map_value).unwrap());
+                let map_value = block_added_cells
+                    .get(&value.out_point())
+                    .expect("added cell");
+                batch.put(&Bytes::from(&map_key), &serialize(map_value).unwrap());
             }
-            for (lock_hash, number, tx_index, output_index, value) in removed_cells.clone() {
-                if common_cells.contains(&value) {
+            for (lock_hash, number, tx_index, output_index, value, _) in removed_cells.clone() {
+                if common_cells.contains(&value.out_point()) {
                     continue;
                 }
                 log::debug!(
@@ -595,12 +681,19 @@ impl Indexer {
 
             // selfdestruct
             for contract_address in &destructed_contracts {
-                // For clean up logs when rollback
-                block_contracts.insert(contract_address.clone(), false);
+                // For clean up logs when rollback, keep it if created in this block
+                block_contracts
+                    .entry(contract_address.clone())
+                    .or_insert(false);
                 let key_bytes = Bytes::from(&Key::ContractMeta(contract_address.clone()));
                 let mut meta: value::ContractMeta = db_get(&self.db, &key_bytes)?
                     .ok_or_else(|| format!("no such contract: {:x}", contract_address.0))?;
-                assert_eq!(meta.destructed, false);
+                if meta.destructed {
+                    return Err(IndexerError::Fatal(format!(
+                        "contract already destructed: {:x}",
+                        contract_address.0
+                    )));
+                }
                 meta.destructed = true;
                 batch.put(&key_bytes, &serialize(&meta).unwrap());
             }
@@ -621,25 +714,625 @@ impl Indexer {
                 &Bytes::from(&Key::BlockDelta(next_number)),
                 &block_contracts_bytes,
             );
+            // Key::TaintedContracts
+            if !tainted_contracts.is_empty() {
+                let tainted_key_bytes = Bytes::from(&Key::TaintedContracts);
+                let mut tainted: value::TaintedContracts =
+                    db_get(&self.db, &tainted_key_bytes)?.unwrap_or_default();
+                for contract in tainted_contracts {
+                    if tainted.0.iter().all(|old| old.address != contract.address) {
+                        tainted.0.push(contract);
+                    }
+                }
+                batch.put(&tainted_key_bytes, &serialize(&tainted).unwrap());
+            }
+
+            batch.commit()?;
+            for (tx_hash, inputs) in block_txs {
+                self.loader.commit_pending_transaction(&tx_hash, &inputs);
+            }
+        }
+    }
+
+    // Skip the invalid transaction if allowed, the contracts it touches are
+    // tainted: their indexed state may be wrong from now on
+    fn quarantine(
+        &mut self,
+        number: u64,
+        tx_hash: &H256,
+        reason: String,
+        contracts: Vec<ContractAddress>,
+    ) -> Result<Vec<value::TaintedContract>, IndexerError> {
+        if !self.skip_invalid_tx {
+            return Err(IndexerError::InvalidTransaction {
+                tx_hash: tx_hash.clone(),
+                reason,
+            });
+        }
+        log::error!(
+            "Skip invalid transaction: hash={:#x}, reason={}, tainted contracts: {}",
+            tx_hash,
+            reason,
+            contracts.len()
+        );
+        let tainted = contracts
+            .iter()
+            .map(|address| value::TaintedContract {
+                address: address.clone(),
+                number,
+                tx_hash: tx_hash.clone(),
+                reason: reason.clone(),
+            })
+            .collect();
+        let mut health = self.health.lock().expect("lock indexer health");
+        health.quarantined.push(QuarantinedTx {
+            number,
+            tx_hash: tx_hash.clone(),
+            reason,
+            contracts,
+        });
+        if health.quarantined.len() > MAX_QUARANTINED {
+            health.quarantined.remove(0);
+        }
+        Ok(tainted)
+    }
 
-            self.db.write(batch).map_err(|err| err.to_string())?;
+    // Seed the empty database, the checkpoint block is the next block to index
+    fn init_checkpoint(&mut self, checkpoint: &Checkpoint) -> Result<(), String> {
+        let header = self
+            .client
+            .get_header_by_number(checkpoint.number)?
+            .ok_or_else(|| format!("Checkpoint block not found: {}", checkpoint.number))?;
+        if let Some(ref hash) = checkpoint.hash {
+            if hash != &header.hash {
+                return Err(format!(
+                    "Checkpoint block hash not match, expected: {:#x}, got: {:#x}",
+                    hash, header.hash
+                ));
+            }
         }
+        self.check_no_contract_before(checkpoint.number)?;
+        log::info!(
+            "Start indexing from block: hash={:#x}, number={}",
+            header.hash,
+            checkpoint.number
+        );
+
+        let mut batch = Batch::new(&self.db);
+        for out_point in &checkpoint.eoa_cells {
+            let tx_hash = out_point.tx_hash.clone();
+            let output_index = out_point.index.value();
+            let tx = self
+                .client
+                .get_transaction(tx_hash.clone())?
+                .ok_or_else(|| format!("Checkpoint transaction not found: {:#x}", tx_hash))?;
+            let block_hash = tx
+                .tx_status
+                .block_hash
+                .ok_or_else(|| format!("Checkpoint transaction not committed: {:#x}", tx_hash))?;
+            let block = self
+                .client
+                .get_block(block_hash.clone())?
+                .ok_or_else(|| format!("Can not get block by hash: {:#x}", block_hash))?;
+            let number = block.header.inner.number.value();
+            if number >= checkpoint.number {
+                return Err(format!(
+                    "Checkpoint cell {:#x}-{} is created after the checkpoint block",
+                    tx_hash, output_index
+                ));
+            }
+            let tx_index = block
+                .transactions
+                .iter()
+                .position(|block_tx| block_tx.hash == tx_hash)
+                .ok_or_else(|| {
+                    format!("Transaction {:#x} not in block {:#x}", tx_hash, block_hash)
+                })? as u32;
+            let tx = tx.transaction.inner;
+            let output = tx
+                .outputs
+                .get(output_index as usize)
+                .cloned()
+                .ok_or_else(|| format!("No such cell: {:#x}-{}", tx_hash, output_index))?;
+            let data = tx.outputs_data[output_index as usize].clone().into_bytes();
+            let data_size = data.len() as u32;
+            let type_script = output.type_.clone().unwrap_or_default();
+            if !is_eoa(&type_script, &output.lock, &self.run_config) {
+                return Err(format!(
+                    "Checkpoint cell is not an EoA cell: {:#x}-{}",
+                    tx_hash, output_index
+                ));
+            }
+            let (eoa_address, eoa_value) = eoa_record(
+                &type_script,
+                &output.lock,
+                &tx_hash,
+                output_index,
+                &output,
+                data_size,
+            );
+            log::info!("Checkpoint eoa account: {:x}", eoa_address);
+            let lock_hash: H256 = packed::Script::from(output.lock.clone())
+                .calc_script_hash()
+                .unpack();
+            let value = value::LockLiveCell {
+                tx_hash,
+                output_index,
+                capacity: output.capacity.value(),
+                data_size,
+                type_script_hash: Some(
+                    packed::Script::from(type_script)
+                        .calc_script_hash()
+                        .unpack(),
+                ),
+            };
+            let key = Key::LockLiveCell {
+                lock_hash,
+                number: Some(number),
+                tx_index: Some(tx_index),
+                output_index: Some(output_index),
+            };
+            let map_value = live_cell_map(number, tx_index, &output, &data);
+            batch.put(
+                &Bytes::from(&Key::LiveCellMap(value.out_point())),
+                &serialize(&map_value).unwrap(),
+            );
+            batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
+            batch.put(
+                &Bytes::from(&Key::EoaLiveCell(eoa_address)),
+                &serialize(&eoa_value).unwrap(),
+            );
+        }
+        if checkpoint.number > 0 {
+            let parent_hash = header.inner.parent_hash;
+            let last = value::Last {
+                number: checkpoint.number - 1,
+                hash: parent_hash.clone(),
+            };
+            batch.put(
+                &Bytes::from(&Key::BlockMap(checkpoint.number - 1)),
+                &serialize(&value::BlockMap(parent_hash)).unwrap(),
+            );
+            batch.put(&Bytes::from(&Key::Last), &serialize(&last).unwrap());
+        }
+        batch.commit()
+    }
+
+    // Contracts can only be created after the type script is deployed, so
+    // only the blocks from the deployment to the checkpoint are searched
+    fn check_no_contract_before(&mut self, number: u64) -> Result<(), String> {
+        let type_dep = &self.run_config.type_dep;
+        let mut deploy_out_points = vec![type_dep.out_point()];
+        if let Ok(core::DepType::DepGroup) = core::DepType::try_from(type_dep.dep_type()) {
+            let (_, data) = self
+                .loader
+                .load_contract_live_cell(
+                    type_dep.out_point().tx_hash().unpack(),
+                    type_dep.out_point().index().unpack(),
+                )
+                .map_err(|err| format!("Can not load the type script dep group: {}", err))?;
+            let out_points = packed::OutPointVec::from_slice(data.as_ref())
+                .map_err(|err| format!("Invalid type script dep group: {}", err))?;
+            deploy_out_points.extend(out_points.into_iter());
+        }
+        let mut deploy_number = number;
+        for out_point in deploy_out_points {
+            let tx_hash: H256 = out_point.tx_hash().unpack();
+            let block_hash = self
+                .client
+                .get_transaction(tx_hash.clone())?
+                .and_then(|tx| tx.tx_status.block_hash)
+                .ok_or_else(|| format!("Type script transaction not committed: {:#x}", tx_hash))?;
+            let header = self
+                .client
+                .get_header(block_hash.clone())?
+                .ok_or_else(|| format!("Can not get header by hash: {:#x}", block_hash))?;
+            deploy_number = deploy_number.min(header.inner.number.value());
+        }
+        let type_script = Script::from(self.run_config.type_script.clone());
+        for block_number in deploy_number..number {
+            let header = self
+                .client
+                .get_header_by_number(block_number)?
+                .ok_or_else(|| format!("Block not found: {}", block_number))?;
+            let block = self
+                .client
+                .get_block(header.hash.clone())?
+                .ok_or_else(|| format!("Can not get block by hash: {:#x}", header.hash))?;
+            for tx in block.transactions {
+                let is_contract = |output: &CellOutput| {
+                    output
+                        .type_
+                        .as_ref()
+                        .map(|type_| {
+                            type_.code_hash == type_script.code_hash
+                                && type_.hash_type == type_script.hash_type
+                        })
+                        .unwrap_or(false)
+                };
+                if let Some(index) = tx.inner.outputs.iter().position(is_contract) {
+                    return Err(format!(
+                        "Contract cell {:#x}-{} is created in block {}, before the checkpoint block {}",
+                        tx.hash, index, block_number, number
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Find the fork point by walking BlockMap back against the CKB headers,
+    // then revert all blocks after it in one pass.
+    fn rollback(&mut self, number: u64) -> Result<(), IndexerError> {
+        let mut fork_number = number;
+        let mut deltas = Vec::new();
+        let fork_hash = loop {
+            let block_map: value::BlockMap =
+                db_get(&self.db, &Bytes::from(&Key::BlockMap(fork_number)))?
+                    .ok_or_else(|| IndexerError::DeepFork(fork_number))?;
+            let header_opt = self
+                .client
+                .get_header_by_number(fork_number)
+                .map_err(IndexerError::Rpc)?;
+            if header_opt.map(|header| header.hash == block_map.0) == Some(true) {
+                break block_map.0;
+            }
+            let block_delta: value::BlockDelta =
+                db_get(&self.db, &Bytes::from(&Key::BlockDelta(fork_number)))?
+                    .ok_or_else(|| IndexerError::DeepFork(fork_number))?;
+            deltas.push((fork_number, block_delta));
+            if fork_number == 0 {
+                return Err(IndexerError::DeepFork(fork_number));
+            }
+            fork_number -= 1;
+        };
+        log::info!(
+            "Rollback {} blocks to fork point, number={}, hash={:#x}",
+            deltas.len(),
+            fork_number,
+            fork_hash
+        );
+
+        let last = value::Last {
+            number: fork_number,
+            hash: fork_hash,
+        };
+        revert_blocks(&self.db, deltas, &last)?;
+        self.health
+            .lock()
+            .expect("lock indexer health")
+            .rollback_count += 1;
+        Ok(())
     }
 }
 
-fn is_eoa(
-    type_script: &Script,
-    lock_script: &Script,
-    eoa_lock_code_hash: &H256,
-    eoa_lock_hash_type: &ScriptHashType,
-) -> bool {
+/// Roll the database back to the block with the stored BlockDeltas, the
+/// indexer reprocesses the later blocks from CKB. Nothing is written when
+/// a BlockDelta is missing.
+pub fn rollback_to(db: &dyn KvStore, number: u64) -> Result<value::Last, String> {
+    let last: value::Last = db_get(db, &Bytes::from(&Key::Last))?
+        .ok_or_else(|| String::from("Database is not indexed yet"))?;
+    if number >= last.number {
+        return Err(format!(
+            "Block #{} is not before the last indexed block #{}",
+            number, last.number
+        ));
+    }
+    let mut blocks = Vec::new();
+    for block_number in (number..=last.number).rev() {
+        let block_map: value::BlockMap = db_get(db, &Bytes::from(&Key::BlockMap(block_number)))?
+            .ok_or_else(|| format!("Block #{} is not indexed", block_number))?;
+        blocks.push((block_number, block_map.0));
+    }
+    let mut deltas = Vec::new();
+    for (block_number, _) in &blocks[..blocks.len() - 1] {
+        let block_delta: value::BlockDelta =
+            db_get(db, &Bytes::from(&Key::BlockDelta(*block_number)))?
+                .ok_or_else(|| format!("BlockDelta of block #{} not found", block_number))?;
+        deltas.push((*block_number, block_delta));
+    }
+    log::info!("Rollback {} blocks to number={}", deltas.len(), number);
+    // One block per batch, an interrupted rollback leaves a consistent database
+    for ((block_number, block_delta), (parent_number, parent_hash)) in
+        deltas.into_iter().zip(blocks.into_iter().skip(1))
+    {
+        let parent = value::Last {
+            number: parent_number,
+            hash: parent_hash,
+        };
+        revert_blocks(db, vec![(block_number, block_delta)], &parent)?;
+    }
+    db_get(db, &Bytes::from(&Key::Last))?.ok_or_else(|| String::from("Last block not found"))
+}
+
+// Revert the blocks, newer blocks first, and set the last block in one batch
+fn revert_blocks(
+    db: &dyn KvStore,
+    deltas: Vec<(u64, value::BlockDelta)>,
+    last: &value::Last,
+) -> Result<(), String> {
+    let mut batch = Batch::new(db);
+    // The batch is not readable, contract metas changed by the reverted
+    // blocks are kept here and written at the end (None for removed ones)
+    let mut metas: HashMap<ContractAddress, Option<value::ContractMeta>> = HashMap::default();
+    // The storage values restored by the reverted blocks
+    let mut restored: HashMap<ContractAddress, HashMap<H256, H256>> = HashMap::default();
+    for (number, block_delta) in deltas {
+        revert_block(
+            db,
+            &mut batch,
+            &mut metas,
+            &mut restored,
+            number,
+            block_delta,
+        )?;
+    }
+    // Only the nodes of the latest roots are persisted, rebuild the trees
+    for (address, meta_opt) in &metas {
+        if meta_opt.is_none() {
+            SmtStore::rebuild_nodes(&mut batch, address, &HashMap::default())?;
+        } else if let Some(values) = restored.remove(address) {
+            let mut storage = load_storage(db, address)?;
+            for (key, value) in values {
+                if value == H256::default() {
+                    storage.remove(&key);
+                } else {
+                    storage.insert(key, value);
+                }
+            }
+            let root = SmtStore::rebuild_nodes(&mut batch, address, &storage)?;
+            log::info!("Rebuild storage tree of {:x}, root: {:#x}", address.0, root);
+        }
+    }
+    for (address, meta_opt) in metas {
+        let key_bytes = Bytes::from(&Key::ContractMeta(address));
+        match meta_opt {
+            Some(meta) => batch.put(&key_bytes, &serialize(&meta).unwrap()),
+            None => batch.delete(&key_bytes),
+        }
+    }
+    batch.put(&Bytes::from(&Key::Last), &serialize(last).unwrap());
+    batch.commit()
+}
+
+fn revert_block(
+    db: &dyn KvStore,
+    batch: &mut Batch,
+    metas: &mut HashMap<ContractAddress, Option<value::ContractMeta>>,
+    restored: &mut HashMap<ContractAddress, HashMap<H256, H256>>,
+    number: u64,
+    block_delta: value::BlockDelta,
+) -> Result<(), String> {
+    log::info!("Rollback block, number={}", number);
+    // Before the contracts, a contract may be created and destructed in
+    // the same block
+    for contract_address in block_delta.destructed_contracts {
+        let meta = load_meta(db, metas, &contract_address)?
+            .as_mut()
+            .ok_or_else(|| format!("no such contract: {:x}", contract_address.0))?;
+        if !meta.destructed {
+            return Err(format!("contract not destructed: {:x}", contract_address.0));
+        }
+        meta.destructed = false;
+    }
+    for (address, is_create) in block_delta.contracts {
+        let change_start_key = Key::ContractChange {
+            address: address.clone(),
+            number: Some(number),
+            tx_index: None,
+            output_index: None,
+        };
+        let change_end_key = Key::ContractChange {
+            address: address.clone(),
+            number: Some(number + 1),
+            tx_index: None,
+            output_index: None,
+        };
+        let logs_start_key = Key::ContractLogs {
+            address: address.clone(),
+            number: Some(number),
+            tx_index: None,
+            output_index: None,
+        };
+        let logs_end_key = Key::ContractLogs {
+            address: address.clone(),
+            number: Some(number + 1),
+            tx_index: None,
+            output_index: None,
+        };
+        // Restore the contract state written in this block, newer changes first
+        let start_key_bytes = Bytes::from(&change_start_key);
+        let end_key_bytes = Bytes::from(&change_end_key);
+        let mut changes: Vec<value::ContractChange> = Vec::new();
+        let mut iter = db_iter(db, KeyType::ContractChange);
+        iter.seek(&start_key_bytes);
+        while iter.valid() {
+            match (iter.key(), iter.value()) {
+                (Some(key_bytes), Some(value_bytes)) if key_bytes < end_key_bytes.as_ref() => {
+                    changes.push(deserialize(value_bytes).map_err(|err| err.to_string())?);
+                }
+                _ => break,
+            }
+            iter.next();
+        }
+        let restored_values = restored.entry(address.clone()).or_default();
+        for change in changes.into_iter().rev() {
+            for (key, old_value, _new_value) in change.storage_diff {
+                restored_values.insert(key.clone(), old_value.clone());
+                let state_key = Bytes::from(&Key::ContractState {
+                    address: address.clone(),
+                    storage_key: Some(key),
+                });
+                if old_value == H256::default() {
+                    batch.delete(&state_key);
+                } else {
+                    batch.put(&state_key, &serialize(&old_value).unwrap());
+                }
+            }
+        }
+        batch.delete_range(&start_key_bytes, &end_key_bytes);
+        batch.delete_range(&Bytes::from(&logs_start_key), &Bytes::from(&logs_end_key));
+        if is_create {
+            metas.insert(address, None);
+        } else {
+            // The balance of the last change before this block
+            let prefix_key_bytes = Bytes::from(&Key::ContractChange {
+                address: address.clone(),
+                number: None,
+                tx_index: None,
+                output_index: None,
+            });
+            iter.seek_for_prev(&start_key_bytes);
+            let prev_balance = match (iter.key(), iter.value()) {
+                (Some(key_bytes), Some(value_bytes))
+                    if iter.valid() && key_bytes.starts_with(&prefix_key_bytes) =>
+                {
+                    let change: value::ContractChange =
+                        deserialize(value_bytes).map_err(|err| err.to_string())?;
+                    change.balance
+                }
+                _ => return Err(format!("no previous change of contract: {:x}", address.0)),
+            };
+            if let Some(meta) = load_meta(db, metas, &address)?.as_mut() {
+                meta.balance = prev_balance;
+            }
+        }
+    }
+    for (lock_hash, tx_index, output_index, value) in block_delta.added_cells {
+        batch.delete(&Bytes::from(&Key::LockLiveCell {
+            lock_hash,
+            number: Some(number),
+            tx_index: Some(tx_index),
+            output_index: Some(output_index),
+        }));
+        batch.delete(&Bytes::from(&Key::LiveCellMap(value.out_point())));
+    }
+    for (lock_hash, old_number, tx_index, output_index, value, map_value) in
+        block_delta.removed_cells
+    {
+        let key = Key::LockLiveCell {
+            lock_hash,
+            number: Some(old_number),
+            tx_index: Some(tx_index),
+            output_index: Some(output_index),
+        };
+        batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
+        let map_key = Key::LiveCellMap(value.out_point());
+        batch.put(&Bytes::from(&map_key), &serialize(&map_value).unwrap());
+    }
+    for eoa_address in block_delta.eoa_added_cells {
+        batch.delete(&Bytes::from(&Key::EoaLiveCell(eoa_address)));
+    }
+    for (eoa_address, value) in block_delta.eoa_removed_cells {
+        let key = Key::EoaLiveCell(eoa_address);
+        batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
+    }
+    // The contracts tainted in this block are trusted again
+    let tainted_key_bytes = Bytes::from(&Key::TaintedContracts);
+    if let Some(mut tainted) = db_get::<_, value::TaintedContracts>(db, &tainted_key_bytes)? {
+        let count = tainted.0.len();
+        tainted.0.retain(|contract| contract.number < number);
+        if tainted.0.len() != count {
+            batch.put(&tainted_key_bytes, &serialize(&tainted).unwrap());
+        }
+    }
+    batch.delete(&Bytes::from(&Key::BlockMap(number)));
+    batch.delete(&Bytes::from(&Key::BlockDelta(number)));
+    Ok(())
+}
+
+// The latest storage of a contract
+fn load_storage(
+    db: &dyn KvStore,
+    address: &ContractAddress,
+) -> Result<HashMap<H256, H256>, String> {
+    let prefix_bytes = Bytes::from(&Key::ContractState {
+        address: address.clone(),
+        storage_key: None,
+    });
+    let mut storage = HashMap::default();
+    let mut iter = db_iter(db, KeyType::ContractState);
+    iter.seek(&prefix_bytes);
+    while iter.valid() {
+        match (iter.key(), iter.value()) {
+            (Some(key_bytes), Some(value_bytes)) if key_bytes.starts_with(&prefix_bytes) => {
+                if let Key::ContractState {
+                    storage_key: Some(storage_key),
+                    ..
+                } = Key::try_from(key_bytes)?
+                {
+                    let value: H256 = deserialize(value_bytes).map_err(|err| err.to_string())?;
+                    storage.insert(storage_key, value);
+                }
+            }
+            _ => break,
+        }
+        iter.next();
+    }
+    Ok(storage)
+}
+
+fn load_meta<'a>(
+    db: &dyn KvStore,
+    metas: &'a mut HashMap<ContractAddress, Option<value::ContractMeta>>,
+    address: &ContractAddress,
+) -> Result<&'a mut Option<value::ContractMeta>, String> {
+    if !metas.contains_key(address) {
+        let meta = db_get(db, &Bytes::from(&Key::ContractMeta(address.clone())))?;
+        metas.insert(address.clone(), meta);
+    }
+    Ok(metas.get_mut(address).unwrap())
+}
+
+struct AliveGuard(Arc<Mutex<IndexerHealth>>);
+
+impl AliveGuard {
+    fn new(health: Arc<Mutex<IndexerHealth>>) -> AliveGuard {
+        health.lock().expect("lock indexer health").alive = true;
+        AliveGuard(health)
+    }
+}
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        let mut health = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        health.alive = false;
+    }
+}
+
+pub fn live_cell_map(
+    number: u64,
+    tx_index: u32,
+    output: &CellOutput,
+    data: &Bytes,
+) -> value::LiveCellMap {
+    let small_data = if data.len() <= OUTPUT_DATA_LEN {
+        Some(data.clone())
+    } else {
+        None
+    };
+    value::LiveCellMap {
+        number,
+        tx_index,
+        output: packed::CellOutput::from(output.clone()).as_bytes(),
+        data_hash: H256::from_slice(&blake2b_256(data.as_ref())[..]).unwrap(),
+        data_size: data.len() as u32,
+        data: small_data,
+    }
+}
+
+// A type id cell locked by one of the EoA locks, with the args the validator accepts
+pub fn is_eoa(type_script: &Script, lock_script: &Script, run_config: &RunConfig) -> bool {
     type_script.code_hash == TYPE_ID_CODE_HASH
         && type_script.hash_type == ScriptHashType::Type
-        && &lock_script.code_hash == eoa_lock_code_hash
-        && &lock_script.hash_type == eoa_lock_hash_type
+        && type_script.args.len() == 32
+        && run_config
+            .eoa_lock(&packed::Script::from(lock_script.clone()))
+            .map(|eoa_lock| eoa_lock.kind.valid_lock_args(lock_script.args.as_bytes()))
+            .unwrap_or(false)
 }
 
-fn eoa_record(
+pub fn eoa_record(
     type_script: &Script,
     lock_script: &Script,
     tx_hash: &H256,
@@ -682,6 +1375,8 @@ pub struct ContractExtractor {
     script_groups: HashMap<ContractAddress, ContractInfo>,
     // EoA address => (input_balance, output_balance)
     eoa_accounts: HashMap<H160, (u64, u64)>,
+    // The reason of the last failed syscall
+    error_message: Option<String>,
 }
 
 #[derive(Default)]
@@ -702,24 +1397,28 @@ pub struct ContractInfo {
     output_balance: u64,
     output_capacity: u64,
     // Updated by ckb-vm
-    tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, DefaultStore<SmtH256>>,
+    tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore>,
+    // The storage root of the input contract cell
+    input_root: SmtH256,
+    // Storage keys written by all programs
+    written_keys: HashSet<SmtH256>,
 }
 
 impl ContractInfo {
     // aka current contract address
-    pub fn selfdestruct(&self) -> Option<ContractAddress> {
-        assert_eq!(
-            self.output.is_none(),
-            self.programs[self.programs.len() - 1]
-                .selfdestruct
-                .is_some(),
-        );
-        let last_program = &self.programs[self.programs.len() - 1];
-        last_program
+    pub fn selfdestruct(&self) -> Result<Option<ContractAddress>, String> {
+        let last_program = self
+            .programs
+            .last()
+            .ok_or_else(|| String::from("no program of the contract"))?;
+        if self.output.is_none() != last_program.selfdestruct.is_some() {
+            return Err(String::from("contract output does not match selfdestruct"));
+        }
+        Ok(last_program
             .selfdestruct
             .as_ref()
             .map(|_| last_program.program.destination.clone())
-            .map(ContractAddress)
+            .map(ContractAddress))
     }
     pub fn is_create(&self) -> bool {
         self.input.is_none()
@@ -738,43 +1437,52 @@ impl ContractInfo {
             .unwrap_or_default()
     }
 
-    pub fn current_call_index(&self) -> usize {
-        self.call_indices[self.program_index]
-    }
-    pub fn current_call_index_mut(&mut self) -> &mut usize {
-        &mut self.call_indices[self.program_index]
+    pub fn current_witness(&self) -> Result<&WitnessData, String> {
+        self.programs
+            .get(self.program_index)
+            .ok_or_else(|| format!("program {} not found", self.program_index))
     }
-    pub fn current_witness(&self) -> &WitnessData {
-        &self.programs[self.program_index]
+    // The call record of the current program, moves to the next call
+    pub fn next_call_record(&mut self) -> Result<CallRecord, String> {
+        let call_index = self.call_indices[self.program_index];
+        let call_record = self
+            .current_witness()?
+            .calls
+            .get(call_index)
+            .cloned()
+            .ok_or_else(|| {
+                format!(
+                    "call {} of program {} not found",
+                    call_index, self.program_index
+                )
+            })?;
+        self.call_indices[self.program_index] += 1;
+        Ok(call_record)
     }
-    pub fn current_program_data(&self) -> Bytes {
-        let mut witness = self.current_witness().clone();
+    pub fn current_program_data(&self) -> Result<Bytes, String> {
+        let mut witness = self.current_witness()?.clone();
         if self.program_index > 0 {
             // Put optmized code field back to program data
             witness.program.code = self.code();
         }
-        witness.program_data()
+        Ok(witness.program_data())
     }
 
     pub fn get_meta(&self, address: &ContractAddress, tx_hash: &H256) -> Option<ContractMeta> {
-        if self.is_create() {
-            let output_index = self.output.as_ref().map(|(index, _)| *index).unwrap() as u32;
-            let balance: u64 = self
-                .output
-                .as_ref()
-                .map(|(_, output)| contract_account_balance(output))
-                .unwrap();
-            log::debug!("get_meta({:x}) balance = {}", address.0, balance);
-            Some(ContractMeta {
-                address: address.clone(),
-                code: self.code(),
-                tx_hash: tx_hash.clone(),
-                output_index,
-                balance,
-                destructed: false,
-            })
-        } else {
-            None
+        match self.output.as_ref() {
+            Some((output_index, output)) if self.is_create() => {
+                let balance: u64 = contract_account_balance(output);
+                log::debug!("get_meta({:x}) balance = {}", address.0, balance);
+                Some(ContractMeta {
+                    address: address.clone(),
+                    code: self.code(),
+                    tx_hash: tx_hash.clone(),
+                    output_index: *output_index as u32,
+                    balance,
+                    destructed: false,
+                })
+            }
+            _ => None,
         }
     }
 
@@ -784,33 +1492,52 @@ impl ContractInfo {
         number: u64,
         tx_index: u32,
         tx_hash: &H256,
-    ) -> Option<ContractChange> {
+    ) -> Result<Option<ContractChange>, String> {
         if let Some((output_index, output)) = self.output.as_ref() {
-            let new_storage: HashMap<H256, H256> = self
-                .tree
-                .store()
-                .leaves_map()
-                .values()
-                .map(|leaf| (smth256_to_h256(&leaf.key), smth256_to_h256(&leaf.value)))
-                .collect();
+            let input_tree = SparseMerkleTree::<CkbBlake2bHasher, SmtH256, SmtStore>::new(
+                self.input_root,
+                self.tree.store().clone(),
+            );
+            let mut storage_diff = Vec::new();
+            for key in &self.written_keys {
+                let old_value = if self.input.is_some() {
+                    input_tree
+                        .get(key)
+                        .map_err(|err| format!("load input storage: {}", err))?
+                } else {
+                    SmtH256::default()
+                };
+                let new_value = self
+                    .tree
+                    .get(key)
+                    .map_err(|err| format!("load output storage: {}", err))?;
+                if old_value != new_value {
+                    storage_diff.push((
+                        smth256_to_h256(key),
+                        smth256_to_h256(&old_value),
+                        smth256_to_h256(&new_value),
+                    ));
+                }
+            }
+            storage_diff.sort();
             let tx_origin = self.programs[0].program.tx_origin.clone();
             let capacity: u64 = output.capacity().unpack();
             let balance: u64 = contract_account_balance(&output);
-            Some(ContractChange {
+            Ok(Some(ContractChange {
                 tx_origin,
                 address: address.clone(),
                 number,
                 tx_index,
                 output_index: *output_index as u32,
                 tx_hash: tx_hash.clone(),
-                new_storage,
+                storage_diff,
                 logs: self.logs.clone(),
                 capacity,
                 balance,
                 is_create: self.is_create(),
-            })
+            }))
         } else {
-            None
+            Ok(None)
         }
     }
 }
@@ -833,10 +1560,13 @@ impl ContractExtractor {
             } else if let Some((output_index, _)) = info.output {
                 output_index
             } else {
-                panic!("Input/Output both empty");
+                return Err(format!("contract {:x} input/output both empty", addr.0));
             };
             let mut start = 0;
-            let witness_args = packed::WitnessArgs::from_slice(witnesses[witness_index].as_bytes())
+            let witness = witnesses
+                .get(witness_index)
+                .ok_or_else(|| format!("witnesses[{}] not found", witness_index))?;
+            let witness_args = packed::WitnessArgs::from_slice(witness.as_bytes())
                 .map_err(|err| err.to_string())?;
             let raw_witness = witness_args
                 .input_type()
@@ -855,11 +1585,11 @@ impl ContractExtractor {
                     tx_origin = witness_data.program.tx_origin.clone();
                 }
                 if tx_origin != witness_data.program.tx_origin {
-                    panic!("multiple tx_origin in one transaction");
+                    return Err(String::from("multiple tx_origin in one transaction"));
                 }
                 if !witness_data.signature.iter().all(|byte| *byte == 0) {
                     if entrance_contract.is_some() {
-                        panic!("Multiple entrance contract");
+                        return Err(String::from("multiple entrance contract"));
                     }
                     entrance_contract = Some(addr.clone());
                 }
@@ -867,10 +1597,13 @@ impl ContractExtractor {
                 info.call_indices.push(0);
                 start += offset;
             }
+            if info.programs.is_empty() {
+                return Err(format!("no program of contract {:x}", addr.0));
+            }
         }
 
         if entrance_contract.is_none() && !script_groups.is_empty() {
-            panic!("Invalid transaction");
+            return Err(String::from("no entrance contract in transaction"));
         }
 
         Ok(entrance_contract.map(|entrance_contract| {
@@ -886,13 +1619,33 @@ impl ContractExtractor {
                 current_contract,
                 script_groups,
                 eoa_accounts,
+                error_message: None,
             }
         }))
     }
 
+    // Run the entrance programs in order, the other programs of the entrance
+    // contract are CALLCODE/DELEGATECALL programs run by them
     pub fn run(&mut self) -> Result<(), Box<dyn StdError>> {
         let entrance_contract = self.entrance_contract.clone();
-        self.run_with(&entrance_contract, false).map(|_| ())
+        loop {
+            let info = self
+                .script_groups
+                .get(&entrance_contract)
+                .ok_or_else(|| format!("No such contract to run: {:x}", entrance_contract.0))?;
+            let witness = match info.programs.get(info.program_index) {
+                Some(witness) => witness,
+                None => return Ok(()),
+            };
+            if witness.signature.iter().all(|byte| *byte == 0) {
+                return Err(format!(
+                    "program {} of entrance contract is not signed",
+                    info.program_index
+                )
+                .into());
+            }
+            self.run_with(&entrance_contract, false)?;
+        }
     }
 
     pub fn run_with(
@@ -914,29 +1667,35 @@ impl ContractExtractor {
             } else {
                 info.program_index
             };
-            let program = info.programs[program_index].program.clone();
+            let program = info
+                .programs
+                .get(program_index)
+                .ok_or_else(|| format!("program {} of {:x} not found", program_index, contract.0))?
+                .program
+                .clone();
             let program_data = if program.kind.is_special_call() {
                 let dest_info = self
                     .script_groups
                     .get_mut(&ContractAddress(program.destination.clone()))
                     .ok_or_else(|| format!("No such contract to run: {:x}", program.destination))?;
-                let program_data = dest_info.current_program_data();
+                let program_data = dest_info.current_program_data()?;
                 dest_info.program_index += 1;
                 program_data
             } else {
-                info.current_program_data()
+                info.current_program_data()?
             };
             (tree_clone, saved_program_index, program, program_data)
         };
 
-        self.handle_transfer(&program.sender, &contract.0, program.value);
+        self.handle_transfer(&program.sender, &contract.0, program.value)?;
 
         let config = Config::from(&self.run_config);
         let _result = match run_with_context(&config, &tree_clone, &program_data, self) {
             Ok(result) => result,
             Err(err) => {
                 log::warn!("Error: {:?}", err);
-                return Err(err);
+                let error_message = self.error_message.take().unwrap_or_else(|| err.to_string());
+                return Err(error_message.into());
             }
         };
         let info = self
@@ -949,17 +1708,31 @@ impl ContractExtractor {
         } else {
             saved_program_index
         };
-        let return_data = info.programs[program_index].return_data.clone();
+        let return_data = info
+            .programs
+            .get(program_index)
+            .ok_or_else(|| format!("program {} of {:x} not found", program_index, contract.0))?
+            .return_data
+            .clone();
         if !program.kind.is_special_call() {
             let run_result = std::mem::take(&mut info.run_result);
-            run_result.commit(&mut info.tree).unwrap();
+            info.written_keys
+                .extend(run_result.write_values.keys().cloned());
+            run_result
+                .commit(&mut info.tree)
+                .map_err(|err| format!("commit storage: {:?}", err))?;
             info.program_index += info.special_call_count + 1;
             info.special_call_count = 0;
         }
         Ok(return_data)
     }
 
-    fn handle_transfer(&mut self, sender: &H160, destination: &H160, value: u64) {
+    fn handle_transfer(
+        &mut self,
+        sender: &H160,
+        destination: &H160,
+        value: u64,
+    ) -> Result<(), String> {
         // handle trasnfer
         log::debug!(
             ">> transfer {} wei from {:x} to {:x}",
@@ -968,21 +1741,31 @@ impl ContractExtractor {
             destination
         );
         if &self.tx_origin.0 == sender {
-            self.eoa_sub_balance(sender, value);
+            self.eoa_sub_balance(sender, value)?;
         } else {
-            self.contract_sub_balance(&ContractAddress(sender.clone()), value);
+            self.contract_sub_balance(&ContractAddress(sender.clone()), value)?;
         }
         if self.eoa_accounts.contains_key(destination) {
-            self.eoa_add_balance(destination, value);
+            self.eoa_add_balance(destination, value)
         } else {
-            self.contract_add_balance(&ContractAddress(destination.clone()), value);
+            self.contract_add_balance(&ContractAddress(destination.clone()), value)
         }
     }
 
-    pub fn get_contract_changes(&self, number: u64) -> Vec<ContractChange> {
+    pub fn get_contract_changes(&self, number: u64) -> Result<Vec<ContractChange>, String> {
+        let mut changes = Vec::new();
+        for (addr, info) in &self.script_groups {
+            if let Some(change) = info.get_change(addr, number, self.tx_index, &self.tx_hash)? {
+                changes.push(change);
+            }
+        }
+        Ok(changes)
+    }
+    pub fn get_contract_trees(&self) -> Vec<(ContractAddress, SmtStore)> {
         self.script_groups
             .iter()
-            .filter_map(|(addr, info)| info.get_change(addr, number, self.tx_index, &self.tx_hash))
+            .filter(|(_, info)| info.output.is_some())
+            .map(|(addr, info)| (addr.clone(), info.tree.store().clone()))
             .collect()
     }
     pub fn get_created_contracts(&self) -> Vec<ContractMeta> {
@@ -991,55 +1774,88 @@ impl ContractExtractor {
             .filter_map(|(addr, info)| info.get_meta(addr, &self.tx_hash))
             .collect()
     }
-    pub fn get_destructed_contracts(&self) -> Vec<ContractAddress> {
-        self.script_groups
-            .values()
-            .filter_map(|info| info.selfdestruct())
-            .collect()
+    pub fn get_destructed_contracts(&self) -> Result<Vec<ContractAddress>, String> {
+        let mut contracts = Vec::new();
+        for info in self.script_groups.values() {
+            if let Some(address) = info.selfdestruct()? {
+                contracts.push(address);
+            }
+        }
+        Ok(contracts)
+    }
+
+    // Keep the reason of a failed syscall, ckb-vm only returns VMError
+    fn vm_error(&mut self, message: String) -> VMError {
+        self.error_message = Some(message);
+        VMError::Unexpected
     }
 
-    pub fn eoa_sub_balance(&mut self, address: &H160, value: u64) {
+    fn current_info_mut(&mut self) -> Result<&mut ContractInfo, VMError> {
+        let current_contract = &self.current_contract;
+        let error_message = &mut self.error_message;
+        self.script_groups.get_mut(current_contract).ok_or_else(|| {
+            *error_message = Some(format!("contract not found: {:x}", current_contract.0));
+            VMError::Unexpected
+        })
+    }
+
+    pub fn eoa_sub_balance(&mut self, address: &H160, value: u64) -> Result<(), String> {
         log::debug!("subbing {} value from eoa {:x}", value, address);
         let input_balance = &mut self
             .eoa_accounts
             .get_mut(address)
-            .expect("eoa account not exists")
+            .ok_or_else(|| format!("eoa account not exists: {:x}", address))?
             .0;
         if *input_balance < value {
-            panic!("eoa sub balance faield: {} < {}", input_balance, value);
+            return Err(format!(
+                "eoa sub balance failed: {} < {}",
+                input_balance, value
+            ));
         }
         *input_balance -= value;
+        Ok(())
     }
-    pub fn eoa_add_balance(&mut self, address: &H160, value: u64) {
+    pub fn eoa_add_balance(&mut self, address: &H160, value: u64) -> Result<(), String> {
         log::debug!("adding {} value to eoa {:x}", value, address);
         let input_balance = &mut self
             .eoa_accounts
             .get_mut(address)
-            .expect("eoa account not exists")
+            .ok_or_else(|| format!("eoa account not exists: {:x}", address))?
             .0;
         *input_balance += value;
+        Ok(())
     }
-    pub fn contract_sub_balance(&mut self, address: &ContractAddress, value: u64) {
+    pub fn contract_sub_balance(
+        &mut self,
+        address: &ContractAddress,
+        value: u64,
+    ) -> Result<(), String> {
         log::debug!("subbing {} value from contract {:x}", value, address.0);
         let info = self
             .script_groups
             .get_mut(address)
-            .expect("contract account not exists");
+            .ok_or_else(|| format!("contract account not exists: {:x}", address.0))?;
         if info.input_balance < value {
-            panic!(
-                "contract sub balance faield: {} < {}",
+            return Err(format!(
+                "contract sub balance failed: {} < {}",
                 info.input_balance, value
-            );
+            ));
         }
         info.input_balance -= value;
+        Ok(())
     }
-    pub fn contract_add_balance(&mut self, address: &ContractAddress, value: u64) {
+    pub fn contract_add_balance(
+        &mut self,
+        address: &ContractAddress,
+        value: u64,
+    ) -> Result<(), String> {
         log::debug!("adding {} value to contract {:x}", value, address.0);
         let info = self
             .script_groups
             .get_mut(address)
-            .expect("contract account not exists");
+            .ok_or_else(|| format!("contract account not exists: {:x}", address.0))?;
         info.input_balance += value;
+        Ok(())
     }
 }
 
@@ -1075,7 +1891,7 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 let value_address = machine.registers()[A1].to_u64();
                 let value = vm_load_h256(machine, value_address)?;
                 log::debug!("[set_storage] key={:x}, value={:x}", key, value);
-                let info = self.script_groups.get_mut(&self.current_contract).unwrap();
+                let info = self.current_info_mut()?;
                 info.run_result
                     .write_values
                     .insert(h256_to_smth256(&key), h256_to_smth256(&value));
@@ -1090,7 +1906,7 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 log::debug!("[get_storage] key {:x}", key);
 
                 let smth256_key = h256_to_smth256(&key);
-                let info = self.script_groups.get_mut(&self.current_contract).unwrap();
+                let info = self.current_info_mut()?;
                 let value = match info.run_result.write_values.get(&smth256_key) {
                     Some(value) => *value,
                     None => {
@@ -1116,11 +1932,8 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 let data_address = machine.registers()[A0].to_u64();
                 let data_length = machine.registers()[A1].to_u32();
                 let data = vm_load_data(machine, data_address, data_length)?;
-                self.script_groups
-                    .get_mut(&self.current_contract)
-                    .unwrap()
-                    .logs
-                    .push(parse_log(&data[..]).unwrap());
+                let log = parse_log(&data[..]).map_err(|err| self.vm_error(err))?;
+                self.current_info_mut()?.logs.push(log);
                 Ok(true)
             }
             // SELFDESTRUCT
@@ -1128,21 +1941,23 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 let data_address = machine.registers()[A0].to_u64();
                 let data_length = machine.registers()[A1].to_u32();
                 let data = vm_load_data(machine, data_address, data_length)?;
-                let address = H160::from_slice(&data).expect("parse selfdestruct H160");
-                let input_capacity = self
-                    .script_groups
-                    .get(&self.current_contract)
-                    .expect("selfdestruct account info")
-                    .input_capacity;
-                if self.eoa_accounts.contains_key(&address) {
-                    self.eoa_add_balance(&address, input_capacity);
+                let address = H160::from_slice(&data).map_err(|_| {
+                    self.vm_error(format!(
+                        "invalid selfdestruct address: {}",
+                        hex::encode(&data)
+                    ))
+                })?;
+                let input_capacity = self.current_info_mut()?.input_capacity;
+                let result = if self.eoa_accounts.contains_key(&address) {
+                    self.eoa_add_balance(&address, input_capacity)
                 } else {
-                    self.contract_add_balance(&ContractAddress(address), input_capacity);
+                    self.contract_add_balance(&ContractAddress(address), input_capacity)
+                };
+                if let Err(err) = result {
+                    self.error_message = Some(err);
+                    return Err(VMError::Unexpected);
                 }
-                let info = self
-                    .script_groups
-                    .get_mut(&self.current_contract)
-                    .expect("selfdestruct account info");
+                let info = self.current_info_mut()?;
                 info.selfdestruct = Some(data.into());
                 info.input_balance = 0;
                 info.input_capacity = 0;
@@ -1169,29 +1984,31 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 msg_data_address += input_size as u64;
                 let _value: U256 = vm_load_u256(machine, msg_data_address)?;
 
-                let kind = CallKind::try_from(kind_value).unwrap();
+                let kind = CallKind::try_from(kind_value).map_err(|err| self.vm_error(err))?;
 
-                let info_mut = self
-                    .script_groups
-                    .get_mut(&self.current_contract)
-                    .expect("can not find current contract");
+                let current_contract = self.current_contract.clone();
+                let info_mut = self.current_info_mut()?;
                 log::debug!(
                     "address: {:x}, program_index: {}",
-                    self.current_contract.0,
+                    current_contract.0,
                     info_mut.program_index
                 );
-                let call_record =
-                    info_mut.current_witness().calls[info_mut.current_call_index()].clone();
-                *info_mut.current_call_index_mut() += 1;
-                if kind.is_call() {
-                    assert_eq!(
-                        call_record.destination, msg_destination,
-                        "destination address not match"
-                    );
+                let call_record_result = info_mut.next_call_record();
+                let call_record = call_record_result.map_err(|err| self.vm_error(err))?;
+                if kind.is_call() && call_record.destination != msg_destination {
+                    return Err(self.vm_error(format!(
+                        "destination address not match: {:x} != {:x}",
+                        call_record.destination, msg_destination
+                    )));
                 };
 
                 let (return_data, create_address) = if call_record.transfer_only {
-                    self.handle_transfer(&sender, &call_record.destination, call_record.value);
+                    if let Err(err) =
+                        self.handle_transfer(&sender, &call_record.destination, call_record.value)
+                    {
+                        self.error_message = Some(err);
+                        return Err(VMError::Unexpected);
+                    }
                     (Default::default(), Default::default())
                 } else {
                     let info_address = if kind.is_special_call() {
@@ -1202,7 +2019,10 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                     let saved_current_contract = self.current_contract.clone();
                     let return_data = self
                         .run_with(&info_address, kind.is_special_call())
-                        .map_err(|_err| VMError::Unexpected)?;
+                        .map_err(|err| {
+                            self.error_message = Some(err.to_string());
+                            VMError::Unexpected
+                        })?;
                     let create_address = if kind.is_create() {
                         ContractAddress(call_record.destination)
                     } else {
@@ -1229,12 +2049,10 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 let address: H160 = vm_load_h160(machine, address_ptr)?;
                 let code_size_ptr = machine.registers()[A1].to_u64();
                 log::debug!("[get_code_size]: address={:x}", address);
-                let code_size = self
-                    .script_groups
-                    .get(&ContractAddress(address))
-                    .expect("Can not get contract info")
-                    .code()
-                    .len() as u32;
+                let code_size = match self.script_groups.get(&ContractAddress(address.clone())) {
+                    Some(info) => info.code().len() as u32,
+                    None => return Err(self.vm_error(format!("contract not found: {:x}", address))),
+                };
                 machine
                     .memory_mut()
                     .store_bytes(code_size_ptr, &code_size.to_le_bytes()[..])?;
@@ -1251,11 +2069,11 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
 
                 let address: H160 = vm_load_h160(machine, address_ptr)?;
                 log::debug!("[copy_code]: address={:x}", address);
-                let code = self
-                    .script_groups
-                    .get(&ContractAddress(address))
-                    .expect("Can not get contract info")
-                    .code();
+                let code = match self.script_groups.get(&ContractAddress(address.clone())) {
+                    Some(info) => info.code(),
+                    None => return Err(self.vm_error(format!("contract not found: {:x}", address))),
+                };
+                let code_offset = std::cmp::min(code_offset, code.len());
                 let done_size = std::cmp::min(code.len() - code_offset, buffer_size);
                 let code_slice = &code.as_ref()[code_offset..code_offset + done_size];
 
@@ -1294,13 +2112,14 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 // TODO: config chain ID
                 let chain_id = U256::one();
                 // FIXME: only recognize secp_blake160 for now
-                let cellbase_lock = self
+                let cellbase_lock = match self
                     .tip_block
                     .transaction(0)
-                    .expect("Cellbase must exists")
-                    .output(0)
-                    .expect("No output in cellbase")
-                    .lock();
+                    .and_then(|cellbase| cellbase.output(0))
+                {
+                    Some(output) => output.lock(),
+                    None => return Err(self.vm_error(String::from("No output in cellbase"))),
+                };
                 let secp_blake160_code_hash: packed::Byte32 =
                     h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8")
                         .pack();
@@ -1333,11 +2152,10 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 let balance_u64: u64 = if let Some(info) = self.script_groups.get(&info_address) {
                     // get balance from current related contract account
                     info.input_balance
+                } else if let Some((balance, _)) = self.eoa_accounts.get(&address) {
+                    *balance
                 } else {
-                    self.eoa_accounts
-                        .get(&address)
-                        .expect("account not exists")
-                        .0
+                    return Err(self.vm_error(format!("account not exists: {:x}", address)));
                 };
                 log::debug!(
                     "get_balance: address={:x}, balance={}",
@@ -0,0 +1,299 @@
+use rocksdb::{DBRawIterator, WriteBatch, DB};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+use super::{column_family, key_column_family, DbSnapshot, KeyType};
+
+/// One write of a batch
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    /// Delete [from, to), both keys are of the same KeyType
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+/// The key-value operations the storage is built on, keys start with their
+/// KeyType byte.
+pub trait KvStore: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    /// Iterate keys of one type, seeks must stay in the prefix of the seek key
+    fn iter(&self, key_type: KeyType) -> Box<dyn KvIterator + '_>;
+
+    /// Apply the writes atomically
+    fn write(&self, ops: Vec<BatchOp>) -> Result<(), String>;
+
+    /// A read-only view of the current state
+    fn snapshot(self: Arc<Self>) -> Arc<dyn KvStore>;
+}
+
+/// A cursor over the keys of one type, works like the RocksDB raw iterator
+pub trait KvIterator {
+    fn seek(&mut self, key: &[u8]);
+    fn seek_for_prev(&mut self, key: &[u8]);
+    fn seek_to_first(&mut self);
+    fn next(&mut self);
+    fn prev(&mut self);
+    fn valid(&self) -> bool;
+    fn key(&self) -> Option<&[u8]>;
+    fn value(&self) -> Option<&[u8]>;
+}
+
+impl KvStore for DB {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.get_cf(key_column_family(self, key), key)
+            .map_err(|err| err.to_string())
+    }
+
+    fn iter(&self, key_type: KeyType) -> Box<dyn KvIterator + '_> {
+        Box::new(self.raw_iterator_cf(column_family(self, key_type)))
+    }
+
+    fn write(&self, ops: Vec<BatchOp>) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    batch.put_cf(key_column_family(self, &key), &key, value);
+                }
+                BatchOp::Delete(key) => {
+                    batch.delete_cf(key_column_family(self, &key), &key);
+                }
+                BatchOp::DeleteRange(from, to) => {
+                    batch.delete_range_cf(key_column_family(self, &from), &from, to);
+                }
+            }
+        }
+        DB::write(self, batch).map_err(|err| err.to_string())
+    }
+
+    fn snapshot(self: Arc<Self>) -> Arc<dyn KvStore> {
+        Arc::new(DbSnapshot::new(self))
+    }
+}
+
+impl<'a> KvIterator for DBRawIterator<'a> {
+    fn seek(&mut self, key: &[u8]) {
+        DBRawIterator::seek(self, key)
+    }
+    fn seek_for_prev(&mut self, key: &[u8]) {
+        DBRawIterator::seek_for_prev(self, key)
+    }
+    fn seek_to_first(&mut self) {
+        DBRawIterator::seek_to_first(self)
+    }
+    fn next(&mut self) {
+        DBRawIterator::next(self)
+    }
+    fn prev(&mut self) {
+        DBRawIterator::prev(self)
+    }
+    fn valid(&self) -> bool {
+        DBRawIterator::valid(self)
+    }
+    fn key(&self) -> Option<&[u8]> {
+        DBRawIterator::key(self)
+    }
+    fn value(&self) -> Option<&[u8]> {
+        DBRawIterator::value(self)
+    }
+}
+
+type MemoryMap = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// An in-memory store for tests and ephemeral deployments. Snapshots and
+/// iterators share the map, a write copies it only when it is shared.
+#[derive(Default)]
+pub struct MemoryStore {
+    map: RwLock<Arc<MemoryMap>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn current(&self) -> Arc<MemoryMap> {
+        Arc::clone(&self.map.read().expect("read memory store"))
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Ok(self
+            .map
+            .read()
+            .expect("read memory store")
+            .get(key)
+            .cloned())
+    }
+
+    fn iter(&self, key_type: KeyType) -> Box<dyn KvIterator + '_> {
+        Box::new(MemoryIterator::new(self.current(), key_type))
+    }
+
+    fn write(&self, ops: Vec<BatchOp>) -> Result<(), String> {
+        let mut guard = self.map.write().expect("write memory store");
+        let map = Arc::make_mut(&mut *guard);
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    map.insert(key, value);
+                }
+                BatchOp::Delete(key) => {
+                    map.remove(&key);
+                }
+                BatchOp::DeleteRange(from, to) => {
+                    let keys: Vec<Vec<u8>> = map
+                        .range::<[u8], _>((Bound::Included(&from[..]), Bound::Excluded(&to[..])))
+                        .map(|(key, _)| key.clone())
+                        .collect();
+                    for key in keys {
+                        map.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(self: Arc<Self>) -> Arc<dyn KvStore> {
+        Arc::new(MemoryStore {
+            map: RwLock::new(self.current()),
+        })
+    }
+}
+
+struct MemoryIterator {
+    map: Arc<MemoryMap>,
+    // The key range of the KeyType
+    start: Vec<u8>,
+    end: Vec<u8>,
+    current: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl MemoryIterator {
+    fn new(map: Arc<MemoryMap>, key_type: KeyType) -> MemoryIterator {
+        let key_type_byte = key_type as u8;
+        MemoryIterator {
+            map,
+            start: vec![key_type_byte],
+            end: vec![key_type_byte + 1],
+            current: None,
+        }
+    }
+
+    fn first_after(&self, from: Bound<&[u8]>) -> Option<(Vec<u8>, Vec<u8>)> {
+        let from = match from {
+            Bound::Included(key) | Bound::Excluded(key) if key >= &self.end[..] => return None,
+            Bound::Included(key) if key < &self.start[..] => Bound::Included(&self.start[..]),
+            from => from,
+        };
+        self.map
+            .range::<[u8], _>((from, Bound::Excluded(&self.end[..])))
+            .next()
+            .map(|(key, value)| (key.clone(), value.clone()))
+    }
+
+    fn last_before(&self, to: Bound<&[u8]>) -> Option<(Vec<u8>, Vec<u8>)> {
+        let to = match to {
+            Bound::Included(key) if key >= &self.end[..] => Bound::Excluded(&self.end[..]),
+            to => to,
+        };
+        self.map
+            .range::<[u8], _>((Bound::Included(&self.start[..]), to))
+            .next_back()
+            .map(|(key, value)| (key.clone(), value.clone()))
+    }
+}
+
+impl KvIterator for MemoryIterator {
+    fn seek(&mut self, key: &[u8]) {
+        self.current = self.first_after(Bound::Included(key));
+    }
+    fn seek_for_prev(&mut self, key: &[u8]) {
+        self.current = if key < &self.start[..] {
+            None
+        } else {
+            self.last_before(Bound::Included(key))
+        };
+    }
+    fn seek_to_first(&mut self) {
+        self.current = self.first_after(Bound::Included(&self.start[..]));
+    }
+    fn next(&mut self) {
+        if let Some((key, _)) = self.current.take() {
+            self.current = self.first_after(Bound::Excluded(&key));
+        }
+    }
+    fn prev(&mut self) {
+        if let Some((key, _)) = self.current.take() {
+            self.current = self.last_before(Bound::Excluded(&key));
+        }
+    }
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+    fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(key, _)| &key[..])
+    }
+    fn value(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(_, value)| &value[..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_store() {
+        let store = Arc::new(MemoryStore::new());
+        let ty = KeyType::BlockMap as u8;
+        let other_ty = KeyType::ContractChange as u8;
+        store
+            .write(vec![
+                BatchOp::Put(vec![ty, 1], vec![1]),
+                BatchOp::Put(vec![ty, 3], vec![3]),
+                BatchOp::Put(vec![ty, 5], vec![5]),
+                BatchOp::Put(vec![other_ty, 0], vec![0]),
+            ])
+            .unwrap();
+        let snapshot = Arc::clone(&store).snapshot();
+        store
+            .write(vec![
+                BatchOp::DeleteRange(vec![ty, 2], vec![ty, 4]),
+                BatchOp::Delete(vec![ty, 5]),
+            ])
+            .unwrap();
+
+        assert_eq!(store.get(&[ty, 1]).unwrap(), Some(vec![1]));
+        assert_eq!(store.get(&[ty, 3]).unwrap(), None);
+        assert_eq!(snapshot.get(&[ty, 3]).unwrap(), Some(vec![3]));
+
+        let mut iter = snapshot.iter(KeyType::BlockMap);
+        iter.seek(&[ty, 2]);
+        assert_eq!(iter.key(), Some(&[ty, 3][..]));
+        iter.next();
+        assert_eq!(iter.value(), Some(&[5][..]));
+        // Stays in the KeyType
+        iter.next();
+        assert!(!iter.valid());
+        iter.seek_for_prev(&[ty, 4]);
+        assert_eq!(iter.key(), Some(&[ty, 3][..]));
+        iter.prev();
+        assert_eq!(iter.key(), Some(&[ty, 1][..]));
+        iter.prev();
+        assert!(!iter.valid());
+        iter.seek_for_prev(&[ty + 1]);
+        assert_eq!(iter.key(), Some(&[ty, 5][..]));
+
+        let mut iter = store.iter(KeyType::BlockMap);
+        iter.seek_to_first();
+        assert_eq!(iter.key(), Some(&[ty, 1][..]));
+        iter.next();
+        assert!(!iter.valid());
+    }
+}
@@ -1,5 +1,6 @@
 use bincode::deserialize;
 use ckb_jsonrpc_types as json_types;
+use ckb_simple_account_layer::CkbBlake2bHasher;
 use ckb_types::{
     bytes::Bytes,
     core::{BlockView, EpochNumberWithFraction, HeaderView, ScriptHashType},
@@ -7,13 +8,17 @@ use ckb_types::{
     prelude::*,
     H160, H256, U256,
 };
-use rocksdb::DB;
-use std::collections::HashSet;
+use serde::de::DeserializeOwned;
+use sparse_merkle_tree::{SparseMerkleTree, H256 as SmtH256};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use super::{db_get, value, Key};
-use crate::client::HttpRpcClient;
+use super::{
+    db_get, db_iter, value, Key, KeyType, KvIterator, KvStore, PendingContract, PendingPool,
+    PendingTransaction, SmtStore,
+};
+use crate::chain::ChainSource;
 use crate::types::{
     ContractAddress, ContractChange, ContractMeta, EoaAddress, LogInfo, CELLBASE_MATURITY,
     SIGHASH_TYPE_HASH,
@@ -21,18 +26,112 @@ use crate::types::{
 
 #[derive(Clone)]
 pub struct Loader {
-    pub db: Arc<DB>,
-    client: HttpRpcClient,
+    pub db: Arc<dyn KvStore>,
+    client: Box<dyn ChainSource>,
+    pending: Arc<Mutex<PendingPool>>,
+    // The indexed block when `db` is a snapshot
+    snapshot_block: Option<(u64, H256)>,
 }
 
 impl Loader {
-    pub fn new(db: Arc<DB>, ckb_uri: &str) -> Result<Loader, String> {
+    pub fn new(
+        db: Arc<dyn KvStore>,
+        client: Box<dyn ChainSource>,
+        pending: Arc<Mutex<PendingPool>>,
+    ) -> Result<Loader, String> {
+        Ok(Loader {
+            db,
+            client,
+            pending,
+            snapshot_block: None,
+        })
+    }
+
+    /// A loader reading from a snapshot of the DB taken now, all reads of
+    /// the returned loader see the same indexed block.
+    pub fn snapshot(&self) -> Result<Loader, String> {
+        let db = Arc::clone(&self.db).snapshot();
+        let last: Option<value::Last> = db_get(db.as_ref(), &Bytes::from(&Key::Last))?;
         Ok(Loader {
             db,
-            client: HttpRpcClient::new(ckb_uri.to_string()),
+            snapshot_block: last.map(|last| (last.number, last.hash)),
+            ..self.clone()
         })
     }
 
+    /// The indexed block the snapshot was taken at
+    pub fn snapshot_block(&self) -> Option<(u64, H256)> {
+        self.snapshot_block.clone()
+    }
+
+    fn get<K: AsRef<[u8]>, T: DeserializeOwned>(&self, key: K) -> Result<Option<T>, String> {
+        db_get(self.db.as_ref(), key)
+    }
+
+    fn raw_iterator(&self, key_type: KeyType) -> Box<dyn KvIterator + '_> {
+        db_iter(self.db.as_ref(), key_type)
+    }
+
+    /// Build a transaction on a snapshot and add it to the pending pool. The
+    /// builder reads a copy of the pool taken with the snapshot, the pool is
+    /// only locked again to add the transaction, which fails if a cell it
+    /// spends was spent or dropped by another transaction in the meantime.
+    pub fn build_pending<T, F>(&self, build: F) -> Result<T, String>
+    where
+        F: FnOnce(Loader) -> Result<(T, PendingTransaction), String>,
+    {
+        let (mut loader, pool) = {
+            let pending = self.pending.lock().expect("lock pending pool");
+            (self.snapshot()?, pending.clone())
+        };
+        loader.pending = Arc::new(Mutex::new(pool));
+        let (value, tx) = build(loader)?;
+        self.pending
+            .lock()
+            .expect("lock pending pool")
+            .insert_checked(tx)?;
+        Ok(value)
+    }
+
+    pub fn commit_pending_transaction(&self, tx_hash: &H256, inputs: &[packed::OutPoint]) {
+        self.pending
+            .lock()
+            .expect("lock pending pool")
+            .commit(tx_hash, inputs);
+    }
+
+    pub fn remove_pending_transaction(&self, tx_hash: &H256) {
+        self.pending
+            .lock()
+            .expect("lock pending pool")
+            .remove(tx_hash);
+    }
+
+    pub fn load_pending_contract(&self, address: &ContractAddress) -> Option<PendingContract> {
+        self.pending
+            .lock()
+            .expect("lock pending pool")
+            .contract(address)
+    }
+
+    // Load the EoA cell which the next transaction should spend, the output
+    // of a pending transaction take precedence over the indexed live cell.
+    pub fn load_spendable_eoa_cell(
+        &mut self,
+        eoa_address: H160,
+    ) -> Result<(value::EoaLiveCell, packed::CellOutput, Bytes), String> {
+        let pending_cell = self
+            .pending
+            .lock()
+            .expect("lock pending pool")
+            .eoa_cell(&eoa_address);
+        if let Some(pending_cell) = pending_cell {
+            log::debug!("use pending eoa cell: {:x}", eoa_address);
+            return Ok((pending_cell.cell, pending_cell.output, pending_cell.data));
+        }
+        self.load_eoa_live_cell(eoa_address)
+    }
+
     pub fn load_contract_live_cell(
         &mut self,
         tx_hash: H256,
@@ -57,7 +156,8 @@ impl Loader {
         eoa_address: H160,
     ) -> Result<(value::EoaLiveCell, packed::CellOutput, Bytes), String> {
         let key_bytes = Bytes::from(&Key::EoaLiveCell(eoa_address.clone()));
-        let value = db_get::<_, value::EoaLiveCell>(&self.db, &key_bytes)?
+        let value = self
+            .get::<_, value::EoaLiveCell>(&key_bytes)?
             .ok_or_else(|| format!("eoa live cell not found: {:x}", eoa_address))?;
         let cell_with_status = self.client.get_live_cell(value.out_point().into(), true)?;
         let cell = cell_with_status.cell.ok_or_else(|| {
@@ -94,11 +194,11 @@ impl Loader {
             output_index: None,
         });
 
-        let max_mature_number = get_max_mature_number(&mut self.client)?;
+        let max_mature_number = get_max_mature_number(self.client.as_mut())?;
         let mut total_capacity: u64 = 0;
         let mut live_cells = Vec::new();
 
-        let mut iter = self.db.raw_iterator();
+        let mut iter = self.raw_iterator(KeyType::LockLiveCell);
         iter.seek(&key_prefix_bytes);
         while iter.valid() {
             if let Some((key_bytes, value_bytes)) = iter
@@ -164,6 +264,7 @@ impl Loader {
         load_logs: bool,
         check_alive: bool,
     ) -> Result<ContractChange, String> {
+        self.check_tainted(&address)?;
         if check_alive {
             let meta = self.load_contract_meta(address.clone())?;
             if meta.destructed {
@@ -188,7 +289,7 @@ impl Loader {
             output_index: None,
         };
 
-        let mut iter = self.db.raw_iterator();
+        let mut iter = self.raw_iterator(KeyType::ContractChange);
         iter.seek_for_prev(&Bytes::from(&last_key));
         let is_valid = iter.valid();
         if let Some((key_bytes, value_bytes)) = iter
@@ -221,7 +322,7 @@ impl Loader {
                     tx_index: Some(tx_index),
                     output_index: Some(output_index),
                 });
-                db_get(&self.db, &logs_key_bytes)?
+                self.get(&logs_key_bytes)?
                     .map(|logs: value::ContractLogs| logs.0)
                     .unwrap_or_default()
             } else {
@@ -231,7 +332,7 @@ impl Loader {
                 tx_origin: value.tx_origin,
                 address,
                 tx_hash: value.tx_hash,
-                new_storage: value.new_storage.into_iter().collect(),
+                storage_diff: value.storage_diff,
                 capacity: value.capacity,
                 balance: value.balance,
                 is_create: value.is_create,
@@ -244,6 +345,86 @@ impl Loader {
         Err(format!("Latest contract change not found: {:x}", address.0))
     }
 
+    /// The full storage after the change: the latest storage with the writes
+    /// of all later changes undone
+    pub fn load_contract_storage(
+        &self,
+        change: &ContractChange,
+    ) -> Result<HashMap<H256, H256>, String> {
+        let address = &change.address;
+        let change_key_bytes = Bytes::from(&change.db_key());
+        let change_key = change_key_bytes.as_ref();
+        let state_prefix_bytes = Bytes::from(&Key::ContractState {
+            address: address.clone(),
+            storage_key: None,
+        });
+        let mut storage = HashMap::default();
+        let mut iter = self.raw_iterator(KeyType::ContractState);
+        iter.seek(&state_prefix_bytes);
+        while iter.valid() {
+            match (iter.key(), iter.value()) {
+                (Some(key_bytes), Some(value_bytes))
+                    if key_bytes.starts_with(&state_prefix_bytes) =>
+                {
+                    let storage_key = match Key::try_from(key_bytes)? {
+                        Key::ContractState { storage_key, .. } => storage_key.expect("illegal key"),
+                        _ => {
+                            panic!("DB corrupted deserialize Key::ContractState");
+                        }
+                    };
+                    let value: H256 = deserialize(value_bytes).map_err(|err| err.to_string())?;
+                    storage.insert(storage_key, value);
+                }
+                _ => break,
+            }
+            iter.next();
+        }
+
+        let change_prefix_bytes = Bytes::from(&Key::ContractChange {
+            address: address.clone(),
+            number: None,
+            tx_index: None,
+            output_index: None,
+        });
+        let last_key = Key::ContractChange {
+            address: address.clone(),
+            number: Some(std::u64::MAX),
+            tx_index: None,
+            output_index: None,
+        };
+        let mut iter = self.raw_iterator(KeyType::ContractChange);
+        iter.seek_for_prev(&Bytes::from(&last_key));
+        while iter.valid() {
+            match (iter.key(), iter.value()) {
+                (Some(key_bytes), Some(value_bytes))
+                    if key_bytes.starts_with(&change_prefix_bytes) && key_bytes > change_key =>
+                {
+                    let change: value::ContractChange =
+                        deserialize(value_bytes).map_err(|err| err.to_string())?;
+                    for (key, old_value, _new_value) in change.storage_diff {
+                        if old_value == H256::default() {
+                            storage.remove(&key);
+                        } else {
+                            storage.insert(key, old_value);
+                        }
+                    }
+                }
+                _ => break,
+            }
+            iter.prev();
+        }
+        Ok(storage)
+    }
+
+    /// The persisted storage tree of a contract
+    pub fn load_contract_tree(
+        &self,
+        address: ContractAddress,
+        root: &H256,
+    ) -> SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore> {
+        SmtStore::load_tree(Arc::clone(&self.db), address, root)
+    }
+
     pub fn load_contract_meta_list(
         &mut self,
         from_block: u64,
@@ -256,7 +437,7 @@ impl Loader {
         let mut all_metas = Vec::new();
         for number in from_block..=to_block {
             let key_bytes = Bytes::from(&Key::BlockDelta(number));
-            let block_delta = match db_get::<_, value::BlockDelta>(&self.db, &key_bytes)? {
+            let block_delta = match self.get::<_, value::BlockDelta>(&key_bytes)? {
                 Some(block_delta) => block_delta,
                 None => {
                     return Ok(all_metas);
@@ -269,7 +450,7 @@ impl Loader {
                 .map(|(addr, _)| addr)
             {
                 let key_bytes = Bytes::from(&Key::ContractMeta(addr.clone()));
-                let meta = db_get::<_, value::ContractMeta>(&self.db, &key_bytes)?.unwrap();
+                let meta = self.get::<_, value::ContractMeta>(&key_bytes)?.unwrap();
                 all_metas.push((
                     number,
                     ContractMeta {
@@ -287,8 +468,9 @@ impl Loader {
     }
 
     pub fn load_contract_meta(&self, address: ContractAddress) -> Result<ContractMeta, String> {
+        self.check_tainted(&address)?;
         let key_bytes = Bytes::from(&Key::ContractMeta(address.clone()));
-        if let Some(value) = db_get::<_, value::ContractMeta>(&self.db, &key_bytes)? {
+        if let Some(value) = self.get::<_, value::ContractMeta>(&key_bytes)? {
             Ok(ContractMeta {
                 address,
                 code: value.code,
@@ -302,6 +484,47 @@ impl Loader {
         }
     }
 
+    // Load the code of the contract for running a program, the pending
+    // contract take precedence over the indexed contract meta.
+    pub fn load_contract_code(&self, address: &ContractAddress) -> Result<Bytes, String> {
+        let pending_code = match self.load_pending_contract(address) {
+            Some(pending) if pending.destructed => {
+                return Err(format!("Contract already destructed: {:x}", address.0));
+            }
+            Some(pending) => pending.code,
+            None => Bytes::default(),
+        };
+        if !pending_code.is_empty() {
+            return Ok(pending_code);
+        }
+        let meta = self.load_contract_meta(address.clone())?;
+        if meta.destructed {
+            return Err(format!("Contract already destructed: {:x}", address.0));
+        }
+        Ok(meta.code)
+    }
+
+    // A contract touched by a skipped invalid transaction is not served
+    pub fn load_tainted_contracts(&self) -> Result<Vec<value::TaintedContract>, String> {
+        let tainted: Option<value::TaintedContracts> =
+            self.get(&Bytes::from(&Key::TaintedContracts))?;
+        Ok(tainted.unwrap_or_default().0)
+    }
+
+    fn check_tainted(&self, address: &ContractAddress) -> Result<(), String> {
+        if let Some(contract) = self
+            .load_tainted_contracts()?
+            .into_iter()
+            .find(|contract| &contract.address == address)
+        {
+            return Err(format!(
+                "Contract {:x} is tainted by the skipped transaction {:#x} in block {}: {}",
+                address.0, contract.tx_hash, contract.number, contract.reason
+            ));
+        }
+        Ok(())
+    }
+
     pub fn load_logs(
         &mut self,
         from_block: u64,
@@ -318,7 +541,7 @@ impl Loader {
         let mut all_logs = Vec::new();
         for number in from_block..=to_block {
             let key_bytes = Bytes::from(&Key::BlockDelta(number));
-            let block_delta = match db_get::<_, value::BlockDelta>(&self.db, &key_bytes)? {
+            let block_delta = match self.get::<_, value::BlockDelta>(&key_bytes)? {
                 Some(block_delta) => block_delta,
                 None => {
                     return Ok(all_logs);
@@ -336,7 +559,7 @@ impl Loader {
                     tx_index: None,
                     output_index: None,
                 });
-                let mut iter = self.db.raw_iterator();
+                let mut iter = self.raw_iterator(KeyType::ContractLogs);
                 iter.seek(&key_prefix_bytes);
                 while iter.valid() {
                     if let Some((key_bytes, value_bytes)) = iter
@@ -396,6 +619,12 @@ impl Loader {
             .collect()
     }
 
+    /// The last indexed block
+    pub fn load_last_block(&self) -> Result<Option<(u64, H256)>, String> {
+        let last: Option<value::Last> = self.get(&Bytes::from(&Key::Last))?;
+        Ok(last.map(|last| (last.number, last.hash)))
+    }
+
     pub fn load_header(&mut self, number_opt: Option<u64>) -> Result<HeaderView, String> {
         let header = if let Some(number) = number_opt {
             self.client
@@ -421,7 +650,7 @@ impl Loader {
 }
 
 // Get max mature block number
-pub fn get_max_mature_number(client: &mut HttpRpcClient) -> Result<u64, String> {
+pub fn get_max_mature_number(client: &mut dyn ChainSource) -> Result<u64, String> {
     let tip_epoch = client
         .get_tip_header()
         .map(|header| EpochNumberWithFraction::from_full_value(header.inner.epoch.value()))?;
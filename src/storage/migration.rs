@@ -0,0 +1,401 @@
+use bincode::{deserialize, serialize};
+use ckb_simple_account_layer::CkbBlake2bHasher;
+use ckb_types::{bytes::Bytes, packed, prelude::*, H256};
+use rocksdb::{WriteBatch, DB};
+use sparse_merkle_tree::{SparseMerkleTree, H256 as SmtH256};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use super::indexer::live_cell_map;
+use super::{column_family, db_get, value, Batch, Key, KeyType, SmtStore, COLUMN_FAMILY_KEY_TYPES};
+use crate::client::HttpRpcClient;
+use crate::types::{h256_to_smth256, storage_diff, ContractAddress};
+
+/// The schema version of the database written by this build
+pub const SCHEMA_VERSION: u32 = 4;
+
+struct Migration {
+    /// The schema version after this migration
+    version: u32,
+    description: &'static str,
+    run: fn(&DB, &mut HttpRpcClient) -> Result<(), String>,
+}
+
+// In version order, every schema change should append a migration here
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "keep cell outputs in the live cell map",
+        run: migrate_live_cell_outputs,
+    },
+    Migration {
+        version: 2,
+        description: "move records to column families",
+        run: migrate_column_families,
+    },
+    Migration {
+        version: 3,
+        description: "store storage diffs in contract changes",
+        run: migrate_storage_diffs,
+    },
+    Migration {
+        version: 4,
+        description: "persist contract storage trees",
+        run: migrate_storage_trees,
+    },
+];
+
+// Records moved per write batch
+const MIGRATION_BATCH_SIZE: usize = 10_000;
+
+/// Check the schema version of the database. An empty database is marked with
+/// the current version, an old database is upgraded in place when `migrate` is
+/// set, otherwise refuse to start.
+pub fn check_schema(db: &DB, ckb_uri: &str, migrate: bool) -> Result<(), String> {
+    let version = match db_get::<_, u32>(db, &Bytes::from(&Key::SchemaVersion))? {
+        Some(version) => version,
+        None if is_empty(db) => {
+            log::info!("Initialize database schema version {}", SCHEMA_VERSION);
+            return put_version(db, SCHEMA_VERSION);
+        }
+        // Databases created before the version key was introduced
+        None => 0,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "Database schema version {} is newer than the supported version {}, please upgrade polyjuice",
+            version, SCHEMA_VERSION
+        ));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+    if !migrate {
+        return Err(format!(
+            "Database schema version {} is older than the supported version {}, please backup the database and restart with --migrate",
+            version, SCHEMA_VERSION
+        ));
+    }
+    let mut client = HttpRpcClient::new(ckb_uri.to_string());
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        log::info!(
+            "Migrate database to schema version {}: {}",
+            migration.version,
+            migration.description
+        );
+        (migration.run)(db, &mut client)?;
+        put_version(db, migration.version)?;
+    }
+    Ok(())
+}
+
+fn contract_addresses(db: &DB) -> Result<Vec<ContractAddress>, String> {
+    let mut addresses: Vec<ContractAddress> = Vec::new();
+    let mut iter = db.raw_iterator_cf(column_family(db, KeyType::ContractMeta));
+    iter.seek_to_first();
+    while iter.valid() {
+        if let Some(key_bytes) = iter.key() {
+            match Key::try_from(key_bytes)? {
+                Key::ContractMeta(address) => addresses.push(address),
+                _ => panic!("DB corrupted deserialize Key::ContractMeta"),
+            }
+        }
+        iter.next();
+    }
+    Ok(addresses)
+}
+
+pub(super) fn is_empty(db: &DB) -> bool {
+    let mut iter = db.raw_iterator();
+    iter.seek_to_first();
+    !iter.valid()
+}
+
+pub(super) fn put_version(db: &DB, version: u32) -> Result<(), String> {
+    let value_bytes = serialize(&version).map_err(|err| err.to_string())?;
+    db.put(&Bytes::from(&Key::SchemaVersion), value_bytes)
+        .map_err(|err| err.to_string())
+}
+
+mod v2 {
+    use crate::types::EoaAddress;
+    use ckb_types::H256;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct ContractChange {
+        pub tx_hash: H256,
+        pub tx_origin: EoaAddress,
+        pub new_storage: Vec<(H256, H256)>,
+        pub capacity: u64,
+        pub balance: u64,
+        pub is_create: bool,
+    }
+}
+
+mod v0 {
+    use super::value::{EoaLiveCell, LockLiveCell};
+    use crate::types::ContractAddress;
+    use ckb_types::{H160, H256};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct LiveCellMap {
+        pub number: u64,
+        pub tx_index: u32,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct BlockDelta {
+        pub contracts: Vec<(ContractAddress, bool)>,
+        pub added_cells: Vec<(H256, u32, u32, LockLiveCell)>,
+        pub removed_cells: Vec<(H256, u64, u32, u32, LockLiveCell)>,
+        pub eoa_added_cells: Vec<H160>,
+        pub eoa_removed_cells: Vec<(H160, EoaLiveCell)>,
+        pub destructed_contracts: Vec<ContractAddress>,
+    }
+}
+
+// Version 0 only kept the position of a live cell, fetch the outputs from CKB.
+// Before version 2 all records are in the default column family.
+fn migrate_live_cell_outputs(db: &DB, client: &mut HttpRpcClient) -> Result<(), String> {
+    let mut txs: HashMap<H256, packed::Transaction> = HashMap::default();
+    let mut load_cell = |out_point: &packed::OutPoint,
+                         number: u64,
+                         tx_index: u32|
+     -> Result<value::LiveCellMap, String> {
+        let tx_hash: H256 = out_point.tx_hash().unpack();
+        if !txs.contains_key(&tx_hash) {
+            let tx = client
+                .get_transaction(tx_hash.clone())?
+                .ok_or_else(|| format!("Can not get transaction: {:#x}", tx_hash))?;
+            txs.insert(
+                tx_hash.clone(),
+                packed::Transaction::from(tx.transaction.inner),
+            );
+        }
+        let tx = &txs[&tx_hash];
+        let index: u32 = out_point.index().unpack();
+        let output = tx
+            .raw()
+            .outputs()
+            .get(index as usize)
+            .ok_or_else(|| format!("Invalid out point: {}", out_point))?;
+        let data = tx
+            .raw()
+            .outputs_data()
+            .get(index as usize)
+            .map(|data| data.raw_data())
+            .unwrap_or_default();
+        Ok(live_cell_map(number, tx_index, &output.into(), &data))
+    };
+
+    let mut batch = WriteBatch::default();
+    let prefix = [KeyType::LiveCellMap as u8];
+    let mut iter = db.raw_iterator();
+    iter.seek(&prefix);
+    while iter.valid() {
+        match (iter.key(), iter.value()) {
+            (Some(key_bytes), Some(value_bytes)) if key_bytes.starts_with(&prefix) => {
+                let out_point = match Key::try_from(key_bytes)? {
+                    Key::LiveCellMap(out_point) => out_point,
+                    _ => panic!("DB corrupted deserialize Key::LiveCellMap"),
+                };
+                let old: v0::LiveCellMap =
+                    deserialize(value_bytes).map_err(|err| err.to_string())?;
+                let cell = load_cell(&out_point, old.number, old.tx_index)?;
+                let value_bytes = serialize(&cell).map_err(|err| err.to_string())?;
+                batch.put(key_bytes, value_bytes);
+            }
+            _ => break,
+        }
+        iter.next();
+    }
+
+    let prefix = [KeyType::BlockDelta as u8];
+    let mut iter = db.raw_iterator();
+    iter.seek(&prefix);
+    while iter.valid() {
+        match (iter.key(), iter.value()) {
+            (Some(key_bytes), Some(value_bytes)) if key_bytes.starts_with(&prefix) => {
+                let old: v0::BlockDelta =
+                    deserialize(value_bytes).map_err(|err| err.to_string())?;
+                let mut removed_cells = Vec::with_capacity(old.removed_cells.len());
+                for (lock_hash, number, tx_index, output_index, lock_cell) in old.removed_cells {
+                    let cell = load_cell(&lock_cell.out_point(), number, tx_index)?;
+                    removed_cells.push((
+                        lock_hash,
+                        number,
+                        tx_index,
+                        output_index,
+                        lock_cell,
+                        cell,
+                    ));
+                }
+                let block_delta = value::BlockDelta {
+                    contracts: old.contracts,
+                    added_cells: old.added_cells,
+                    removed_cells,
+                    eoa_added_cells: old.eoa_added_cells,
+                    eoa_removed_cells: old.eoa_removed_cells,
+                    destructed_contracts: old.destructed_contracts,
+                };
+                let value_bytes = serialize(&block_delta).map_err(|err| err.to_string())?;
+                batch.put(key_bytes, value_bytes);
+            }
+            _ => break,
+        }
+        iter.next();
+    }
+    db.write(batch).map_err(|err| err.to_string())
+}
+
+// Version 1 keeps all records in the default column family, move every key
+// type to its own column family. Records are moved in several batches, an
+// interrupted migration is resumed on next start.
+fn migrate_column_families(db: &DB, _client: &mut HttpRpcClient) -> Result<(), String> {
+    for key_type in COLUMN_FAMILY_KEY_TYPES.iter() {
+        let cf = column_family(db, key_type.clone());
+        let prefix = [key_type.clone() as u8];
+        let mut moved = 0;
+        let mut batch = WriteBatch::default();
+        let mut iter = db.raw_iterator();
+        iter.seek(&prefix);
+        while iter.valid() {
+            match (iter.key(), iter.value()) {
+                (Some(key_bytes), Some(value_bytes)) if key_bytes.starts_with(&prefix) => {
+                    batch.put_cf(cf, key_bytes, value_bytes);
+                    batch.delete(key_bytes);
+                    moved += 1;
+                    if moved % MIGRATION_BATCH_SIZE == 0 {
+                        db.write(batch).map_err(|err| err.to_string())?;
+                        batch = WriteBatch::default();
+                    }
+                }
+                _ => break,
+            }
+            iter.next();
+        }
+        db.write(batch).map_err(|err| err.to_string())?;
+        log::info!(
+            "Moved {} records to column family {}",
+            moved,
+            key_type.column_family()
+        );
+    }
+    Ok(())
+}
+
+// Version 2 stores the full storage in every contract change, replace it by
+// the written keys and keep the latest storage in Key::ContractState.
+fn migrate_storage_diffs(db: &DB, _client: &mut HttpRpcClient) -> Result<(), String> {
+    let change_cf = column_family(db, KeyType::ContractChange);
+    let state_cf = column_family(db, KeyType::ContractState);
+
+    for address in contract_addresses(db)? {
+        // Contracts with state are migrated by an interrupted run. Contracts
+        // without storage are the same in both versions.
+        let state_prefix_bytes = Bytes::from(&Key::ContractState {
+            address: address.clone(),
+            storage_key: None,
+        });
+        let mut iter = db.raw_iterator_cf(state_cf);
+        iter.seek(&state_prefix_bytes);
+        if iter.valid()
+            && iter
+                .key()
+                .map(|key| key.starts_with(&state_prefix_bytes))
+                .unwrap_or(false)
+        {
+            continue;
+        }
+
+        let change_prefix_bytes = Bytes::from(&Key::ContractChange {
+            address: address.clone(),
+            number: None,
+            tx_index: None,
+            output_index: None,
+        });
+        let mut storage: HashMap<H256, H256> = HashMap::default();
+        let mut batch = WriteBatch::default();
+        let mut iter = db.raw_iterator_cf(change_cf);
+        iter.seek(&change_prefix_bytes);
+        while iter.valid() {
+            match (iter.key(), iter.value()) {
+                (Some(key_bytes), Some(value_bytes))
+                    if key_bytes.starts_with(&change_prefix_bytes) =>
+                {
+                    let old: v2::ContractChange =
+                        deserialize(value_bytes).map_err(|err| err.to_string())?;
+                    let new_storage: HashMap<H256, H256> = old.new_storage.into_iter().collect();
+                    let change = value::ContractChange {
+                        tx_hash: old.tx_hash,
+                        tx_origin: old.tx_origin,
+                        storage_diff: storage_diff(&storage, &new_storage),
+                        capacity: old.capacity,
+                        balance: old.balance,
+                        is_create: old.is_create,
+                    };
+                    let value_bytes = serialize(&change).map_err(|err| err.to_string())?;
+                    batch.put_cf(change_cf, key_bytes, value_bytes);
+                    storage = new_storage;
+                }
+                _ => break,
+            }
+            iter.next();
+        }
+        for (key, value) in storage {
+            let state_key = Key::ContractState {
+                address: address.clone(),
+                storage_key: Some(key),
+            };
+            let value_bytes = serialize(&value).map_err(|err| err.to_string())?;
+            batch.put_cf(state_cf, &Bytes::from(&state_key), value_bytes);
+        }
+        db.write(batch).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+// Build the storage tree of the latest state of every contract. Trees of
+// older states are not persisted, a rollback to a block before the
+// migration needs a reindex.
+fn migrate_storage_trees(db: &DB, _client: &mut HttpRpcClient) -> Result<(), String> {
+    rebuild_storage_trees(db)
+}
+
+/// Write the tree nodes of all contracts from their latest storage
+pub(super) fn rebuild_storage_trees(db: &DB) -> Result<(), String> {
+    let state_cf = column_family(db, KeyType::ContractState);
+    for address in contract_addresses(db)? {
+        let mut tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore> =
+            SparseMerkleTree::default();
+        let state_prefix_bytes = Bytes::from(&Key::ContractState {
+            address: address.clone(),
+            storage_key: None,
+        });
+        let mut iter = db.raw_iterator_cf(state_cf);
+        iter.seek(&state_prefix_bytes);
+        while iter.valid() {
+            match (iter.key(), iter.value()) {
+                (Some(key_bytes), Some(value_bytes))
+                    if key_bytes.starts_with(&state_prefix_bytes) =>
+                {
+                    let storage_key = match Key::try_from(key_bytes)? {
+                        Key::ContractState { storage_key, .. } => storage_key.expect("illegal key"),
+                        _ => panic!("DB corrupted deserialize Key::ContractState"),
+                    };
+                    let value: H256 = deserialize(value_bytes).map_err(|err| err.to_string())?;
+                    tree.update(h256_to_smth256(&storage_key), h256_to_smth256(&value))
+                        .map_err(|err| err.to_string())?;
+                }
+                _ => break,
+            }
+            iter.next();
+        }
+        let mut batch = Batch::new(db);
+        tree.store().write_nodes(&mut batch, &address);
+        batch.commit()?;
+    }
+    Ok(())
+}
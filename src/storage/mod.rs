@@ -1,18 +1,38 @@
+mod export;
+mod fetcher;
 mod indexer;
+mod kv;
 mod loader;
+mod migration;
+mod pending;
 mod runner;
-
-pub use indexer::Indexer;
+mod smt;
+mod snapshot;
+mod verify;
+
+pub use export::{export_state, import_state, state_file_block};
+pub use fetcher::BlockFetcher;
+pub use indexer::{rollback_to, Checkpoint, Indexer, IndexerHealth, QuarantinedTx};
+pub use kv::{BatchOp, KvIterator, KvStore, MemoryStore};
 pub use loader::Loader;
+pub use migration::{check_schema, SCHEMA_VERSION};
+pub use pending::{PendingContract, PendingEoaCell, PendingPool, PendingTransaction};
 pub use runner::{CsalRunContext, Runner};
+pub use smt::SmtStore;
+pub use snapshot::DbSnapshot;
+pub use verify::Verifier;
 
 use crate::types::ContractAddress;
 use bincode::deserialize;
 use ckb_types::{bytes::Bytes, packed, prelude::*, H160, H256};
-use rocksdb::DB;
+use rocksdb::{
+    BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor, Options, SliceTransform, DB,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
 use serde::de::DeserializeOwned;
 use std::convert::TryFrom;
 use std::mem;
+use std::path::Path;
 
 type BlockNumber = u64;
 
@@ -30,7 +50,7 @@ pub enum KeyType {
 
     /// Contract state change
     ///   (ContractAddress, BlockNumber, TransactionIndex, OutputIndex)
-    ///      => (TransactionHash, SenderAddress, NewStorageTree)
+    ///      => (TransactionHash, SenderAddress, StorageDiff)
     ContractChange = 0x02,
 
     /// Contract logs
@@ -51,13 +71,94 @@ pub enum KeyType {
     ///   EthAddress => (TransactionHash, OutputIndex)
     EoaLiveCell = 0x06,
 
-    /// Store meta info of a live cell outpoint
-    ///   OutPoint => (BlockNumber, TransactionIndex)
+    /// Store meta info and output of a live cell outpoint
+    ///   OutPoint => (BlockNumber, TransactionIndex, CellOutput, DataHash, Data)
     LiveCellMap = 0x07,
 
+    /// The latest storage of a contract
+    ///   (ContractAddress, StorageKey) => StorageValue
+    ContractState = 0x08,
+
+    /// Sparse merkle tree branch nodes of a contract
+    ///   (ContractAddress, NodeHash) => BranchNode
+    SmtBranch = 0x09,
+
+    /// Sparse merkle tree leaf nodes of a contract
+    ///   (ContractAddress, LeafHash) => LeafNode
+    SmtLeaf = 0x0A,
+
+    /// Contracts touched by skipped invalid transactions, they are not served
+    ///   "tainted_contracts" => value::TaintedContracts
+    TaintedContracts = 0x0B,
+
     /// Delta in the block (for rollback)
     ///   BlockNumber => value::BlockDelta
     BlockDelta = 0xF0,
+
+    /// The key is just schema version
+    ///   "schema_version" => u32
+    SchemaVersion = 0xFE,
+}
+
+/// Key types stored in their own column family
+const COLUMN_FAMILY_KEY_TYPES: [KeyType; 11] = [
+    KeyType::BlockMap,
+    KeyType::ContractChange,
+    KeyType::ContractLogs,
+    KeyType::ContractMeta,
+    KeyType::LockLiveCell,
+    KeyType::EoaLiveCell,
+    KeyType::LiveCellMap,
+    KeyType::ContractState,
+    KeyType::SmtBranch,
+    KeyType::SmtLeaf,
+    KeyType::BlockDelta,
+];
+
+impl KeyType {
+    /// The column family of the keys, keys still start with the KeyType byte
+    pub fn column_family(&self) -> &'static str {
+        match self {
+            KeyType::Last | KeyType::TaintedContracts | KeyType::SchemaVersion => {
+                DEFAULT_COLUMN_FAMILY_NAME
+            }
+            KeyType::BlockMap => "block_map",
+            KeyType::ContractChange => "contract_change",
+            KeyType::ContractLogs => "contract_logs",
+            KeyType::ContractMeta => "contract_meta",
+            KeyType::LockLiveCell => "lock_live_cell",
+            KeyType::EoaLiveCell => "eoa_live_cell",
+            KeyType::LiveCellMap => "live_cell_map",
+            KeyType::ContractState => "contract_state",
+            KeyType::SmtBranch => "smt_branch",
+            KeyType::SmtLeaf => "smt_leaf",
+            KeyType::BlockDelta => "block_delta",
+        }
+    }
+
+    // The length of the prefix scanned by the loader (KeyType byte included)
+    fn prefix_len(&self) -> Option<usize> {
+        match self {
+            KeyType::ContractChange | KeyType::ContractLogs | KeyType::ContractState => {
+                Some(1 + mem::size_of::<H160>())
+            }
+            KeyType::LockLiveCell => Some(1 + mem::size_of::<H256>()),
+            _ => None,
+        }
+    }
+
+    fn column_family_options(&self) -> Options {
+        let mut block_opts = BlockBasedOptions::default();
+        block_opts.set_bloom_filter(10, false);
+        let mut opts = Options::default();
+        opts.set_block_based_table_factory(&block_opts);
+        if let Some(prefix_len) = self.prefix_len() {
+            // Seeks out of the prefix of the seek key are not supported any more
+            opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(prefix_len));
+            opts.set_memtable_prefix_bloom_ratio(0.1);
+        }
+        opts
+    }
 }
 
 impl TryFrom<u8> for KeyType {
@@ -72,7 +173,12 @@ impl TryFrom<u8> for KeyType {
             0x05 => Ok(KeyType::LockLiveCell),
             0x06 => Ok(KeyType::EoaLiveCell),
             0x07 => Ok(KeyType::LiveCellMap),
+            0x08 => Ok(KeyType::ContractState),
+            0x09 => Ok(KeyType::SmtBranch),
+            0x0A => Ok(KeyType::SmtLeaf),
+            0x0B => Ok(KeyType::TaintedContracts),
             0xF0 => Ok(KeyType::BlockDelta),
+            0xFE => Ok(KeyType::SchemaVersion),
             _ => Err(format!("Invalid KeyType {}", value)),
         }
     }
@@ -109,7 +215,21 @@ pub enum Key {
     },
     EoaLiveCell(H160),
     LiveCellMap(packed::OutPoint),
+    ContractState {
+        address: ContractAddress,
+        storage_key: Option<H256>,
+    },
+    SmtBranch {
+        address: ContractAddress,
+        node: H256,
+    },
+    SmtLeaf {
+        address: ContractAddress,
+        leaf_hash: H256,
+    },
+    TaintedContracts,
     BlockDelta(BlockNumber),
+    SchemaVersion,
 }
 
 impl From<&Key> for Bytes {
@@ -199,11 +319,36 @@ impl From<&Key> for Bytes {
                 bytes.extend(out_point.as_slice());
                 bytes.into()
             }
+            Key::ContractState {
+                address,
+                storage_key,
+            } => {
+                let mut bytes = vec![KeyType::ContractState as u8];
+                bytes.extend(address.0.as_bytes());
+                if let Some(storage_key) = storage_key {
+                    bytes.extend(storage_key.as_bytes());
+                }
+                bytes.into()
+            }
+            Key::SmtBranch { address, node } => {
+                let mut bytes = vec![KeyType::SmtBranch as u8];
+                bytes.extend(address.0.as_bytes());
+                bytes.extend(node.as_bytes());
+                bytes.into()
+            }
+            Key::SmtLeaf { address, leaf_hash } => {
+                let mut bytes = vec![KeyType::SmtLeaf as u8];
+                bytes.extend(address.0.as_bytes());
+                bytes.extend(leaf_hash.as_bytes());
+                bytes.into()
+            }
+            Key::TaintedContracts => vec![KeyType::TaintedContracts as u8].into(),
             Key::BlockDelta(number) => {
                 let mut bytes = vec![KeyType::BlockDelta as u8];
                 bytes.extend(&number.to_be_bytes());
                 bytes.into()
             }
+            Key::SchemaVersion => vec![KeyType::SchemaVersion as u8].into(),
         }
     }
 }
@@ -312,11 +457,50 @@ impl TryFrom<&[u8]> for Key {
                 let out_point = packed::OutPoint::from_slice(content).unwrap();
                 Ok(Key::LiveCellMap(out_point))
             }
+            KeyType::ContractState => {
+                ensure_content_len(
+                    "ContractState",
+                    content,
+                    mem::size_of::<H160>() + mem::size_of::<H256>(),
+                )?;
+                let address = ContractAddress::from(
+                    H160::from_slice(&content[0..20]).expect("deserialize address"),
+                );
+                let storage_key = H256::from_slice(&content[20..52]).expect("deserialize key");
+                Ok(Key::ContractState {
+                    address,
+                    storage_key: Some(storage_key),
+                })
+            }
+            KeyType::SmtBranch | KeyType::SmtLeaf => {
+                ensure_content_len(
+                    "SmtNode",
+                    content,
+                    mem::size_of::<H160>() + mem::size_of::<H256>(),
+                )?;
+                let address = ContractAddress::from(
+                    H160::from_slice(&content[0..20]).expect("deserialize address"),
+                );
+                let hash = H256::from_slice(&content[20..52]).expect("deserialize hash");
+                if key_type == KeyType::SmtBranch {
+                    Ok(Key::SmtBranch {
+                        address,
+                        node: hash,
+                    })
+                } else {
+                    Ok(Key::SmtLeaf {
+                        address,
+                        leaf_hash: hash,
+                    })
+                }
+            }
+            KeyType::TaintedContracts => Ok(Key::TaintedContracts),
             KeyType::BlockDelta => {
                 ensure_content_len("BlockDelta", content, mem::size_of::<BlockNumber>())?;
                 let number = deserialize_u64(&content[0..8]);
                 Ok(Key::BlockDelta(number))
             }
+            KeyType::SchemaVersion => Ok(Key::SchemaVersion),
         }
     }
 }
@@ -341,7 +525,9 @@ pub mod value {
     pub struct ContractChange {
         pub tx_hash: H256,
         pub tx_origin: EoaAddress,
-        pub new_storage: Vec<(H256, H256)>,
+        /// The written storage: (key, old_value, new_value), the full storage
+        /// is rebuilt from Key::ContractState and later changes
+        pub storage_diff: Vec<(H256, H256, H256)>,
         pub capacity: u64,
         pub balance: u64,
         pub is_create: bool,
@@ -418,10 +604,29 @@ pub mod value {
         }
     }
 
-    #[derive(Debug, Clone, Deserialize, Serialize)]
+    #[derive(Debug, Clone, Deserialize, Serialize, Hash, Eq, PartialEq)]
     pub struct LiveCellMap {
         pub number: BlockNumber,
         pub tx_index: u32,
+        /// Serialized packed::CellOutput (capacity, lock script, type script)
+        pub output: Bytes,
+        pub data_hash: H256,
+        pub data_size: u32,
+        /// Only keep the data of small cells (contract cells included)
+        pub data: Option<Bytes>,
+    }
+
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct TaintedContracts(pub Vec<TaintedContract>);
+
+    /// A contract touched by a skipped invalid transaction, its indexed state
+    /// is not trusted any more
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct TaintedContract {
+        pub address: ContractAddress,
+        pub number: BlockNumber,
+        pub tx_hash: H256,
+        pub reason: String,
     }
 
     /// For rollback
@@ -432,7 +637,7 @@ pub mod value {
         /// (lock_hash, tx_index, output_index)
         pub added_cells: Vec<(H256, u32, u32, LockLiveCell)>,
         /// (lock_hash, number, tx_index, output_index)
-        pub removed_cells: Vec<(H256, u64, u32, u32, LockLiveCell)>,
+        pub removed_cells: Vec<(H256, u64, u32, u32, LockLiveCell, LiveCellMap)>,
         /// (eoa_address)
         pub eoa_added_cells: Vec<H160>,
         pub eoa_removed_cells: Vec<(H160, EoaLiveCell)>,
@@ -441,9 +646,95 @@ pub mod value {
     }
 }
 
-fn db_get<K: AsRef<[u8]>, T: DeserializeOwned>(db: &DB, key: K) -> Result<Option<T>, String> {
-    db.get(key)
-        .map_err(|err| err.to_string())?
+/// Open the database with all column families, missing ones are created
+pub fn open_db<P: AsRef<Path>>(path: P) -> Result<DB, String> {
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    let mut descriptors = vec![ColumnFamilyDescriptor::new(
+        DEFAULT_COLUMN_FAMILY_NAME,
+        Options::default(),
+    )];
+    descriptors.extend(COLUMN_FAMILY_KEY_TYPES.iter().map(|key_type| {
+        ColumnFamilyDescriptor::new(key_type.column_family(), key_type.column_family_options())
+    }));
+    DB::open_cf_descriptors(&opts, path, descriptors).map_err(|err| err.to_string())
+}
+
+/// Delete all records, the schema version included
+pub fn clear_db(db: &dyn KvStore) -> Result<(), String> {
+    let mut batch = Batch::new(db);
+    for key_type in COLUMN_FAMILY_KEY_TYPES.iter() {
+        let key_type_byte = key_type.clone() as u8;
+        batch.delete_range(&[key_type_byte][..], &[key_type_byte + 1][..]);
+    }
+    batch.delete(&Bytes::from(&Key::Last));
+    batch.delete(&Bytes::from(&Key::TaintedContracts));
+    batch.delete(&Bytes::from(&Key::SchemaVersion));
+    batch.commit()
+}
+
+fn column_family(db: &DB, key_type: KeyType) -> &ColumnFamily {
+    db.cf_handle(key_type.column_family())
+        .expect("column family not opened")
+}
+
+// Keys are routed to their column family by the leading KeyType byte
+fn key_column_family<'a>(db: &'a DB, key: &[u8]) -> &'a ColumnFamily {
+    let key_type = key
+        .first()
+        .ok_or_else(|| String::from("empty key"))
+        .and_then(|byte| KeyType::try_from(*byte))
+        .expect("invalid key");
+    column_family(db, key_type)
+}
+
+/// Iterate keys of one type, seeks must stay in the prefix of the seek key
+fn db_iter(db: &dyn KvStore, key_type: KeyType) -> Box<dyn KvIterator + '_> {
+    db.iter(key_type)
+}
+
+/// A write batch, applied atomically on commit
+pub struct Batch<'a> {
+    db: &'a dyn KvStore,
+    ops: Vec<BatchOp>,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(db: &'a dyn KvStore) -> Batch<'a> {
+        Batch {
+            db,
+            ops: Vec::new(),
+        }
+    }
+
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.ops
+            .push(BatchOp::Put(key.as_ref().to_vec(), value.as_ref().to_vec()));
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.ops.push(BatchOp::Delete(key.as_ref().to_vec()));
+    }
+
+    /// Both keys must be of the same KeyType
+    pub fn delete_range<K: AsRef<[u8]>>(&mut self, from: K, to: K) {
+        self.ops.push(BatchOp::DeleteRange(
+            from.as_ref().to_vec(),
+            to.as_ref().to_vec(),
+        ));
+    }
+
+    pub fn commit(self) -> Result<(), String> {
+        self.db.write(self.ops)
+    }
+}
+
+fn db_get<K: AsRef<[u8]>, T: DeserializeOwned>(
+    db: &dyn KvStore,
+    key: K,
+) -> Result<Option<T>, String> {
+    db.get(key.as_ref())?
         .map(|value_bytes| deserialize(&value_bytes).map_err(|err| err.to_string()))
         .transpose()
 }
@@ -477,12 +768,23 @@ mod test {
                 tx_index: Some(4),
                 output_index: Some(55),
             },
-            Key::EoaLiveCell {
-                type_id_args: h256!("0x5342"),
-                anyone_can_pay_args: h160!("0xabcd"),
-            }
+            Key::EoaLiveCell(h160!("0xabcd")),
             Key::LiveCellMap(packed::OutPoint::default()),
+            Key::ContractState {
+                address: ContractAddress(h160!("0xab")),
+                storage_key: Some(h256!("0x01")),
+            },
+            Key::SmtBranch {
+                address: ContractAddress(h160!("0xab")),
+                node: h256!("0x02"),
+            },
+            Key::SmtLeaf {
+                address: ContractAddress(h160!("0xab")),
+                leaf_hash: h256!("0x03"),
+            },
+            Key::TaintedContracts,
             Key::BlockDelta(8),
+            Key::SchemaVersion,
         ] {
             let binary = Bytes::from(&key1);
             let key2 = Key::try_from(binary.as_ref()).unwrap();
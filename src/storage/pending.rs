@@ -0,0 +1,284 @@
+use ckb_simple_account_layer::CkbBlake2bHasher;
+use ckb_types::{bytes::Bytes, packed, prelude::*, H160, H256};
+use sparse_merkle_tree::{SparseMerkleTree, H256 as SmtH256};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{value, SmtStore};
+use crate::types::ContractAddress;
+
+/// Transactions handed out by polyjuice but not yet committed on chain.
+///
+/// New transactions are chained on the outputs of pending transactions, so
+/// the same sender can send several transactions before the first one is
+/// committed.
+#[derive(Clone)]
+pub struct PendingPool {
+    timeout: Duration,
+    // Ordered by insertion time, a transaction can only spend outputs of
+    // transactions before it.
+    txs: Vec<Arc<PendingTransaction>>,
+    // Recently dropped transactions, a transaction built before the drop
+    // may still spend their outputs.
+    dropped: Vec<(H256, Instant)>,
+    // Inputs of recently committed transactions
+    committed_inputs: Vec<(packed::OutPoint, Instant)>,
+}
+
+pub struct PendingTransaction {
+    pub tx_hash: H256,
+    pub inputs: Vec<packed::OutPoint>,
+    pub eoa_cells: Vec<(H160, PendingEoaCell)>,
+    pub contracts: Vec<(ContractAddress, PendingContract)>,
+    pub created_at: Instant,
+}
+
+#[derive(Clone)]
+pub struct PendingEoaCell {
+    pub cell: value::EoaLiveCell,
+    pub output: packed::CellOutput,
+    pub data: Bytes,
+}
+
+#[derive(Clone)]
+pub struct PendingContract {
+    pub out_point: packed::OutPoint,
+    pub output: packed::CellOutput,
+    pub data: Bytes,
+    /// Nodes of the storage tree, the root is in the cell data
+    pub tree_store: SmtStore,
+    pub balance: u64,
+    pub code: Bytes,
+    pub destructed: bool,
+}
+
+impl PendingContract {
+    pub fn merkle_tree(&self) -> SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore> {
+        let mut root = [0u8; 32];
+        root.copy_from_slice(&self.data[0..32]);
+        SparseMerkleTree::new(root.into(), self.tree_store.clone())
+    }
+}
+
+impl PendingPool {
+    pub fn new(timeout: Duration) -> PendingPool {
+        PendingPool {
+            timeout,
+            txs: Vec::new(),
+            dropped: Vec::new(),
+            committed_inputs: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, tx: PendingTransaction) {
+        self.prune();
+        log::debug!(
+            "add pending tx: {:#x}, pending count: {}",
+            tx.tx_hash,
+            self.txs.len() + 1
+        );
+        self.txs.push(Arc::new(tx));
+    }
+
+    /// Insert a transaction built on a copy of the pool. The transaction is
+    /// rejected if one of its inputs is spent by another pending or committed
+    /// transaction, or is an output of a pending transaction dropped since
+    /// then.
+    pub fn insert_checked(&mut self, tx: PendingTransaction) -> Result<(), String> {
+        self.prune();
+        for input in &tx.inputs {
+            let input_tx_hash: H256 = input.tx_hash().unpack();
+            let spent = self
+                .txs
+                .iter()
+                .any(|pending_tx| pending_tx.inputs.contains(input))
+                || self
+                    .committed_inputs
+                    .iter()
+                    .any(|(committed_input, _)| committed_input == input);
+            let dropped = self
+                .dropped
+                .iter()
+                .any(|(tx_hash, _)| tx_hash == &input_tx_hash);
+            if spent || dropped {
+                return Err(format!(
+                    "Conflict: cell {:#x}:{} was changed by another transaction, build the transaction again",
+                    input_tx_hash,
+                    Unpack::<u32>::unpack(&input.index())
+                ));
+            }
+        }
+        self.insert(tx);
+        Ok(())
+    }
+
+    /// The latest pending EoA cell of the address
+    pub fn eoa_cell(&mut self, address: &H160) -> Option<PendingEoaCell> {
+        self.prune();
+        self.txs.iter().rev().find_map(|tx| {
+            tx.eoa_cells
+                .iter()
+                .find(|(eoa_address, _)| eoa_address == address)
+                .map(|(_, cell)| cell.clone())
+        })
+    }
+
+    /// The latest pending state of the contract
+    pub fn contract(&mut self, address: &ContractAddress) -> Option<PendingContract> {
+        self.prune();
+        self.txs.iter().rev().find_map(|tx| {
+            tx.contracts
+                .iter()
+                .find(|(contract_address, _)| contract_address == address)
+                .map(|(_, contract)| contract.clone())
+        })
+    }
+
+    /// Called by the indexer for every committed transaction. Pending
+    /// transactions spending the same inputs are double spent, they are
+    /// dropped together with all transactions chained on them.
+    pub fn commit(&mut self, tx_hash: &H256, inputs: &[packed::OutPoint]) {
+        if let Some(index) = self.txs.iter().position(|tx| &tx.tx_hash == tx_hash) {
+            log::debug!("pending tx committed: {:#x}", tx_hash);
+            self.txs.remove(index);
+        }
+        let now = Instant::now();
+        self.committed_inputs
+            .extend(inputs.iter().map(|input| (input.clone(), now)));
+        let spent = inputs.iter().collect::<HashSet<_>>();
+        let conflicts = self
+            .txs
+            .iter()
+            .filter(|tx| tx.inputs.iter().any(|input| spent.contains(input)))
+            .map(|tx| tx.tx_hash.clone())
+            .collect::<Vec<_>>();
+        for conflict_tx_hash in conflicts {
+            log::info!("drop double spent pending tx: {:#x}", conflict_tx_hash);
+            self.remove_with_descendants(&conflict_tx_hash);
+        }
+    }
+
+    /// Drop a pending transaction which will never be sent, together with all
+    /// transactions chained on it.
+    pub fn remove(&mut self, tx_hash: &H256) {
+        log::debug!("remove pending tx: {:#x}", tx_hash);
+        self.remove_with_descendants(tx_hash);
+    }
+
+    fn prune(&mut self) {
+        let timeout = self.timeout;
+        let expired = self
+            .txs
+            .iter()
+            .filter(|tx| tx.created_at.elapsed() >= timeout)
+            .map(|tx| tx.tx_hash.clone())
+            .collect::<Vec<_>>();
+        for tx_hash in expired {
+            log::info!("drop timeout pending tx: {:#x}", tx_hash);
+            self.remove_with_descendants(&tx_hash);
+        }
+        self.dropped
+            .retain(|(_, dropped_at)| dropped_at.elapsed() < timeout);
+        self.committed_inputs
+            .retain(|(_, committed_at)| committed_at.elapsed() < timeout);
+    }
+
+    fn remove_with_descendants(&mut self, tx_hash: &H256) {
+        let mut removed = HashSet::new();
+        removed.insert(tx_hash.clone());
+        let mut remaining = Vec::new();
+        for tx in self.txs.drain(..) {
+            let is_descendant = tx.inputs.iter().any(|input| {
+                let input_tx_hash: H256 = input.tx_hash().unpack();
+                removed.contains(&input_tx_hash)
+            });
+            if removed.contains(&tx.tx_hash) || is_descendant {
+                removed.insert(tx.tx_hash.clone());
+            } else {
+                remaining.push(tx);
+            }
+        }
+        self.txs = remaining;
+        let now = Instant::now();
+        self.dropped
+            .extend(removed.into_iter().map(|tx_hash| (tx_hash, now)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ckb_types::{h160, h256};
+
+    fn pending_tx(tx_hash: H256, inputs: Vec<packed::OutPoint>) -> PendingTransaction {
+        let cell = PendingEoaCell {
+            cell: value::EoaLiveCell::new(tx_hash.clone(), 0, 0, 0),
+            output: packed::CellOutput::default(),
+            data: Bytes::default(),
+        };
+        PendingTransaction {
+            tx_hash,
+            inputs,
+            eoa_cells: vec![(h160!("0xab"), cell)],
+            contracts: Vec::new(),
+            created_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_pending_chain() {
+        let tx1_hash = h256!("0x1");
+        let tx2_hash = h256!("0x2");
+        let committed_input = packed::OutPoint::new(h256!("0x99").pack(), 0);
+        let mut pool = PendingPool::new(Duration::from_secs(60));
+        pool.insert(pending_tx(tx1_hash.clone(), vec![committed_input.clone()]));
+        pool.insert(pending_tx(
+            tx2_hash.clone(),
+            vec![packed::OutPoint::new(tx1_hash.pack(), 0)],
+        ));
+        let cell = pool.eoa_cell(&h160!("0xab")).unwrap();
+        assert_eq!(cell.cell.tx_hash, tx2_hash);
+
+        // tx1 committed, tx2 still pending
+        pool.commit(&tx1_hash, &[committed_input.clone()]);
+        let cell = pool.eoa_cell(&h160!("0xab")).unwrap();
+        assert_eq!(cell.cell.tx_hash, tx2_hash);
+
+        // Another transaction spent the same input, tx1 and tx2 are dropped
+        let mut pool = PendingPool::new(Duration::from_secs(60));
+        pool.insert(pending_tx(tx1_hash.clone(), vec![committed_input.clone()]));
+        pool.insert(pending_tx(
+            tx2_hash,
+            vec![packed::OutPoint::new(tx1_hash.pack(), 0)],
+        ));
+        pool.commit(&h256!("0x3"), &[committed_input]);
+        assert!(pool.eoa_cell(&h160!("0xab")).is_none());
+    }
+
+    #[test]
+    fn test_insert_checked() {
+        let tx1_hash = h256!("0x1");
+        let input = packed::OutPoint::new(h256!("0x99").pack(), 0);
+        let mut pool = PendingPool::new(Duration::from_secs(60));
+        // Built on the same copy of the pool
+        pool.insert_checked(pending_tx(tx1_hash.clone(), vec![input.clone()]))
+            .unwrap();
+        assert!(pool
+            .insert_checked(pending_tx(h256!("0x2"), vec![input.clone()]))
+            .is_err());
+
+        // Spends an output of a dropped transaction
+        pool.remove(&tx1_hash);
+        let tx1_output = packed::OutPoint::new(tx1_hash.pack(), 0);
+        assert!(pool
+            .insert_checked(pending_tx(h256!("0x3"), vec![tx1_output]))
+            .is_err());
+
+        // Spends a cell committed by another transaction
+        pool.commit(&h256!("0x4"), &[input.clone()]);
+        assert!(pool
+            .insert_checked(pending_tx(h256!("0x5"), vec![input]))
+            .is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_jsonrpc_types as json_types;
 use ckb_sdk::HumanCapacity;
 use ckb_simple_account_layer::{
     run_with_context, CkbBlake2bHasher, Config, RunContext, RunProofResult, RunResult,
@@ -8,7 +9,7 @@ use ckb_types::{
     core::{BlockView, Capacity, ScriptHashType, TransactionBuilder},
     h256,
     packed::{
-        Byte32, BytesOpt, CellInput, CellOutput, OutPoint, Script, ScriptOpt, Transaction,
+        Byte32, BytesOpt, CellDep, CellInput, CellOutput, OutPoint, Script, ScriptOpt, Transaction,
         WitnessArgs,
     },
     prelude::*,
@@ -19,17 +20,19 @@ use ckb_vm::{
     Error as VMError, Memory, Register, SupportMachine,
 };
 use numext_fixed_uint::prelude::UintConvert;
-use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree, H256 as SmtH256};
+use sparse_merkle_tree::{SparseMerkleTree, H256 as SmtH256};
 use std::collections::{BTreeMap, HashSet};
 use std::convert::TryFrom;
 use std::error::Error as StdError;
+use std::time::Instant;
 
-use super::{value, Loader};
+use super::indexer::{eoa_record, is_eoa};
+use super::{value, Loader, PendingContract, PendingEoaCell, PendingTransaction, SmtStore};
 use crate::types::{
-    h256_to_smth256, parse_log, smth256_to_h256, vm_load_data, vm_load_h160, vm_load_h256,
-    vm_load_i32, vm_load_i64, vm_load_u256, vm_load_u32, vm_load_u8, CallKind, CallRecord,
-    Coinbase, ContractAddress, ContractCell, EoaAddress, Program, RunConfig, WitnessData,
-    ALWAYS_SUCCESS_SCRIPT, ONE_CKB, SIGHASH_CELL_DEP,
+    h256_to_smth256, parse_log, same_script_code, smth256_to_h256, vm_load_data, vm_load_h160,
+    vm_load_h256, vm_load_i32, vm_load_i64, vm_load_u256, vm_load_u32, vm_load_u8, CallKind,
+    CallRecord, Coinbase, ContractAddress, ContractCell, EoaAddress, EoaLockKind, Program,
+    RunConfig, WitnessData, ONE_CKB,
 };
 
 pub struct Runner {
@@ -48,27 +51,19 @@ impl Runner {
         destination: ContractAddress,
         input: Bytes,
     ) -> Result<CsalRunContext, Box<dyn StdError>> {
-        let meta = self.loader.load_contract_meta(destination.clone())?;
-        if meta.destructed {
-            return Err(format!("Contract already destructed: {:x}", destination.0).into());
-        }
+        let code = self.loader.load_contract_code(&destination)?;
         let program = Program::new_call(
             EoaAddress(sender.clone()),
             sender,
             destination.0,
-            meta.code,
+            code,
             input,
             0,
             false,
         );
 
-        let tip_block = self.loader.load_block(None)?;
-        let mut context =
-            CsalRunContext::new(self.loader.clone(), self.run_config.clone(), tip_block);
-        if let Err(err) = context.run(program) {
-            log::warn!("Error: {:?}", err);
-            return Err(err);
-        }
+        let mut context = self.new_context()?;
+        context.run_entrance(program)?;
         // TODO: merge with context
         Ok(context)
     }
@@ -80,27 +75,8 @@ impl Runner {
         input: Bytes,
         value: u64,
     ) -> Result<CsalRunContext, Box<dyn StdError>> {
-        let meta = self.loader.load_contract_meta(destination.clone())?;
-        if meta.destructed {
-            return Err(format!("Contract already destructed: {:x}", destination.0).into());
-        }
-        let program = Program::new_call(
-            EoaAddress(sender.clone()),
-            sender,
-            destination.0,
-            meta.code,
-            input,
-            value,
-            false,
-        );
-
-        let tip_block = self.loader.load_block(None)?;
-        let mut context =
-            CsalRunContext::new(self.loader.clone(), self.run_config.clone(), tip_block);
-        if let Err(err) = context.run(program) {
-            log::warn!("Error: {:?}", err);
-            return Err(err);
-        }
+        let mut context = self.new_context()?;
+        context.call(sender, destination, input, value)?;
         Ok(context)
     }
 
@@ -110,21 +86,26 @@ impl Runner {
         code: Bytes,
         value: u64,
     ) -> Result<CsalRunContext, Box<dyn StdError>> {
-        let program = Program::new_create(EoaAddress(sender.clone()), sender, code, value);
-        let tip_block = self.loader.load_block(None)?;
-        let mut context =
-            CsalRunContext::new(self.loader.clone(), self.run_config.clone(), tip_block);
-        if let Err(err) = context.run(program) {
-            log::warn!("Error: {:?}", err);
-            return Err(err);
-        }
+        let mut context = self.new_context()?;
+        context.create(sender, code, value)?;
         Ok(context)
     }
+
+    // An empty context on the tip block, every create/call on it is an
+    // entrance program of the same transaction
+    pub fn new_context(&mut self) -> Result<CsalRunContext, Box<dyn StdError>> {
+        let tip_block = self.loader.load_block(None)?;
+        Ok(CsalRunContext::new(
+            self.loader.clone(),
+            self.run_config.clone(),
+            tip_block,
+        ))
+    }
 }
 
 pub struct ContractInfo {
     pub address: ContractAddress,
-    pub tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, DefaultStore<SmtH256>>,
+    pub tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore>,
     pub code: Bytes,
     // input and selfdestruct can not both empty (invoke selfdestruct in a constructor?)
     pub input: Option<ContractInput>,
@@ -173,16 +154,19 @@ pub struct ExecuteRecord {
     pub run_proof: Bytes,
 
     pub calls: Vec<CallRecord>,
+    // The entrance program this program is run by
+    pub entrance_index: usize,
 }
 
 impl ExecuteRecord {
-    pub fn new(program: Program) -> ExecuteRecord {
+    pub fn new(program: Program, entrance_index: usize) -> ExecuteRecord {
         ExecuteRecord {
             program,
             logs: Vec::new(),
             return_data: Bytes::default(),
             run_proof: Bytes::default(),
             calls: Vec::new(),
+            entrance_index,
         }
     }
 
@@ -225,7 +209,7 @@ impl ContractInfo {
         address: ContractAddress,
         input: Option<ContractInput>,
         init_balance: u64,
-        tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, DefaultStore<SmtH256>>,
+        tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore>,
     ) -> ContractInfo {
         log::info!("ContractInfo::new(address: {:x})", address.0);
         ContractInfo {
@@ -260,12 +244,13 @@ impl ContractInfo {
         self.balance_changed = true;
     }
 
-    fn capacity(&self) -> u64 {
+    // The lock script of the cell to create if there is no input
+    fn capacity(&self, lock_script: &Script) -> u64 {
         let init_capacity = self
             .input
             .as_ref()
             .map(|input| input.capacity())
-            .unwrap_or_else(contract_cell_min_capacity);
+            .unwrap_or_else(|| contract_cell_min_capacity(lock_script));
         init_capacity + self.balance - self.init_balance
     }
 
@@ -356,11 +341,12 @@ impl ContractInfo {
         !self.execute_records.is_empty() && self.execute_records[0].program.is_create()
     }
 
-    pub fn add_record(&mut self, program: Program) {
+    pub fn add_record(&mut self, program: Program, entrance_index: usize) {
         if !program.is_create() && self.code.is_empty() {
             self.code = program.code.clone();
         }
-        self.execute_records.push(ExecuteRecord::new(program));
+        self.execute_records
+            .push(ExecuteRecord::new(program, entrance_index));
         self.execute_index = self.execute_records.len();
     }
 
@@ -380,6 +366,12 @@ impl ContractInfo {
     }
 }
 
+/// The return data and logs of an entrance program
+pub struct EntranceResult {
+    pub return_data: Bytes,
+    pub logs: Vec<(ContractAddress, Vec<H256>, Bytes)>,
+}
+
 pub struct CsalRunContext {
     pub loader: Loader,
     pub run_config: RunConfig,
@@ -394,8 +386,8 @@ pub struct CsalRunContext {
     pub other_eoa_outputs: BTreeMap<H160, (CellOutput, Bytes)>,
     // First contract input cell (when kind.is_call())
     pub first_contract_input: Option<ContractInput>,
-    // The entrance program
-    pub entrance_program: Option<Program>,
+    // The entrance programs, all of them are sent to the entrance contract
+    pub entrance_programs: Vec<Program>,
     // Current running contract
     contract_index: usize,
     contracts: Vec<(ContractAddress, ContractInfo)>,
@@ -419,7 +411,7 @@ impl CsalRunContext {
             other_eoa_cells: Default::default(),
             other_eoa_outputs: Default::default(),
             first_contract_input: None,
-            entrance_program: None,
+            entrance_programs: Vec::new(),
             contract_index: 0,
             contracts: Vec::new(),
             state_changed: false,
@@ -428,14 +420,20 @@ impl CsalRunContext {
     }
 
     pub fn is_static(&self) -> bool {
-        self.entrance_program
-            .as_ref()
+        self.entrance_programs
+            .first()
             .map(|program| program.flags == 1)
             .unwrap_or(false)
     }
 
     pub fn tx_origin_input(&self) -> CellInput {
-        CellInput::new(self.tx_origin_cell.out_point(), 0)
+        let lock_script = self.tx_origin_output.0.lock();
+        let since = self
+            .run_config
+            .eoa_lock(&lock_script)
+            .map(|eoa_lock| eoa_lock.kind.input_since(&lock_script.args().raw_data()))
+            .unwrap_or(0);
+        CellInput::new(self.tx_origin_cell.out_point(), since)
     }
 
     fn state_changed(&self) -> bool {
@@ -453,12 +451,35 @@ impl CsalRunContext {
         let tx_fee = ONE_CKB;
         // Setup cell_deps
         // TODO: fill load all inputs' headers as dependencies
-        let cell_deps = vec![
-            SIGHASH_CELL_DEP.clone(),
+        let mut cell_deps = vec![
+            self.run_config.sighash_dep.clone(),
             self.run_config.type_dep.clone(),
             self.run_config.lock_dep.clone(),
             self.run_config.eoa_lock_dep.clone(),
         ];
+        // Contract cells created under the legacy locks
+        for (address, info) in &self.contracts {
+            let lock_script = match info.input.as_ref() {
+                Some(input) => input.output.lock(),
+                None => continue,
+            };
+            if same_script_code(&lock_script, &self.run_config.lock_script) {
+                continue;
+            }
+            let lock_dep = self
+                .run_config
+                .legacy_locks
+                .iter()
+                .find(|(_, script)| same_script_code(&lock_script, script))
+                .map(|(dep, _)| dep.clone())
+                .ok_or_else(|| format!("Unknown lock of contract {:x}", address.0))?;
+            push_cell_dep(&mut cell_deps, lock_dep);
+        }
+        // The lock of tx_origin may be any of the EoA locks, other EoA cells
+        // are anyone-can-pay
+        if let Some(eoa_lock) = self.run_config.eoa_lock(&self.tx_origin_output.0.lock()) {
+            push_cell_dep(&mut cell_deps, eoa_lock.dep);
+        }
 
         // Collect inputs
         let other_inputs: Vec<CellInput> = self
@@ -528,12 +549,12 @@ impl CsalRunContext {
                                 .output
                                 .clone()
                                 .as_builder()
-                                .capacity(info.capacity().pack())
+                                .capacity(info.capacity(&self.run_config.lock_script).pack())
                                 .build()
                         })
                         .unwrap_or_else(|| {
                             // Create contract
-                            let contract_lock_script = ALWAYS_SUCCESS_SCRIPT.clone();
+                            let contract_lock_script = self.run_config.lock_script.clone();
                             let contract_type_script = self
                                 .run_config
                                 .type_script
@@ -579,9 +600,10 @@ impl CsalRunContext {
         log::debug!("create_contracts_count: {}", create_contracts_count);
         log::debug!(
             "contract_cell_min_capacity(): {}",
-            HumanCapacity(contract_cell_min_capacity())
+            HumanCapacity(contract_cell_min_capacity(&self.run_config.lock_script))
         );
-        let addition_capacity = tx_fee + create_contracts_count * contract_cell_min_capacity();
+        let addition_capacity = tx_fee
+            + create_contracts_count * contract_cell_min_capacity(&self.run_config.lock_script);
         if self.tx_origin_cell.balance() < addition_capacity {
             return Err(format!(
                 "tx_origin don't have enough capacity for transaction, {} < {}",
@@ -712,42 +734,96 @@ impl CsalRunContext {
                 Ok((input_opt, tree, info.balance))
             })
             .unwrap_or_else(|| {
-                let change = self.loader.load_latest_contract_change(
-                    info_address.clone(),
-                    None,
-                    false,
-                    false,
-                )?;
-                let (output, data) = self
-                    .loader
-                    .load_contract_live_cell(change.tx_hash.clone(), change.output_index)?;
-                let input = ContractInput::new(change.out_point(), output, data);
-                Ok((Some(input), change.merkle_tree(), change.balance))
+                let (input, tree, balance) = self.load_contract_state(&info_address)?;
+                Ok((Some(input), tree, balance))
             })?;
 
         let empty_run_proof = Bytes::from(RunProofResult::default().serialize_pure().unwrap());
         log::debug!("empty_run_proof: {}", hex::encode(&empty_run_proof));
+        let entrance_index = self.entrance_index();
         if let Some(contract_index) = self.get_contract_index(&info_address) {
             self.contract_index = contract_index;
             let info = &mut self.contracts[contract_index].1;
-            info.add_record(program);
+            info.add_record(program, entrance_index);
             info.current_record_mut().run_proof = empty_run_proof;
         } else {
             self.contract_index = self.contracts.len();
             let mut info =
                 ContractInfo::new(info_address.clone(), contract_input_opt, balance, tree);
-            info.add_record(program);
+            info.add_record(program, entrance_index);
             info.current_record_mut().run_proof = empty_run_proof;
             self.contracts.push((info_address, info));
         }
         Ok(())
     }
 
-    pub fn run(&mut self, mut program: Program) -> Result<(), Box<dyn StdError>> {
-        if self.contracts.is_empty() {
+    /// Run a CREATE program as an entrance program of the transaction
+    pub fn create(
+        &mut self,
+        sender: H160,
+        code: Bytes,
+        value: u64,
+    ) -> Result<(), Box<dyn StdError>> {
+        let program = Program::new_create(EoaAddress(sender.clone()), sender, code, value);
+        self.run_entrance(program)
+    }
+
+    /// Run a CALL program as an entrance program of the transaction
+    pub fn call(
+        &mut self,
+        sender: H160,
+        destination: ContractAddress,
+        input: Bytes,
+        value: u64,
+    ) -> Result<(), Box<dyn StdError>> {
+        // The contract may be created by an earlier entrance program
+        let code = self.get_contract_code(&destination)?;
+        let program = Program::new_call(
+            EoaAddress(sender.clone()),
+            sender,
+            destination.0,
+            code,
+            input,
+            value,
+            false,
+        );
+        self.run_entrance(program)
+    }
+
+    // The validator only accepts entrance programs of one contract, so only
+    // the first entrance program can be a CREATE program
+    fn run_entrance(&mut self, program: Program) -> Result<(), Box<dyn StdError>> {
+        if let Some(first_program) = self.entrance_programs.first() {
+            if program.is_create() {
+                return Err(String::from("CREATE must be the first entrance program").into());
+            }
+            let entrance_contract = self.entrance_contract();
+            if program.destination != entrance_contract.0 {
+                return Err(format!(
+                    "entrance programs must call the entrance contract {:x}, got {:x}",
+                    entrance_contract.0, program.destination
+                )
+                .into());
+            }
+            if program.sender != first_program.sender {
+                return Err(format!(
+                    "entrance programs must be sent by {:x}, got {:x}",
+                    first_program.sender, program.sender
+                )
+                .into());
+            }
+            self.entrance_programs.push(program.clone());
+        } else {
             self.set_entrance_program(program.clone())?;
         }
+        if let Err(err) = self.run(program) {
+            log::warn!("Error: {:?}", err);
+            return Err(err);
+        }
+        Ok(())
+    }
 
+    pub fn run(&mut self, mut program: Program) -> Result<(), Box<dyn StdError>> {
         let mut info_address = match program.kind {
             CallKind::CALL | CallKind::CREATE | CallKind::CREATE2 => {
                 ContractAddress(program.destination.clone())
@@ -768,17 +844,8 @@ impl CsalRunContext {
                     Ok((input_opt, tree, info.balance))
                 })
                 .unwrap_or_else(|| {
-                    let change = self.loader.load_latest_contract_change(
-                        info_address.clone(),
-                        None,
-                        false,
-                        false,
-                    )?;
-                    let (output, data) = self
-                        .loader
-                        .load_contract_live_cell(change.tx_hash.clone(), change.output_index)?;
-                    let input = ContractInput::new(change.out_point(), output, data);
-                    Ok((Some(input), change.merkle_tree(), change.balance))
+                    let (input, tree, balance) = self.load_contract_state(&info_address)?;
+                    Ok((Some(input), tree, balance))
                 })?
         };
         let destination = self.destination(&program, self.contracts.len() as u64);
@@ -789,15 +856,16 @@ impl CsalRunContext {
         }
 
         log::debug!("[contract]: {:x}", info_address.0);
+        let entrance_index = self.entrance_index();
         if let Some(contract_index) = self.get_contract_index(&info_address) {
             self.contract_index = contract_index;
             let info = &mut self.contracts[contract_index].1;
-            info.add_record(program.clone());
+            info.add_record(program.clone(), entrance_index);
         } else {
             self.contract_index = self.contracts.len();
             let mut info =
                 ContractInfo::new(info_address.clone(), contract_input_opt, balance, tree);
-            info.add_record(program.clone());
+            info.add_record(program.clone(), entrance_index);
             self.contracts.push((info_address, info));
         }
 
@@ -905,34 +973,162 @@ impl CsalRunContext {
 
     pub fn set_entrance_program(&mut self, program: Program) -> Result<(), Box<dyn StdError>> {
         if program.kind.is_call() {
-            let latest_change = self.loader.load_latest_contract_change(
-                ContractAddress(program.destination.clone()),
-                None,
-                false,
-                false,
-            )?;
-
-            let out_point = OutPoint::new(latest_change.tx_hash.pack(), latest_change.output_index);
-            let (contract_live_cell, latest_contract_data) = self.loader.load_contract_live_cell(
-                latest_change.tx_hash.clone(),
-                latest_change.output_index,
-            )?;
-            self.first_contract_input = Some(ContractInput::new(
-                out_point,
-                contract_live_cell,
-                latest_contract_data,
-            ));
+            let (input, _tree, _balance) =
+                self.load_contract_state(&ContractAddress(program.destination.clone()))?;
+            self.first_contract_input = Some(input);
         }
         log::info!("> tx_origin: {:x}", program.sender);
         self.tx_origin = EoaAddress(program.sender.clone());
-        let (eoa_live_cell, output, output_data) =
-            self.loader.load_eoa_live_cell(program.sender.clone())?;
+        let (eoa_live_cell, output, output_data) = self
+            .loader
+            .load_spendable_eoa_cell(program.sender.clone())?;
         self.tx_origin_cell = eoa_live_cell;
         self.tx_origin_output = (output, output_data);
-        self.entrance_program = Some(program);
+        self.entrance_programs.push(program);
         Ok(())
     }
 
+    // The index of the running entrance program
+    fn entrance_index(&self) -> usize {
+        self.entrance_programs.len().saturating_sub(1)
+    }
+
+    // Load the latest state of a contract, the output of a pending transaction
+    // take precedence over the indexed contract change.
+    fn load_contract_state(
+        &mut self,
+        address: &ContractAddress,
+    ) -> Result<
+        (
+            ContractInput,
+            SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore>,
+            u64,
+        ),
+        String,
+    > {
+        if let Some(pending) = self.loader.load_pending_contract(address) {
+            if pending.destructed {
+                return Err(format!("Contract already destructed: {:x}", address.0));
+            }
+            log::debug!("use pending contract cell: {:x}", address.0);
+            let tree = pending.merkle_tree();
+            let input = ContractInput::new(pending.out_point, pending.output, pending.data);
+            return Ok((input, tree, pending.balance));
+        }
+        let change =
+            self.loader
+                .load_latest_contract_change(address.clone(), None, false, false)?;
+        let (output, data) = self
+            .loader
+            .load_contract_live_cell(change.tx_hash.clone(), change.output_index)?;
+        if data.len() < 32 {
+            return Err(format!(
+                "Invalid contract cell data of {:x}: {}",
+                address.0,
+                hex::encode(&data)
+            ));
+        }
+        let storage_root = H256::from_slice(&data[0..32]).expect("storage root");
+        let tree = self
+            .loader
+            .load_contract_tree(address.clone(), &storage_root);
+        let input = ContractInput::new(change.out_point(), output, data);
+        Ok((input, tree, change.balance))
+    }
+
+    // The outputs of the built transaction, later transactions are chained on
+    // them before this transaction is committed.
+    pub fn pending_transaction(&self, tx: &Transaction) -> PendingTransaction {
+        let tx_hash: H256 = tx.calc_tx_hash().unpack();
+        let raw_tx = tx.raw();
+        let inputs = raw_tx
+            .inputs()
+            .into_iter()
+            .map(|input| input.previous_output())
+            .collect::<Vec<_>>();
+        let outputs = raw_tx
+            .outputs()
+            .into_iter()
+            .zip(raw_tx.outputs_data().into_iter())
+            .collect::<Vec<_>>();
+
+        let mut eoa_cells = Vec::new();
+        for (output_index, (output, data)) in outputs.iter().enumerate() {
+            let json_output = json_types::CellOutput::from(output.clone());
+            let type_script = json_output.type_.clone().unwrap_or_default();
+            if is_eoa(&type_script, &json_output.lock, &self.run_config) {
+                let data = data.raw_data();
+                let (eoa_address, cell) = eoa_record(
+                    &type_script,
+                    &json_output.lock,
+                    &tx_hash,
+                    output_index as u32,
+                    &json_output,
+                    data.len() as u32,
+                );
+                let pending_cell = PendingEoaCell {
+                    cell,
+                    output: output.clone(),
+                    data,
+                };
+                eoa_cells.push((eoa_address, pending_cell));
+            }
+        }
+
+        let contracts = self
+            .contracts
+            .iter()
+            .filter_map(|(address, info)| {
+                if info.selfdestruct.is_some() {
+                    let pending_contract = PendingContract {
+                        out_point: OutPoint::default(),
+                        output: CellOutput::default(),
+                        data: Bytes::default(),
+                        tree_store: SmtStore::default(),
+                        balance: 0,
+                        code: info.code.clone(),
+                        destructed: true,
+                    };
+                    return Some((address.clone(), pending_contract));
+                }
+                let type_script_hash = self
+                    .run_config
+                    .type_script
+                    .clone()
+                    .as_builder()
+                    .args(Bytes::from(address.0.as_bytes().to_vec()).pack())
+                    .build()
+                    .calc_script_hash();
+                let output_index = outputs.iter().position(|(output, _)| {
+                    output
+                        .type_()
+                        .to_opt()
+                        .map(|script| script.calc_script_hash())
+                        == Some(type_script_hash.clone())
+                })?;
+                let (output, data) = &outputs[output_index];
+                let pending_contract = PendingContract {
+                    out_point: OutPoint::new(tx_hash.pack(), output_index as u32),
+                    output: output.clone(),
+                    data: data.raw_data(),
+                    tree_store: info.tree.store().clone(),
+                    balance: info.balance,
+                    code: info.code.clone(),
+                    destructed: false,
+                };
+                Some((address.clone(), pending_contract))
+            })
+            .collect::<Vec<_>>();
+
+        PendingTransaction {
+            tx_hash,
+            inputs,
+            eoa_cells,
+            contracts,
+            created_at: Instant::now(),
+        }
+    }
+
     pub fn first_cell_input(&self) -> (CellInput, u64) {
         if let Some(ref input) = self.first_contract_input {
             (input.cell_input(), input.capacity())
@@ -993,16 +1189,37 @@ impl CsalRunContext {
             })
     }
 
+    pub fn entrance_results(&self) -> Result<Vec<EntranceResult>, String> {
+        let mut results = self
+            .entrance_info()
+            .execute_records
+            .iter()
+            .filter(|record| record.program.depth == 0)
+            .map(|record| EntranceResult {
+                return_data: record.return_data.clone(),
+                logs: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        for (addr, info) in &self.contracts {
+            for record in &info.execute_records {
+                let result = results.get_mut(record.entrance_index).ok_or_else(|| {
+                    format!("entrance program {} not found", record.entrance_index)
+                })?;
+                for log_data in &record.logs {
+                    let (topics, data) = parse_log(log_data)?;
+                    result.logs.push((addr.clone(), topics, data));
+                }
+            }
+        }
+        Ok(results)
+    }
+
     pub fn get_contract_code(&self, address: &ContractAddress) -> Result<Bytes, String> {
         self.get_contract_info(address)
             .map(|info| info.code.clone())
             .filter(|code| !code.is_empty())
             .map(Ok)
-            .unwrap_or_else(|| {
-                self.loader
-                    .load_contract_meta(address.clone())
-                    .map(|meta| meta.code)
-            })
+            .unwrap_or_else(|| self.loader.load_contract_code(address))
     }
 
     pub fn get_contract_index(&self, address: &ContractAddress) -> Option<usize> {
@@ -1034,7 +1251,18 @@ impl CsalRunContext {
         }
 
         if !self.other_eoa_cells.contains_key(address) {
-            let (cell, output, output_data) = self.loader.load_eoa_live_cell(address.clone())?;
+            let (cell, output, output_data) =
+                self.loader.load_spendable_eoa_cell(address.clone())?;
+            let kind = self
+                .run_config
+                .eoa_lock(&output.lock())
+                .map(|eoa_lock| eoa_lock.kind);
+            if kind.map(EoaLockKind::can_receive) != Some(true) {
+                return Err(format!(
+                    "EoA {:x} can not receive without signature, lock: {:?}",
+                    address, kind
+                ));
+            }
             self.other_eoa_cells.insert(address.clone(), cell);
             self.other_eoa_outputs
                 .insert(address.clone(), (output, output_data));
@@ -1163,7 +1391,9 @@ impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
                     ));
                     VMError::IO(std::io::ErrorKind::InvalidInput)
                 })?;
-                let capacity = self.current_contract_info().capacity();
+                let capacity = self
+                    .current_contract_info()
+                    .capacity(&self.run_config.lock_script);
                 // FIXME: selfdestruct beneficiary is a contract account
                 let mut error_message = None;
                 match self.get_eoa_cell_mut(&address) {
@@ -1474,6 +1704,9 @@ impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
                 let balance_u64: u64 = if let Some(info) = self.get_contract_info(&info_address) {
                     // get balance from current related contract account
                     info.balance
+                } else if let Some(pending) = self.loader.load_pending_contract(&info_address) {
+                    // get balance from current unrelated pending contract account
+                    pending.balance
                 } else if let Ok(meta) = self.loader.load_contract_meta(info_address.clone()) {
                     // get balance from current unrelated(unchanged) contract account
                     meta.balance
@@ -1511,15 +1744,23 @@ impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
     }
 }
 
+fn push_cell_dep(cell_deps: &mut Vec<CellDep>, cell_dep: CellDep) {
+    if cell_deps
+        .iter()
+        .all(|dep| dep.as_slice() != cell_dep.as_slice())
+    {
+        cell_deps.push(cell_dep);
+    }
+}
+
 // Contract cell's min (occupied) capacity
-fn contract_cell_min_capacity() -> u64 {
+fn contract_cell_min_capacity(lock_script: &Script) -> u64 {
     let type_script = Script::new_builder()
         .args(Bytes::from(H160::default().as_bytes().to_vec()).pack())
         .build();
-    let lock_script = Script::new_builder().build();
     let output = CellOutput::new_builder()
         .type_(ScriptOpt::new_builder().set(Some(type_script)).build())
-        .lock(lock_script)
+        .lock(lock_script.clone())
         .capacity(0.pack())
         .build();
     let data_capacity = Capacity::shannons((32 + 32) * ONE_CKB);
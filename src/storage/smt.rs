@@ -0,0 +1,289 @@
+use bincode::serialize;
+use ckb_simple_account_layer::CkbBlake2bHasher;
+use ckb_types::{bytes::Bytes, H256};
+use sparse_merkle_tree::{
+    error::Error,
+    traits::Store,
+    tree::{BranchNode, LeafNode},
+    SparseMerkleTree, H256 as SmtH256,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use super::{db_get, Batch, Key, KeyType, KvStore};
+use crate::types::{h256_to_smth256, smth256_to_h256, ContractAddress};
+
+// (fork_height, key, node, sibling)
+type BranchValue = (u8, [u8; 32], [u8; 32], [u8; 32]);
+// (key, value)
+type LeafValue = ([u8; 32], [u8; 32]);
+
+/// A sparse merkle tree store reading the persisted nodes of a contract
+/// lazily. New nodes are kept in memory until the indexer writes them.
+///
+/// Only the tree of the latest root is persisted, nodes replaced by an
+/// update are deleted when the nodes are written. Removed nodes are still
+/// readable in memory, so the input tree of a transaction can be loaded
+/// after the update. A rollback rebuilds the tree, see `rebuild_nodes`.
+#[derive(Clone, Default)]
+pub struct SmtStore {
+    // None for contracts created in memory
+    db: Option<(Arc<dyn KvStore>, ContractAddress)>,
+    branches: HashMap<SmtH256, BranchNode>,
+    leaves: HashMap<SmtH256, LeafNode<SmtH256>>,
+    removed_branches: HashSet<SmtH256>,
+    removed_leaves: HashSet<SmtH256>,
+}
+
+impl SmtStore {
+    pub fn new(db: Arc<dyn KvStore>, address: ContractAddress) -> SmtStore {
+        SmtStore {
+            db: Some((db, address)),
+            ..Default::default()
+        }
+    }
+
+    /// The persisted tree of the contract at the root
+    pub fn load_tree(
+        db: Arc<dyn KvStore>,
+        address: ContractAddress,
+        root: &H256,
+    ) -> SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore> {
+        SparseMerkleTree::new(h256_to_smth256(root), SmtStore::new(db, address))
+    }
+
+    /// Write the nodes created in memory, delete the removed ones
+    pub fn write_nodes(&self, batch: &mut Batch, address: &ContractAddress) {
+        for node in &self.removed_branches {
+            batch.delete(&Bytes::from(&Key::SmtBranch {
+                address: address.clone(),
+                node: smth256_to_h256(node),
+            }));
+        }
+        for leaf_hash in &self.removed_leaves {
+            batch.delete(&Bytes::from(&Key::SmtLeaf {
+                address: address.clone(),
+                leaf_hash: smth256_to_h256(leaf_hash),
+            }));
+        }
+        for (node, branch) in &self.branches {
+            if self.removed_branches.contains(node) {
+                continue;
+            }
+            let key = Key::SmtBranch {
+                address: address.clone(),
+                node: smth256_to_h256(node),
+            };
+            let value: BranchValue = (
+                branch.fork_height,
+                to_array(&branch.key),
+                to_array(&branch.node),
+                to_array(&branch.sibling),
+            );
+            batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
+        }
+        for (leaf_hash, leaf) in &self.leaves {
+            if self.removed_leaves.contains(leaf_hash) {
+                continue;
+            }
+            let key = Key::SmtLeaf {
+                address: address.clone(),
+                leaf_hash: smth256_to_h256(leaf_hash),
+            };
+            let value: LeafValue = (to_array(&leaf.key), to_array(&leaf.value));
+            batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
+        }
+    }
+
+    /// Replace all persisted nodes of a contract by the tree of the storage,
+    /// return the root. Used when the contract is rolled back to an older
+    /// root, an empty storage only deletes the nodes.
+    pub fn rebuild_nodes(
+        batch: &mut Batch,
+        address: &ContractAddress,
+        storage: &HashMap<H256, H256>,
+    ) -> Result<H256, String> {
+        for key_type in &[KeyType::SmtBranch, KeyType::SmtLeaf] {
+            let mut from = vec![key_type.clone() as u8];
+            from.extend(address.0.as_bytes());
+            // Node keys are 32 bytes
+            let mut to = from.clone();
+            to.extend(&[0xffu8; 33][..]);
+            batch.delete_range(&from, &to);
+        }
+        let mut tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore> =
+            SparseMerkleTree::default();
+        for (key, value) in storage {
+            tree.update(h256_to_smth256(key), h256_to_smth256(value))
+                .map_err(|err| err.to_string())?;
+        }
+        tree.store().write_nodes(batch, address);
+        Ok(smth256_to_h256(tree.root()))
+    }
+}
+
+impl Store<SmtH256> for SmtStore {
+    fn get_branch(&self, node: &SmtH256) -> Result<Option<BranchNode>, Error> {
+        if let Some(branch) = self.branches.get(node) {
+            return Ok(Some(branch.clone()));
+        }
+        let (db, address) = match self.db.as_ref() {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let key = Key::SmtBranch {
+            address: address.clone(),
+            node: smth256_to_h256(node),
+        };
+        let value: Option<BranchValue> = db_get(db, &Bytes::from(&key)).map_err(Error::Store)?;
+        Ok(value.map(|(fork_height, key, node, sibling)| BranchNode {
+            fork_height,
+            key: key.into(),
+            node: node.into(),
+            sibling: sibling.into(),
+        }))
+    }
+
+    fn get_leaf(&self, leaf_hash: &SmtH256) -> Result<Option<LeafNode<SmtH256>>, Error> {
+        if let Some(leaf) = self.leaves.get(leaf_hash) {
+            return Ok(Some(leaf.clone()));
+        }
+        let (db, address) = match self.db.as_ref() {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let key = Key::SmtLeaf {
+            address: address.clone(),
+            leaf_hash: smth256_to_h256(leaf_hash),
+        };
+        let value: Option<LeafValue> = db_get(db, &Bytes::from(&key)).map_err(Error::Store)?;
+        Ok(value.map(|(key, value)| LeafNode {
+            key: key.into(),
+            value: value.into(),
+        }))
+    }
+
+    fn insert_branch(&mut self, node: SmtH256, branch: BranchNode) -> Result<(), Error> {
+        self.removed_branches.remove(&node);
+        self.branches.insert(node, branch);
+        Ok(())
+    }
+
+    fn insert_leaf(&mut self, leaf_hash: SmtH256, leaf: LeafNode<SmtH256>) -> Result<(), Error> {
+        self.removed_leaves.remove(&leaf_hash);
+        self.leaves.insert(leaf_hash, leaf);
+        Ok(())
+    }
+
+    // Kept in memory until written, the input tree may still read them
+    fn remove_branch(&mut self, node: &SmtH256) -> Result<(), Error> {
+        self.removed_branches.insert(*node);
+        Ok(())
+    }
+
+    fn remove_leaf(&mut self, leaf_hash: &SmtH256) -> Result<(), Error> {
+        self.removed_leaves.insert(*leaf_hash);
+        Ok(())
+    }
+}
+
+fn to_array(hash: &SmtH256) -> [u8; 32] {
+    let mut buf = [0u8; 32];
+    buf.copy_from_slice(hash.as_slice());
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::MemoryStore;
+    use super::*;
+    use ckb_types::{h160, h256};
+
+    fn node_keys(db: &dyn KvStore) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        for key_type in &[KeyType::SmtBranch, KeyType::SmtLeaf] {
+            let mut iter = db.iter(key_type.clone());
+            iter.seek_to_first();
+            while iter.valid() {
+                keys.push(iter.key().unwrap().to_vec());
+                iter.next();
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn test_load_persisted_tree() {
+        let db: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let address = ContractAddress(h160!("0x1"));
+        let (key1, key2) = (h256!("0x1"), h256!("0x2"));
+        let mut tree = SmtStore::load_tree(Arc::clone(&db), address.clone(), &H256::default());
+        tree.update(h256_to_smth256(&key1), h256_to_smth256(&h256!("0x11")))
+            .unwrap();
+        let mut batch = Batch::new(db.as_ref());
+        tree.store().write_nodes(&mut batch, &address);
+        batch.commit().unwrap();
+        let old_root = smth256_to_h256(tree.root());
+
+        let mut tree = SmtStore::load_tree(Arc::clone(&db), address.clone(), &old_root);
+        tree.update(h256_to_smth256(&key1), h256_to_smth256(&h256!("0x12")))
+            .unwrap();
+        tree.update(h256_to_smth256(&key2), h256_to_smth256(&h256!("0x22")))
+            .unwrap();
+        // The input tree is still readable before the nodes are written
+        let input_tree = SparseMerkleTree::<CkbBlake2bHasher, SmtH256, SmtStore>::new(
+            h256_to_smth256(&old_root),
+            tree.store().clone(),
+        );
+        assert_eq!(
+            input_tree.get(&h256_to_smth256(&key1)).unwrap(),
+            h256_to_smth256(&h256!("0x11"))
+        );
+        let root = smth256_to_h256(tree.root());
+        let mut batch = Batch::new(db.as_ref());
+        tree.store().write_nodes(&mut batch, &address);
+        batch.commit().unwrap();
+
+        let tree = SmtStore::load_tree(Arc::clone(&db), address.clone(), &root);
+        assert_eq!(
+            tree.get(&h256_to_smth256(&key1)).unwrap(),
+            h256_to_smth256(&h256!("0x12"))
+        );
+        assert_eq!(
+            tree.get(&h256_to_smth256(&key2)).unwrap(),
+            h256_to_smth256(&h256!("0x22"))
+        );
+        // Only the nodes of the latest tree are kept
+        let storage: HashMap<H256, H256> =
+            vec![(key1.clone(), h256!("0x12")), (key2, h256!("0x22"))]
+                .into_iter()
+                .collect();
+        let new_db = MemoryStore::new();
+        let mut batch = Batch::new(&new_db);
+        assert_eq!(
+            SmtStore::rebuild_nodes(&mut batch, &address, &storage).unwrap(),
+            root
+        );
+        batch.commit().unwrap();
+        assert_eq!(node_keys(db.as_ref()), node_keys(&new_db));
+
+        // Roll back to the old root
+        let storage: HashMap<H256, H256> =
+            vec![(key1.clone(), h256!("0x11"))].into_iter().collect();
+        let mut batch = Batch::new(db.as_ref());
+        assert_eq!(
+            SmtStore::rebuild_nodes(&mut batch, &address, &storage).unwrap(),
+            old_root
+        );
+        batch.commit().unwrap();
+        let old_tree = SmtStore::load_tree(db, address, &old_root);
+        assert_eq!(
+            old_tree.get(&h256_to_smth256(&key1)).unwrap(),
+            h256_to_smth256(&h256!("0x11"))
+        );
+        assert_eq!(
+            old_tree.get(&h256_to_smth256(&key2)).unwrap(),
+            SmtH256::zero()
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use rocksdb::{Snapshot, DB};
+use std::mem::{self, ManuallyDrop};
+use std::sync::Arc;
+
+use super::{column_family, key_column_family, BatchOp, KeyType, KvIterator, KvStore};
+
+/// A RocksDB snapshot owning a handle of the DB, so it can be shared by the
+/// loaders serving one RPC request.
+///
+/// `Snapshot` borrows the DB, it can not be stored next to the `Arc<DB>` it
+/// borrows from, so the borrow is extended to `'static`. This is sound as
+/// long as:
+///
+/// * the DB lives behind the `Arc`, moving a `DbSnapshot` does not move it;
+/// * `db` is never replaced, it keeps the DB open while `inner` is alive;
+/// * `inner` is released in `drop` before `db` is dropped;
+/// * nothing borrowed from `inner` outlives `&self` (iterators borrow
+///   `self`, never `'static`).
+pub struct DbSnapshot {
+    inner: ManuallyDrop<Snapshot<'static>>,
+    db: Arc<DB>,
+}
+
+impl DbSnapshot {
+    pub fn new(db: Arc<DB>) -> DbSnapshot {
+        let snapshot = db.snapshot();
+        // SAFETY: see the invariants above
+        let inner = unsafe { mem::transmute::<Snapshot<'_>, Snapshot<'static>>(snapshot) };
+        DbSnapshot {
+            inner: ManuallyDrop::new(inner),
+            db,
+        }
+    }
+}
+
+impl Drop for DbSnapshot {
+    fn drop(&mut self) {
+        // SAFETY: `inner` is not used after this, and the DB is still kept
+        // open by `db`, which is dropped after this function returns
+        unsafe { ManuallyDrop::drop(&mut self.inner) }
+    }
+}
+
+impl KvStore for DbSnapshot {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner
+            .get_cf(key_column_family(&self.db, key), key)
+            .map_err(|err| err.to_string())
+    }
+
+    fn iter(&self, key_type: KeyType) -> Box<dyn KvIterator + '_> {
+        Box::new(
+            self.inner
+                .raw_iterator_cf(column_family(&self.db, key_type)),
+        )
+    }
+
+    fn write(&self, _ops: Vec<BatchOp>) -> Result<(), String> {
+        Err(String::from("Can not write to a snapshot"))
+    }
+
+    fn snapshot(self: Arc<Self>) -> Arc<dyn KvStore> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::open_db;
+    use super::*;
+
+    #[test]
+    fn test_snapshot_outlives_db_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = Arc::new(open_db(&path).unwrap());
+        let ty = KeyType::BlockMap as u8;
+        KvStore::write(&*db, vec![BatchOp::Put(vec![ty, 1], vec![1])]).unwrap();
+        let snapshot = Arc::clone(&db).snapshot();
+        KvStore::write(
+            &*db,
+            vec![
+                BatchOp::Put(vec![ty, 1], vec![2]),
+                BatchOp::Put(vec![ty, 2], vec![2]),
+            ],
+        )
+        .unwrap();
+        // The snapshot is the last owner of the DB
+        drop(db);
+
+        assert_eq!(snapshot.get(&[ty, 1]).unwrap(), Some(vec![1]));
+        assert_eq!(snapshot.get(&[ty, 2]).unwrap(), None);
+        {
+            let mut iter = snapshot.iter(KeyType::BlockMap);
+            iter.seek_to_first();
+            assert_eq!(iter.key(), Some(&[ty, 1][..]));
+            iter.next();
+            assert!(!iter.valid());
+        }
+        // Still open, RocksDB holds the lock of the directory
+        assert!(open_db(&path).is_err());
+
+        // The snapshot is released first, then the DB is closed
+        drop(snapshot);
+        let db = open_db(&path).unwrap();
+        assert_eq!(KvStore::get(&db, &[ty, 1]).unwrap(), Some(vec![2]));
+    }
+}
@@ -0,0 +1,277 @@
+use bincode::deserialize;
+use ckb_jsonrpc_types as json_types;
+use ckb_simple_account_layer::CkbBlake2bHasher;
+use ckb_types::{bytes::Bytes, packed, prelude::*, H256};
+use sparse_merkle_tree::{SparseMerkleTree, H256 as SmtH256};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{db_get, db_iter, value, Key, KeyType, KvStore, Loader, PendingPool, SmtStore};
+use crate::chain::ChainSource;
+use crate::types::{contract_account_balance, h256_to_smth256, ContractAddress};
+
+/// Walk the database and report records inconsistent with each other or
+/// with CKB.
+pub struct Verifier {
+    db: Arc<dyn KvStore>,
+    loader: Loader,
+    client: Box<dyn ChainSource>,
+    problems: Vec<String>,
+}
+
+impl Verifier {
+    pub fn new(db: Arc<dyn KvStore>, client: Box<dyn ChainSource>) -> Result<Verifier, String> {
+        let pending = Arc::new(Mutex::new(PendingPool::new(Duration::from_secs(0))));
+        let loader = Loader::new(Arc::clone(&db), client.clone(), pending)?;
+        Ok(Verifier {
+            db,
+            loader,
+            client,
+            problems: Vec::new(),
+        })
+    }
+
+    /// Run all checks, return the inconsistencies found
+    pub fn verify(mut self) -> Result<Vec<String>, String> {
+        log::info!("Check live cells");
+        self.check_live_cells()?;
+        log::info!("Check EoA cells");
+        self.check_eoa_cells()?;
+        log::info!("Check contracts");
+        self.check_contracts()?;
+        log::info!("Check block map");
+        self.check_block_map()?;
+        Ok(self.problems)
+    }
+
+    fn report(&mut self, problem: String) {
+        log::warn!("{}", problem);
+        self.problems.push(problem);
+    }
+
+    // Every LockLiveCell has a LiveCellMap of the same position and lock,
+    // and the other way around.
+    fn check_live_cells(&mut self) -> Result<(), String> {
+        let db = Arc::clone(&self.db);
+        scan(&db, KeyType::LockLiveCell, |key_bytes, value_bytes| {
+            let (lock_hash, number, tx_index) = match Key::try_from(key_bytes)? {
+                Key::LockLiveCell {
+                    lock_hash,
+                    number,
+                    tx_index,
+                    ..
+                } => (
+                    lock_hash,
+                    number.expect("illegal key"),
+                    tx_index.expect("illegal key"),
+                ),
+                _ => panic!("DB corrupted deserialize Key::LockLiveCell"),
+            };
+            let value: value::LockLiveCell =
+                deserialize(value_bytes).map_err(|err| err.to_string())?;
+            let out_point = value.out_point();
+            let map_key = Key::LiveCellMap(out_point.clone());
+            match db_get::<_, value::LiveCellMap>(&db, &Bytes::from(&map_key))? {
+                Some(cell) => {
+                    let output = packed::CellOutput::from_slice(&cell.output)
+                        .map_err(|err| err.to_string())?;
+                    let cell_lock_hash: H256 = output.lock().calc_script_hash().unpack();
+                    if cell.number != number
+                        || cell.tx_index != tx_index
+                        || cell_lock_hash != lock_hash
+                    {
+                        self.report(format!(
+                            "LiveCellMap of cell {} not match LockLiveCell",
+                            format_out_point(&out_point)
+                        ));
+                    }
+                }
+                None => self.report(format!(
+                    "LiveCellMap of cell {} not found",
+                    format_out_point(&out_point)
+                )),
+            }
+            Ok(())
+        })?;
+        scan(&db, KeyType::LiveCellMap, |key_bytes, value_bytes| {
+            let out_point = match Key::try_from(key_bytes)? {
+                Key::LiveCellMap(out_point) => out_point,
+                _ => panic!("DB corrupted deserialize Key::LiveCellMap"),
+            };
+            let cell: value::LiveCellMap =
+                deserialize(value_bytes).map_err(|err| err.to_string())?;
+            let output =
+                packed::CellOutput::from_slice(&cell.output).map_err(|err| err.to_string())?;
+            let lock_key = Key::LockLiveCell {
+                lock_hash: output.lock().calc_script_hash().unpack(),
+                number: Some(cell.number),
+                tx_index: Some(cell.tx_index),
+                output_index: Some(out_point.index().unpack()),
+            };
+            if db_get::<_, value::LockLiveCell>(&db, &Bytes::from(&lock_key))?.is_none() {
+                self.report(format!(
+                    "LockLiveCell of cell {} not found",
+                    format_out_point(&out_point)
+                ));
+            }
+            Ok(())
+        })
+    }
+
+    // EoA cells are still live on CKB
+    fn check_eoa_cells(&mut self) -> Result<(), String> {
+        let db = Arc::clone(&self.db);
+        scan(&db, KeyType::EoaLiveCell, |key_bytes, value_bytes| {
+            let address = match Key::try_from(key_bytes)? {
+                Key::EoaLiveCell(address) => address,
+                _ => panic!("DB corrupted deserialize Key::EoaLiveCell"),
+            };
+            let value: value::EoaLiveCell =
+                deserialize(value_bytes).map_err(|err| err.to_string())?;
+            let out_point = value.out_point();
+            let cell_with_status = self
+                .client
+                .get_live_cell(json_types::OutPoint::from(out_point.clone()), false)?;
+            if cell_with_status.status != "live" {
+                self.report(format!(
+                    "EoA cell of {:x} is {} on CKB: {}",
+                    address,
+                    cell_with_status.status,
+                    format_out_point(&out_point)
+                ));
+            }
+            Ok(())
+        })
+    }
+
+    // The storage root of the latest change matches the contract cell data,
+    // and the balance in the meta matches the contract cell.
+    fn check_contracts(&mut self) -> Result<(), String> {
+        let mut metas: Vec<(ContractAddress, value::ContractMeta)> = Vec::new();
+        scan(&self.db, KeyType::ContractMeta, |key_bytes, value_bytes| {
+            let address = match Key::try_from(key_bytes)? {
+                Key::ContractMeta(address) => address,
+                _ => panic!("DB corrupted deserialize Key::ContractMeta"),
+            };
+            metas.push((
+                address,
+                deserialize(value_bytes).map_err(|err| err.to_string())?,
+            ));
+            Ok(())
+        })?;
+        for (address, meta) in metas {
+            if meta.destructed {
+                continue;
+            }
+            let change =
+                match self
+                    .loader
+                    .load_latest_contract_change(address.clone(), None, false, false)
+                {
+                    Ok(change) => change,
+                    Err(err) => {
+                        self.report(format!("Contract {:x}: {}", address.0, err));
+                        continue;
+                    }
+                };
+            let (output, data) = match self
+                .loader
+                .load_contract_live_cell(change.tx_hash.clone(), change.output_index)
+            {
+                Ok(cell) => cell,
+                Err(err) => {
+                    self.report(format!("Contract {:x}: {}", address.0, err));
+                    continue;
+                }
+            };
+            if data.len() < 32 {
+                self.report(format!(
+                    "Contract {:x}: cell data too short: {}",
+                    address.0,
+                    hex::encode(&data)
+                ));
+                continue;
+            }
+            let storage: HashMap<H256, H256> = self.loader.load_contract_storage(&change)?;
+            let mut tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, SmtStore> =
+                SparseMerkleTree::default();
+            for (key, value) in &storage {
+                tree.update(h256_to_smth256(key), h256_to_smth256(value))
+                    .map_err(|err| err.to_string())?;
+            }
+            if tree.root().as_slice() != &data[0..32] {
+                self.report(format!(
+                    "Contract {:x}: storage root {} not match the cell data {}",
+                    address.0,
+                    hex::encode(tree.root().as_slice()),
+                    hex::encode(&data[0..32])
+                ));
+            }
+            let balance = contract_account_balance(&output);
+            if meta.balance != balance {
+                self.report(format!(
+                    "Contract {:x}: balance {} not match the cell balance {}",
+                    address.0, meta.balance, balance
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // BlockMap matches the CKB block hashes, Key::Last is in BlockMap
+    fn check_block_map(&mut self) -> Result<(), String> {
+        let db = Arc::clone(&self.db);
+        scan(&db, KeyType::BlockMap, |key_bytes, value_bytes| {
+            let number = match Key::try_from(key_bytes)? {
+                Key::BlockMap(number) => number,
+                _ => panic!("DB corrupted deserialize Key::BlockMap"),
+            };
+            let block_map: value::BlockMap =
+                deserialize(value_bytes).map_err(|err| err.to_string())?;
+            match self.client.get_header_by_number(number)? {
+                Some(header) if header.hash == block_map.0 => {}
+                Some(header) => self.report(format!(
+                    "Block #{} hash {:#x} not match CKB block hash {:#x}",
+                    number, block_map.0, header.hash
+                )),
+                None => self.report(format!("Block #{} not found on CKB", number)),
+            }
+            Ok(())
+        })?;
+        if let Some(last) = db_get::<_, value::Last>(&db, &Bytes::from(&Key::Last))? {
+            let block_map: Option<value::BlockMap> =
+                db_get(&db, &Bytes::from(&Key::BlockMap(last.number)))?;
+            if block_map.map(|block_map| block_map.0) != Some(last.hash.clone()) {
+                self.report(format!(
+                    "Last block #{} {:#x} not match BlockMap",
+                    last.number, last.hash
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Walk all records of a key type
+fn scan<F>(db: &dyn KvStore, key_type: KeyType, mut f: F) -> Result<(), String>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<(), String>,
+{
+    let mut iter = db_iter(db, key_type);
+    iter.seek_to_first();
+    while iter.valid() {
+        if let (Some(key_bytes), Some(value_bytes)) = (iter.key(), iter.value()) {
+            f(key_bytes, value_bytes)?;
+        }
+        iter.next();
+    }
+    Ok(())
+}
+
+fn format_out_point(out_point: &packed::OutPoint) -> String {
+    let tx_hash: H256 = out_point.tx_hash().unpack();
+    let index: u32 = out_point.index().unpack();
+    format!("{:#x}-{}", tx_hash, index)
+}
@@ -1,4 +1,4 @@
-use ckb_simple_account_layer::{CkbBlake2bHasher, Config};
+use ckb_simple_account_layer::Config;
 use ckb_types::{
     bytes::{BufMut, Bytes, BytesMut},
     core::{BlockView, Capacity, DepType, EpochNumberWithFraction, ScriptHashType},
@@ -10,7 +10,7 @@ use ckb_types::{
 use ckb_vm::{Error as VMError, Memory, Register, SupportMachine};
 use numext_fixed_uint::prelude::UintConvert;
 use serde::{Deserialize, Serialize};
-use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree, H256 as SmtH256};
+use sparse_merkle_tree::H256 as SmtH256;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 
@@ -21,37 +21,26 @@ pub const ONE_CKB: u64 = 100_000_000;
 
 pub const SIGHASH_TYPE_HASH: H256 =
     h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8");
+pub const MULTISIG_TYPE_HASH: H256 =
+    h256!("0x5c5069eb0857efc65e1bca0c07df34c31663b3622fd3876c876320fc9634e2a8");
+/// The data hash of `tests/anyone_can_pay`, deployed by hand on dev chains
+pub const DEV_ANYONE_CAN_PAY_CODE_HASH: H256 =
+    h256!("0x8b10144daa110152e78dd002b44f429238cbbee5e62052205fdc6a1fc2c57a2a");
 pub const ALWAYS_SUCCESS_CODE_HASH: H256 =
     h256!("0x28e83a1277d48add8e72fadaa9248559e1b632bab2bd60b27955ebc4c03800a5");
+/// The always success binary (hex), the default lock of contract cells
+pub const ALWAYS_SUCCESS_BINARY: &str = "7f454c460201010000000000000000000200f3000100000078000100000000004000000000000000980000000000000005000000400038000100400003000200010000000500000000000000000000000000010000000000000001000000000082000000000000008200000000000000001000000000000001459308d00573000000002e7368737472746162002e74657874000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000b000000010000000600000000000000780001000000000078000000000000000a0000000000000000000000000000000200000000000000000000000000000001000000030000000000000000000000000000000000000082000000000000001100000000000000000000000000000001000000000000000000000000000000";
+
+pub const MAINNET_GENESIS_HASH: H256 =
+    h256!("0x92b197aa1fba0f63633922c61c92375c9c074a93e85963554f5499fe1450d0e5");
+pub const TESTNET_GENESIS_HASH: H256 =
+    h256!("0x10639e0895502b5688a6be8cf69460d76541bfa4821629d86d62ba0aae3f9606");
 
 pub const CELLBASE_MATURITY: EpochNumberWithFraction =
     EpochNumberWithFraction::new_unchecked(4, 0, 1);
 
 lazy_static::lazy_static! {
     pub static ref SECP256K1: secp256k1::Secp256k1<secp256k1::All> = secp256k1::Secp256k1::new();
-    pub static ref SIGHASH_CELL_DEP: packed::CellDep = {
-        let out_point = packed::OutPoint::new_builder()
-            .tx_hash(h256!("0xace5ea83c478bb866edf122ff862085789158f5cbff155b7bb5f13058555b708").pack())
-            .index(0u32.pack())
-            .build();
-        packed::CellDep::new_builder()
-            .out_point(out_point)
-            .dep_type(DepType::DepGroup.into())
-            .build()
-    };
-    pub static ref ALWAYS_SUCCESS_OUT_POINT: packed::OutPoint = {
-        // FIXME: replace this later
-        packed::OutPoint::new_builder()
-            .tx_hash(h256!("0x1111111111111111111111111111111111111111111111111111111111111111").pack())
-            .index(0u32.pack())
-            .build()
-    };
-    pub static ref ALWAYS_SUCCESS_CELL_DEP: packed::CellDep = {
-        packed::CellDep::new_builder()
-            .out_point(ALWAYS_SUCCESS_OUT_POINT.clone())
-            .dep_type(DepType::Code.into())
-            .build()
-    };
     pub static ref ALWAYS_SUCCESS_SCRIPT: packed::Script = {
         packed::Script::new_builder()
             .code_hash(ALWAYS_SUCCESS_CODE_HASH.pack())
@@ -66,12 +55,199 @@ pub struct RunConfig {
     // Type script (Validator)
     pub type_dep: packed::CellDep,
     pub type_script: packed::Script,
-    // Lock script for contract (default always success)
+    // Lock script for new contract cells (default always success)
     pub lock_dep: packed::CellDep,
     pub lock_script: packed::Script,
-    // Lock script for EoA account
+    // Locks of contract cells created under earlier configs
+    pub legacy_locks: Vec<(packed::CellDep, packed::Script)>,
+    // Lock script for EoA account (anyone-can-pay)
     pub eoa_lock_dep: packed::CellDep,
     pub eoa_lock_script: packed::Script,
+    // Other lock scripts accepted for EoA account
+    pub extra_eoa_locks: Vec<EoaLock>,
+    // The secp256k1 sighash dep group (for signing EoA cells)
+    pub sighash_dep: packed::CellDep,
+}
+
+impl RunConfig {
+    /// The EoA lock matching the code hash and hash type of a lock script
+    pub fn eoa_lock(&self, lock_script: &packed::Script) -> Option<EoaLock> {
+        if same_script_code(lock_script, &self.eoa_lock_script) {
+            return Some(EoaLock {
+                kind: EoaLockKind::AnyoneCanPay,
+                dep: self.eoa_lock_dep.clone(),
+                script: self.eoa_lock_script.clone(),
+            });
+        }
+        self.extra_eoa_locks
+            .iter()
+            .find(|eoa_lock| same_script_code(lock_script, &eoa_lock.script))
+            .cloned()
+    }
+
+    pub fn eoa_lock_scripts(&self) -> Vec<(EoaLockKind, packed::Script)> {
+        let mut scripts = vec![(EoaLockKind::AnyoneCanPay, self.eoa_lock_script.clone())];
+        scripts.extend(
+            self.extra_eoa_locks
+                .iter()
+                .map(|eoa_lock| (eoa_lock.kind, eoa_lock.script.clone())),
+        );
+        scripts
+    }
+}
+
+/// The lock of an EoA cell, the EoA address is always
+/// blake2b(type_args ++ lock_args)[0..20]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EoaLockKind {
+    /// args = blake160(pubkey), receiving needs no signature
+    AnyoneCanPay,
+    /// args = blake160(pubkey)
+    Sighash,
+    /// args = blake160(multisig script) (++ since)
+    Multisig,
+}
+
+impl EoaLockKind {
+    /// Only anyone-can-pay EoA can receive value from others
+    pub fn can_receive(self) -> bool {
+        self == EoaLockKind::AnyoneCanPay
+    }
+
+    /// The validator accepts 20 bytes lock args, or 28 bytes for a
+    /// multisig with since
+    pub fn valid_lock_args(self, lock_args: &[u8]) -> bool {
+        lock_args.len() == 20 || (self == EoaLockKind::Multisig && lock_args.len() == 28)
+    }
+
+    /// The since of the input, multisig args may carry a since
+    pub fn input_since(self, lock_args: &[u8]) -> u64 {
+        if self == EoaLockKind::Multisig && lock_args.len() == 28 {
+            let mut since = [0u8; 8];
+            since.copy_from_slice(&lock_args[20..28]);
+            u64::from_le_bytes(since)
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EoaLock {
+    pub kind: EoaLockKind,
+    pub dep: packed::CellDep,
+    /// The code hash and hash type, args are ignored
+    pub script: packed::Script,
+}
+
+/// Same code hash and hash type, the args may differ
+pub fn same_script_code(a: &packed::Script, b: &packed::Script) -> bool {
+    a.code_hash().as_slice() == b.code_hash().as_slice()
+        && a.hash_type().as_slice() == b.hash_type().as_slice()
+}
+
+/// The system scripts differ between networks
+#[derive(Debug, Clone)]
+pub struct ChainScripts {
+    /// The secp256k1 sighash dep group
+    pub sighash_dep: packed::CellDep,
+    /// The secp256k1 multisig dep group
+    pub multisig_dep: packed::CellDep,
+    /// The anyone-can-pay lock (without args)
+    pub anyone_can_pay_script: packed::Script,
+    /// None if anyone-can-pay is not deployed in genesis or by the preset
+    pub anyone_can_pay_dep: Option<packed::CellDep>,
+}
+
+impl ChainScripts {
+    /// The presets of mainnet (lina) and testnet (aggron) by genesis hash,
+    /// the validator recognizes these anyone-can-pay locks by type hash
+    pub fn preset(genesis_hash: &H256) -> Option<ChainScripts> {
+        let (sighash_tx, acp_code_hash, acp_tx) = if genesis_hash == &MAINNET_GENESIS_HASH {
+            (
+                h256!("0x71a7ba8fc96349fea0ed3a5c47992e3b4084b031a42264a018e0072e8172e46c"),
+                h256!("0xd369597ff47f29fbc0d47d2e3775370d1250b85140c670e4718af712983a2354"),
+                h256!("0x4153a2014952d7cac45f285ce9a7c5c0c0e1b21f2d378b82ac1433cb11c25c4d"),
+            )
+        } else if genesis_hash == &TESTNET_GENESIS_HASH {
+            (
+                h256!("0xf8de3bb47d055cdf460d93a2a6e1b05f7432f9777c8c474abf4eec1d4aee5d37"),
+                h256!("0x3419a1c09eb2567f6552ee7a8ecffd64155cffe0f1796e6e61ec088d740c1356"),
+                h256!("0xec26b0f85ed839ece5f11c4c4e837ec359f5adc4420410f6453b1f6b60fb96a6"),
+            )
+        } else {
+            return None;
+        };
+        Some(ChainScripts {
+            sighash_dep: dep_group(sighash_tx.clone(), 0),
+            multisig_dep: dep_group(sighash_tx, 1),
+            anyone_can_pay_script: type_script(acp_code_hash),
+            anyone_can_pay_dep: Some(dep_group(acp_tx, 0)),
+        })
+    }
+
+    /// The preset of dev chains, the sighash and multisig dep groups are the
+    /// first two outputs of the second genesis transaction
+    pub fn dev(dep_group_tx_hash: H256) -> ChainScripts {
+        ChainScripts {
+            sighash_dep: dep_group(dep_group_tx_hash.clone(), 0),
+            multisig_dep: dep_group(dep_group_tx_hash, 1),
+            anyone_can_pay_script: packed::Script::new_builder()
+                .code_hash(DEV_ANYONE_CAN_PAY_CODE_HASH.pack())
+                .hash_type(ScriptHashType::Data.into())
+                .build(),
+            anyone_can_pay_dep: None,
+        }
+    }
+
+    /// The EoA locks of the system scripts
+    pub fn system_eoa_locks(&self) -> Vec<EoaLock> {
+        vec![
+            EoaLock {
+                kind: EoaLockKind::Sighash,
+                dep: self.sighash_dep.clone(),
+                script: type_script(SIGHASH_TYPE_HASH),
+            },
+            EoaLock {
+                kind: EoaLockKind::Multisig,
+                dep: self.multisig_dep.clone(),
+                script: type_script(MULTISIG_TYPE_HASH),
+            },
+        ]
+    }
+
+    pub fn eoa_lock_scripts(&self) -> Vec<(EoaLockKind, packed::Script)> {
+        let mut scripts = vec![(
+            EoaLockKind::AnyoneCanPay,
+            self.anyone_can_pay_script.clone(),
+        )];
+        scripts.extend(
+            self.system_eoa_locks()
+                .into_iter()
+                .map(|eoa_lock| (eoa_lock.kind, eoa_lock.script)),
+        );
+        scripts
+    }
+}
+
+fn type_script(code_hash: H256) -> packed::Script {
+    packed::Script::new_builder()
+        .code_hash(code_hash.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .build()
+}
+
+fn dep_group(tx_hash: H256, index: u32) -> packed::CellDep {
+    packed::CellDep::new_builder()
+        .out_point(
+            packed::OutPoint::new_builder()
+                .tx_hash(tx_hash.pack())
+                .index(index.pack())
+                .build(),
+        )
+        .dep_type(DepType::DepGroup.into())
+        .build()
 }
 
 /// A contract account's cell data
@@ -205,7 +381,8 @@ pub struct ContractChange {
     /// Output index in current transaction
     pub output_index: u32,
     pub tx_hash: H256,
-    pub new_storage: HashMap<H256, H256>,
+    /// The written storage: (key, old_value, new_value)
+    pub storage_diff: Vec<(H256, H256, H256)>,
     pub logs: Vec<(Vec<H256>, Bytes)>,
     pub capacity: u64,
     pub balance: u64,
@@ -668,17 +845,6 @@ impl ContractMeta {
 }
 
 impl ContractChange {
-    pub fn merkle_tree(
-        &self,
-    ) -> SparseMerkleTree<CkbBlake2bHasher, SmtH256, DefaultStore<SmtH256>> {
-        let mut tree = SparseMerkleTree::default();
-        for (key, value) in &self.new_storage {
-            tree.update(h256_to_smth256(key), h256_to_smth256(value))
-                .unwrap();
-        }
-        tree
-    }
-
     pub fn out_point(&self) -> packed::OutPoint {
         packed::OutPoint::new_builder()
             .tx_hash(self.tx_hash.pack())
@@ -698,7 +864,7 @@ impl ContractChange {
         value::ContractChange {
             tx_hash: self.tx_hash.clone(),
             tx_origin: self.tx_origin.clone(),
-            new_storage: self.new_storage.clone().into_iter().collect(),
+            storage_diff: self.storage_diff.clone(),
             capacity: self.capacity,
             balance: self.balance,
             is_create: self.is_create,
@@ -721,6 +887,32 @@ impl ContractChange {
     }
 }
 
+/// The written storage from old to new, a removed key has zero new value
+pub fn storage_diff(
+    old_storage: &HashMap<H256, H256>,
+    new_storage: &HashMap<H256, H256>,
+) -> Vec<(H256, H256, H256)> {
+    let mut diff = new_storage
+        .iter()
+        .filter_map(|(key, new_value)| {
+            let old_value = old_storage.get(key).cloned().unwrap_or_default();
+            if &old_value != new_value {
+                Some((key.clone(), old_value, new_value.clone()))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    diff.extend(
+        old_storage
+            .iter()
+            .filter(|(key, _)| !new_storage.contains_key(key))
+            .map(|(key, old_value)| (key.clone(), old_value.clone(), H256::default())),
+    );
+    diff.sort();
+    diff
+}
+
 pub fn smth256_to_h256(hash: &SmtH256) -> H256 {
     H256::from_slice(hash.as_slice()).unwrap()
 }